@@ -55,3 +55,11 @@ function overflowMenu(subtree = document) {
 }
 
 addEventListener("htmx:load", e => overflowMenu(e.target));
+
+// Failed requests come back with a fragment saying what went wrong, which htmx ignores by default.
+addEventListener("htmx:beforeSwap", e => {
+  if (e.detail.isError && e.detail.xhr.getResponseHeader("HX-Retarget")) {
+    e.detail.shouldSwap = true;
+    e.detail.isError = false;
+  }
+});
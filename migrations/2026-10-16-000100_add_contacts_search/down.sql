@@ -0,0 +1,5 @@
+DROP INDEX contacts_search_document_idx;
+DROP INDEX contacts_search_text_trgm_idx;
+ALTER TABLE contacts DROP COLUMN search_document;
+ALTER TABLE contacts DROP COLUMN search_text;
+DROP EXTENSION IF EXISTS pg_trgm;
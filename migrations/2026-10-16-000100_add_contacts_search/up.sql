@@ -0,0 +1,13 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- Every searchable field in one place, so the trigram index can serve
+-- substring matches (ILIKE '%...%') as well as typo-tolerant word similarity.
+ALTER TABLE contacts ADD COLUMN search_text TEXT
+    GENERATED ALWAYS AS (lower(first_name || ' ' || last_name || ' ' || phone || ' ' || email_address)) STORED NOT NULL;
+
+-- The 'simple' configuration doesn't stem, which is what we want for names and email addresses.
+ALTER TABLE contacts ADD COLUMN search_document TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('simple', first_name || ' ' || last_name || ' ' || phone || ' ' || email_address)) STORED NOT NULL;
+
+CREATE INDEX contacts_search_text_trgm_idx ON contacts USING GIN (search_text gin_trgm_ops);
+CREATE INDEX contacts_search_document_idx ON contacts USING GIN (search_document);
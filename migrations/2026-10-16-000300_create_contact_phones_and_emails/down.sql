@@ -0,0 +1,2 @@
+DROP TABLE contact_emails;
+DROP TABLE contact_phones;
@@ -0,0 +1,29 @@
+-- `contacts.phone` and `contacts.email_address` keep a copy of the primary entries,
+-- which is what search, sorting and the v1 API look at.
+CREATE TABLE contact_phones (
+    id SERIAL PRIMARY KEY,
+    contact_id INTEGER NOT NULL REFERENCES contacts (id) ON DELETE CASCADE,
+    label VARCHAR NOT NULL DEFAULT '',
+    number VARCHAR NOT NULL,
+    is_primary BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE contact_emails (
+    id SERIAL PRIMARY KEY,
+    contact_id INTEGER NOT NULL REFERENCES contacts (id) ON DELETE CASCADE,
+    label VARCHAR NOT NULL DEFAULT '',
+    address VARCHAR NOT NULL,
+    is_primary BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX contact_phones_contact_id_idx ON contact_phones (contact_id);
+CREATE INDEX contact_emails_contact_id_idx ON contact_emails (contact_id);
+-- At most one primary entry per contact.
+CREATE UNIQUE INDEX contact_phones_primary_idx ON contact_phones (contact_id) WHERE is_primary;
+CREATE UNIQUE INDEX contact_emails_primary_idx ON contact_emails (contact_id) WHERE is_primary;
+
+INSERT INTO contact_phones (contact_id, number, is_primary)
+SELECT id, phone, TRUE FROM contacts WHERE phone <> '';
+
+INSERT INTO contact_emails (contact_id, address, is_primary)
+SELECT id, email_address, TRUE FROM contacts WHERE email_address <> '';
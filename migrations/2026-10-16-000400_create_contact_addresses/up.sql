@@ -0,0 +1,12 @@
+CREATE TABLE contact_addresses (
+    id SERIAL PRIMARY KEY,
+    contact_id INTEGER NOT NULL REFERENCES contacts (id) ON DELETE CASCADE,
+    label VARCHAR NOT NULL DEFAULT '',
+    street VARCHAR NOT NULL,
+    city VARCHAR NOT NULL,
+    region VARCHAR NOT NULL DEFAULT '',
+    postal_code VARCHAR NOT NULL DEFAULT '',
+    country VARCHAR NOT NULL DEFAULT ''
+);
+
+CREATE INDEX contact_addresses_contact_id_idx ON contact_addresses (contact_id);
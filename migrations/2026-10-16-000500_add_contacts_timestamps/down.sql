@@ -0,0 +1,4 @@
+DROP TRIGGER set_updated_at ON contacts;
+ALTER TABLE contacts
+    DROP COLUMN created_at,
+    DROP COLUMN updated_at;
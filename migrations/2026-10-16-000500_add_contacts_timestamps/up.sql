@@ -0,0 +1,9 @@
+-- Existing contacts get the time of the migration, we don't know any better.
+ALTER TABLE contacts
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+SELECT diesel_manage_updated_at('contacts');
+
+CREATE INDEX contacts_created_at_idx ON contacts (created_at);
+CREATE INDEX contacts_updated_at_idx ON contacts (updated_at);
@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS bump_version ON contacts;
+DROP FUNCTION IF EXISTS bump_version();
+ALTER TABLE contacts DROP COLUMN version;
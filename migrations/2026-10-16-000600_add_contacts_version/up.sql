@@ -0,0 +1,14 @@
+-- Bumped on every change to a contact, so writers can tell if they're about to overwrite an edit they haven't seen.
+ALTER TABLE contacts ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+CREATE OR REPLACE FUNCTION bump_version() RETURNS trigger AS $$
+BEGIN
+    IF NEW IS DISTINCT FROM OLD THEN
+        NEW.version := OLD.version + 1;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER bump_version BEFORE UPDATE ON contacts
+    FOR EACH ROW EXECUTE PROCEDURE bump_version();
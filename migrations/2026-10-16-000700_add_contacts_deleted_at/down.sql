@@ -0,0 +1,2 @@
+DELETE FROM contacts WHERE deleted_at IS NOT NULL;
+ALTER TABLE contacts DROP COLUMN deleted_at;
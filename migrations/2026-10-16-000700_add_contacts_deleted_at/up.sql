@@ -0,0 +1,4 @@
+-- Deleted contacts are kept in the trash until they're restored or purged.
+ALTER TABLE contacts ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX contacts_deleted_at_idx ON contacts (deleted_at) WHERE deleted_at IS NOT NULL;
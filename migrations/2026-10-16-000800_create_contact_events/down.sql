@@ -0,0 +1,2 @@
+DROP TABLE contact_events;
+DROP FUNCTION forbid_contact_event_updates();
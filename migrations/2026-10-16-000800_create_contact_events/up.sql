@@ -0,0 +1,34 @@
+-- Every change to a contact's name, phone and email, with what it looked like before and after.
+-- Purging a contact takes its history with it, but history is never rewritten.
+CREATE TABLE contact_events (
+    id SERIAL PRIMARY KEY,
+    contact_id INTEGER NOT NULL REFERENCES contacts (id) ON DELETE CASCADE,
+    kind VARCHAR NOT NULL,
+    source VARCHAR NOT NULL,
+    before JSONB,
+    after JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX contact_events_contact_id_idx ON contact_events (contact_id, id);
+
+CREATE FUNCTION forbid_contact_event_updates() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'contact_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER contact_events_append_only BEFORE UPDATE ON contact_events
+    FOR EACH ROW EXECUTE PROCEDURE forbid_contact_event_updates();
+
+-- History starts with the contacts as they are now.
+INSERT INTO contact_events (contact_id, kind, source, after, created_at)
+SELECT id, 'created', 'migration',
+       jsonb_build_object(
+           'first_name', first_name,
+           'last_name', last_name,
+           'phone', phone,
+           'email_address', email_address
+       ),
+       created_at
+FROM contacts;
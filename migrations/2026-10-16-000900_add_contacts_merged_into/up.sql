@@ -0,0 +1,2 @@
+-- A contact merged into another stays in the trash, pointing at where its details went.
+ALTER TABLE contacts ADD COLUMN merged_into INTEGER REFERENCES contacts (id) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE contacts DROP COLUMN owner_id;
+DROP TABLE sessions;
+DROP TABLE users;
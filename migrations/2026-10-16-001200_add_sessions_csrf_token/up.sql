@@ -0,0 +1,4 @@
+-- Sessions from before there were CSRF tokens have none to check forms against, so they're ended.
+DELETE FROM sessions;
+
+ALTER TABLE sessions ADD COLUMN csrf_token VARCHAR NOT NULL;
@@ -0,0 +1,17 @@
+ALTER TABLE contacts ADD COLUMN owner_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+
+CREATE INDEX contacts_owner_id_idx ON contacts (owner_id);
+
+-- Shared books go back to whichever of their admins joined first.
+UPDATE contacts SET owner_id = (
+    SELECT user_id FROM memberships
+    WHERE memberships.address_book_id = contacts.address_book_id AND role = 'admin'
+    ORDER BY created_at, user_id
+    LIMIT 1
+);
+
+ALTER TABLE contacts DROP COLUMN address_book_id;
+
+DROP TABLE memberships;
+
+DROP TABLE address_books;
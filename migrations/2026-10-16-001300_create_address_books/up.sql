@@ -0,0 +1,35 @@
+CREATE TABLE address_books (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE memberships (
+    address_book_id INTEGER NOT NULL REFERENCES address_books (id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role VARCHAR NOT NULL CHECK (role IN ('viewer', 'editor', 'admin')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (address_book_id, user_id)
+);
+
+CREATE INDEX memberships_user_id_idx ON memberships (user_id);
+
+-- Contacts from before there were users have no book until the first user signs up and adopts them.
+ALTER TABLE contacts ADD COLUMN address_book_id INTEGER REFERENCES address_books (id) ON DELETE CASCADE;
+
+CREATE INDEX contacts_address_book_id_idx ON contacts (address_book_id);
+
+-- Everyone gets a book of their own with the contacts they had so far.
+DO $$
+DECLARE
+    account RECORD;
+    book INTEGER;
+BEGIN
+    FOR account IN SELECT id, username FROM users ORDER BY id LOOP
+        INSERT INTO address_books (name) VALUES (account.username || '''s Contacts') RETURNING id INTO book;
+        INSERT INTO memberships (address_book_id, user_id, role) VALUES (book, account.id, 'admin');
+        UPDATE contacts SET address_book_id = book WHERE owner_id = account.id;
+    END LOOP;
+END $$;
+
+ALTER TABLE contacts DROP COLUMN owner_id;
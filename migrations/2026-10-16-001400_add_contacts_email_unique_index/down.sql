@@ -0,0 +1 @@
+DROP INDEX contacts_email_address_lower_idx;
@@ -0,0 +1,22 @@
+-- Two contacts that already share an address would make the index fail with nothing to go on.
+DO $$
+DECLARE
+    clashes INTEGER;
+BEGIN
+    SELECT count(*) INTO clashes FROM (
+        SELECT 1 FROM contacts
+        WHERE deleted_at IS NULL AND email_address <> ''
+        GROUP BY address_book_id, lower(email_address)
+        HAVING count(*) > 1
+    ) AS clashing;
+    IF clashes > 0 THEN
+        RAISE EXCEPTION '% email addresses belong to more than one contact in the same address book', clashes
+            USING HINT = 'Merge or edit those contacts on the duplicates page (/contacts/duplicates), then run the migrations again.';
+    END IF;
+END $$;
+
+-- The application checks this first, across every book a user can see.
+-- The index is what stops two requests that both passed that check.
+CREATE UNIQUE INDEX contacts_email_address_lower_idx
+    ON contacts (address_book_id, lower(email_address))
+    WHERE deleted_at IS NULL AND email_address <> '';
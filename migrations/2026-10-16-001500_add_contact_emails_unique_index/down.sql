@@ -0,0 +1,12 @@
+CREATE UNIQUE INDEX contacts_email_address_lower_idx
+    ON contacts (address_book_id, lower(email_address))
+    WHERE deleted_at IS NULL AND email_address <> '';
+
+DROP INDEX contact_emails_address_lower_idx;
+DROP TRIGGER contacts_copy_to_emails ON contacts;
+DROP FUNCTION contacts_copy_to_emails();
+DROP TRIGGER contact_emails_copy_contact ON contact_emails;
+DROP FUNCTION contact_emails_copy_contact();
+ALTER TABLE contact_emails
+    DROP COLUMN address_book_id,
+    DROP COLUMN in_trash;
@@ -0,0 +1,79 @@
+-- Email addresses are unique per address book among the contacts outside the trash, whichever
+-- of a contact's addresses they are, so the index moves from `contacts.email_address` to
+-- `contact_emails`. That needs the book and whether the contact is in the trash next to each
+-- address, which triggers copy over from `contacts`.
+
+-- A contact listing the same address twice keeps its primary entry, or else the first one.
+DELETE FROM contact_emails duplicate
+USING contact_emails original
+WHERE original.contact_id = duplicate.contact_id
+    AND lower(btrim(original.address)) = lower(btrim(duplicate.address))
+    AND (
+        (original.is_primary AND NOT duplicate.is_primary)
+        OR (original.is_primary = duplicate.is_primary AND original.id < duplicate.id)
+    );
+
+-- Which of two contacts keeps a shared address is for a person to decide.
+DO $$
+DECLARE
+    clashes INTEGER;
+BEGIN
+    SELECT count(*) INTO clashes FROM (
+        SELECT 1 FROM contact_emails
+        JOIN contacts ON contacts.id = contact_emails.contact_id
+        WHERE contacts.deleted_at IS NULL AND contacts.address_book_id IS NOT NULL
+        GROUP BY contacts.address_book_id, lower(btrim(contact_emails.address))
+        HAVING count(*) > 1
+    ) AS clashing;
+    IF clashes > 0 THEN
+        RAISE EXCEPTION '% email addresses belong to more than one contact in the same address book', clashes
+            USING HINT = 'Merge or edit those contacts on the duplicates page (/contacts/duplicates), then run the migrations again.';
+    END IF;
+END $$;
+
+ALTER TABLE contact_emails
+    ADD COLUMN address_book_id INTEGER,
+    ADD COLUMN in_trash BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE contact_emails
+SET address_book_id = contacts.address_book_id, in_trash = contacts.deleted_at IS NOT NULL
+FROM contacts
+WHERE contacts.id = contact_emails.contact_id;
+
+CREATE FUNCTION contact_emails_copy_contact() RETURNS trigger AS $$
+BEGIN
+    SELECT address_book_id, deleted_at IS NOT NULL
+    INTO NEW.address_book_id, NEW.in_trash
+    FROM contacts
+    WHERE id = NEW.contact_id;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER contact_emails_copy_contact
+    BEFORE INSERT OR UPDATE OF contact_id ON contact_emails
+    FOR EACH ROW EXECUTE FUNCTION contact_emails_copy_contact();
+
+CREATE FUNCTION contacts_copy_to_emails() RETURNS trigger AS $$
+BEGIN
+    UPDATE contact_emails
+    SET address_book_id = NEW.address_book_id, in_trash = NEW.deleted_at IS NOT NULL
+    WHERE contact_id = NEW.id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER contacts_copy_to_emails
+    AFTER UPDATE OF address_book_id, deleted_at ON contacts
+    FOR EACH ROW
+    WHEN (
+        OLD.address_book_id IS DISTINCT FROM NEW.address_book_id
+        OR (OLD.deleted_at IS NULL) <> (NEW.deleted_at IS NULL)
+    )
+    EXECUTE FUNCTION contacts_copy_to_emails();
+
+CREATE UNIQUE INDEX contact_emails_address_lower_idx
+    ON contact_emails (address_book_id, lower(btrim(address)))
+    WHERE NOT in_trash;
+
+DROP INDEX contacts_email_address_lower_idx;
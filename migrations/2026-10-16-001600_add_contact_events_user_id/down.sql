@@ -0,0 +1 @@
+ALTER TABLE contact_events DROP COLUMN user_id;
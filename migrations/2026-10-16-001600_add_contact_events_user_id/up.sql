@@ -0,0 +1,3 @@
+-- Who made each change. Events from before there were accounts have no one,
+-- and neither do those of users who have been deleted since.
+ALTER TABLE contact_events ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE SET NULL;
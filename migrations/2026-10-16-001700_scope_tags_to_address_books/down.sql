@@ -0,0 +1,14 @@
+DROP INDEX tags_address_book_id_name_idx;
+
+-- Back to one tag per name, shared by the contacts of every book.
+UPDATE contact_tags
+SET tag_id = kept.id
+FROM tags, (SELECT DISTINCT ON (name) id, name FROM tags ORDER BY name, id) AS kept
+WHERE tags.id = contact_tags.tag_id
+  AND kept.name = tags.name
+  AND kept.id <> tags.id;
+
+DELETE FROM tags WHERE id NOT IN (SELECT DISTINCT ON (name) id FROM tags ORDER BY name, id);
+
+ALTER TABLE tags DROP COLUMN address_book_id;
+ALTER TABLE tags ADD CONSTRAINT tags_name_key UNIQUE (name);
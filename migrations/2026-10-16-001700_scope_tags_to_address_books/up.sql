@@ -0,0 +1,39 @@
+-- Tags belong to an address book, so that one book's tags aren't offered to, or renamed for,
+-- the members of another. Contacts that aren't in a book yet keep theirs without one,
+-- until the first user adopts them.
+ALTER TABLE tags ADD COLUMN address_book_id INTEGER REFERENCES address_books (id) ON DELETE CASCADE;
+ALTER TABLE tags DROP CONSTRAINT tags_name_key;
+
+-- A copy of every tag for each book it's used in, which takes over that book's contacts.
+-- Names that only differed in case become one tag.
+CREATE TEMPORARY TABLE global_tags AS SELECT id FROM tags;
+
+INSERT INTO tags (name, address_book_id)
+SELECT DISTINCT ON (lower(tags.name), contacts.address_book_id) tags.name, contacts.address_book_id
+FROM contact_tags
+JOIN tags ON tags.id = contact_tags.tag_id
+JOIN contacts ON contacts.id = contact_tags.contact_id
+ORDER BY lower(tags.name), contacts.address_book_id, tags.id;
+
+-- The same contact can have had two tags that are now the same one.
+DELETE FROM contact_tags
+USING tags, contact_tags AS other, tags AS other_tag
+WHERE tags.id = contact_tags.tag_id
+  AND other.contact_id = contact_tags.contact_id
+  AND other_tag.id = other.tag_id
+  AND lower(other_tag.name) = lower(tags.name)
+  AND other.tag_id < contact_tags.tag_id;
+
+UPDATE contact_tags
+SET tag_id = copy.id
+FROM tags AS original, contacts, tags AS copy
+WHERE original.id = contact_tags.tag_id
+  AND contacts.id = contact_tags.contact_id
+  AND lower(copy.name) = lower(original.name)
+  AND copy.address_book_id IS NOT DISTINCT FROM contacts.address_book_id
+  AND copy.id NOT IN (SELECT id FROM global_tags);
+
+DELETE FROM tags WHERE id IN (SELECT id FROM global_tags);
+DROP TABLE global_tags;
+
+CREATE UNIQUE INDEX tags_address_book_id_name_idx ON tags (address_book_id, lower(name));
@@ -1,47 +1,209 @@
 use axum::body::Body;
+use axum::extract::Query;
 use axum::extract::State;
+use axum::http::HeaderMap;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
+use axum::response::Redirect;
 use axum::response::Response;
 use axum::Json;
+use axum_extra::headers::ETag;
+use axum_extra::headers::HeaderMapExt;
+use axum_extra::headers::IfMatch;
+use axum_extra::headers::IfModifiedSince;
+use axum_extra::headers::LastModified;
+use axum_extra::routing::TypedPath;
+use axum_extra::TypedHeader;
+use chrono::DateTime;
+use chrono::Utc;
 use diesel::prelude::*;
 use diesel::QueryDsl;
 use diesel::SelectableHelper;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::AsyncPgConnection;
 use diesel_async::RunQueryDsl;
+use serde::Deserialize;
 use serde::Serialize;
 
+use crate::books;
+use crate::contact_details;
+use crate::duplicates;
+use crate::errors::Problem;
+use crate::history;
+use crate::history::Change;
+use crate::history::Source;
+
+use crate::auth::ApiUser;
+use crate::auth::ApiWriter;
 use crate::html_views::Contacts;
+use crate::html_views::GetContactsParams;
+use crate::html_views::Pagination;
 use crate::html_views::ViewContact;
+use crate::model::Address;
+use crate::model::AddressBookId;
 use crate::model::Contact;
-use crate::model::NewContact;
+use crate::model::ContactAttributes;
+use crate::model::ContactId;
+use crate::model::DetailsInput;
+use crate::model::EventKind;
+use crate::model::PendingContact;
+use crate::model::Permission;
+use crate::model::TaggedContact;
+use crate::model::UserId;
+use crate::model::ValidContact;
+use crate::pagination;
+use crate::pagination::ContactFilter;
+use crate::pagination::Cursor;
+use crate::pagination::Ordering;
+use crate::tags;
+use crate::trash;
 use crate::AppError;
 use crate::AppState;
 
+pub mod v2;
+
+/// Where `main` nests these routes, for building links back into the API.
+pub const API_PREFIX: &str = "/api/v1";
+
+const PAGE_SIZE: i64 = 50;
+
+/// A contact as version 1 hands it out: a single phone and email, its tags and its addresses.
+#[derive(Serialize)]
+pub struct ContactV1 {
+    #[serde(flatten)]
+    pub contact: TaggedContact,
+    pub addresses: Vec<Address>,
+}
+
+/// What version 1 creates and updates contacts with.
+/// The phone and email are checked by the contact form's rules, along with everything else.
+#[derive(Deserialize)]
+pub struct ContactInput {
+    pub first_name: String,
+    pub last_name: String,
+    pub phone: String,
+    pub email_address: String,
+    /// Where to add a new contact, the user's default book if it's missing.
+    /// Updates don't move contacts between books, so they ignore it.
+    pub address_book_id: Option<AddressBookId>,
+}
+
+/// A page of contacts with links to the pages on either side, for every version of the API.
+struct ContactsPage {
+    contacts: Vec<Contact>,
+    next: Option<String>,
+    prev: Option<String>,
+}
+
+/// `None` if the cursor isn't one we handed out.
+async fn load_contacts_page(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    GetContactsParams::Form {
+        query,
+        cursor,
+        sort,
+        direction,
+        tag,
+        recent,
+        ..
+    }: GetContactsParams::Form,
+    api_prefix: &str,
+) -> Result<Option<ContactsPage>, AppError> {
+    let search_string = query.filter(|q| !q.trim().is_empty());
+    let tag = tag.filter(|tag| !tag.is_empty());
+    let cursor = match cursor.as_deref().map(Cursor::decode) {
+        Some(None) => return Ok(None),
+        Some(cursor) => cursor,
+        None => None,
+    };
+    let pagination::Page {
+        contacts,
+        next,
+        prev,
+    } = pagination::load_page(
+        connection,
+        user,
+        ContactFilter {
+            query: search_string.as_deref(),
+            tag: tag.as_deref(),
+            recent,
+        },
+        Ordering::new(
+            search_string.as_deref(),
+            sort,
+            direction.unwrap_or_default(),
+            recent,
+        ),
+        cursor.as_ref(),
+        PAGE_SIZE,
+    )
+    .await?;
+
+    let link = |cursor: Cursor| {
+        format!(
+            "{}{}",
+            api_prefix,
+            Contacts.with_query_params(Pagination {
+                q: search_string.clone(),
+                sort,
+                direction,
+                tag: tag.clone(),
+                recent,
+                cursor: Some(cursor.encode()),
+            })
+        )
+    };
+    Ok(Some(ContactsPage {
+        contacts,
+        next: next.map(link),
+        prev: prev.map(link),
+    }))
+}
+
+/// Pages through contacts with the `next`/`prev` cursors of the response,
+/// using the same ranked search and sorting as the contacts page.
 pub async fn get_contacts(
     _: Contacts,
+    Query(params): Query<GetContactsParams::Form>,
     State(state): State<AppState>,
+    ApiUser(user): ApiUser,
 ) -> Result<Response<Body>, AppError> {
     let mut connection = state.db_pool.get().await?;
-    let contacts = {
-        use crate::schema::contacts::dsl::*;
-
-        contacts
-            .select(Contact::as_select())
-            .get_results(&mut connection)
-            .await?
+    let Some(ContactsPage {
+        contacts,
+        next,
+        prev,
+    }) = load_contacts_page(&mut connection, user.id, params, API_PREFIX).await?
+    else {
+        return Ok(invalid_cursor());
     };
+    let contacts = with_tags_and_addresses(&mut connection, contacts).await?;
 
     #[derive(Serialize)]
     struct Contacts {
-        contacts: Vec<Contact>,
+        contacts: Vec<ContactV1>,
+        next: Option<String>,
+        prev: Option<String>,
     }
 
-    Ok(Json(Contacts { contacts }).into_response())
+    Ok(Json(Contacts {
+        contacts,
+        next,
+        prev,
+    })
+    .into_response())
 }
 
+/// Answers with `304 Not Modified` when the contact hasn't changed since `If-Modified-Since`,
+/// so sync scripts only have to download what did.
+/// The `ETag` is what `update_contact` wants back in `If-Match`.
 pub async fn get_contact(
     ViewContact { id: contact_id }: ViewContact,
     State(state): State<AppState>,
+    ApiUser(user): ApiUser,
+    if_modified_since: Option<TypedHeader<IfModifiedSince>>,
 ) -> Result<Response<Body>, AppError> {
     let mut connection = state.db_pool.get().await?;
     let contact: Option<Contact> = {
@@ -49,64 +211,421 @@ pub async fn get_contact(
 
         contacts
             .find(contact_id)
+            .filter(address_book_id.eq_any(books::allowing(user.id, Permission::View)))
+            .filter(deleted_at.is_null())
             .select(Contact::as_select())
             .first(&mut connection)
             .await
             .optional()?
     };
     match contact {
-        None => Ok((StatusCode::NOT_FOUND, "Could not find contact").into_response()),
-        Some(contact) => Ok(Json(contact).into_response()),
+        None => missing_contact(&mut connection, user.id, contact_id, API_PREFIX).await,
+        Some(contact) => {
+            let headers = (etag(contact.version), last_modified(contact.updated_at));
+            if !modified_since(contact.updated_at, if_modified_since) {
+                return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+            }
+            Ok((headers, Json(single(&mut connection, contact).await?)).into_response())
+        }
     }
 }
 
+/// The body can be the contact as `get_contact` returned it,
+/// but only its attributes are saved, once they pass the same validation as the contact form.
+///
+/// `If-Match` has to carry the contact's `ETag`, so that edits made in the meantime aren't lost.
 pub async fn update_contact(
     ViewContact { id: contact_id }: ViewContact,
     State(state): State<AppState>,
-    Json(contact): Json<Contact>,
+    ApiWriter(user): ApiWriter,
+    headers: HeaderMap,
+    Json(input): Json<ContactInput>,
 ) -> Result<Response<Body>, AppError> {
+    // A missing `If-Match` decodes as one that matches nothing, so it's looked up by hand.
+    let Some(if_match) = headers.typed_get::<IfMatch>() else {
+        return Ok(precondition_required());
+    };
     let mut connection = state.db_pool.get().await?;
-    let contact = {
-        use crate::schema::contacts::dsl::*;
+    check_editable(&mut connection, user.id, contact_id).await?;
+    let ValidContact { attributes, .. } =
+        validate(&mut connection, user.id, &input.to_form(), Some(contact_id)).await?;
+    let contact = connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::contacts::dsl::*;
 
-        diesel::update(contacts.find(contact_id))
-            .set(contact)
-            .returning(Contact::as_returning())
-            .get_result(&mut connection)
-            .await?
-    };
-    Ok(Json(contact).into_response())
+                let before = match lock_if_match(connection, user.id, contact_id, &if_match).await?
+                {
+                    Ok(before) => before,
+                    Err(rejection) => return Ok::<_, AppError>(Err(rejection)),
+                };
+                let contact = diesel::update(contacts.find(contact_id))
+                    .set(&attributes)
+                    .returning(Contact::as_returning())
+                    .get_result(connection)
+                    .await?;
+                contact_details::save_primary(connection, contact.id, &contact).await?;
+                let change = Change {
+                    contact_id,
+                    before: Some(&before),
+                    after: Some(&contact.attributes),
+                };
+                history::record(
+                    connection,
+                    user.id,
+                    Source::Api,
+                    EventKind::Updated,
+                    &[change],
+                )
+                .await?;
+                Ok(Ok(contact))
+            }
+            .scope_boxed()
+        })
+        .await?;
+    match contact {
+        Err(rejection) => Ok(rejection.into_response()),
+        Ok(contact) => Ok((
+            etag(contact.version),
+            Json(single(&mut connection, contact).await?),
+        )
+            .into_response()),
+    }
 }
 
+/// Moves the contact to the trash, where the web UI can restore it.
 pub async fn delete_contact(
     ViewContact { id: contact_id }: ViewContact,
     State(state): State<AppState>,
+    ApiWriter(user): ApiWriter,
 ) -> Result<Response<Body>, AppError> {
     let mut connection = state.db_pool.get().await?;
-    {
-        use crate::schema::contacts::dsl::*;
-
-        diesel::delete(contacts.find(contact_id))
-            .execute(&mut connection)
-            .await?;
-    }
+    books::check(&mut connection, user.id, contact_id, Permission::Delete).await?;
+    trash::trash(&mut connection, user.id, Source::Api, &[contact_id]).await?;
     Ok((StatusCode::OK, "Successfully deleted").into_response())
 }
 
 pub async fn new_contact(
     _: Contacts,
     State(state): State<AppState>,
-    Json(new_contact): Json<NewContact>,
-) -> Result<Json<Contact>, AppError> {
+    ApiWriter(user): ApiWriter,
+    Json(input): Json<ContactInput>,
+) -> Result<Json<ContactV1>, AppError> {
     let mut connection = state.db_pool.get().await?;
-    let new_contact = {
+    let ValidContact { attributes, .. } =
+        validate(&mut connection, user.id, &input.to_form(), None).await?;
+    let book = books::book_to_add_to(&mut connection, user.id, input.address_book_id).await?;
+    let new_contact = connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::contacts;
+
+                let contact = diesel::insert_into(contacts::table)
+                    .values((&attributes, contacts::address_book_id.eq(book)))
+                    .returning(Contact::as_returning())
+                    .get_result(connection)
+                    .await?;
+                contact_details::save_primary(connection, contact.id, &contact).await?;
+                let change = Change {
+                    contact_id: contact.id,
+                    before: None,
+                    after: Some(&contact.attributes),
+                };
+                history::record(
+                    connection,
+                    user.id,
+                    Source::Api,
+                    EventKind::Created,
+                    &[change],
+                )
+                .await?;
+                Ok::<_, AppError>(contact)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    Ok(Json(single(&mut connection, new_contact).await?))
+}
+
+/// Runs the contact form's validation on what came in through the API,
+/// answering with every field error at once.
+async fn validate(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    form: &PendingContact::Form,
+    contact: Option<ContactId>,
+) -> Result<ValidContact, AppError> {
+    duplicates::validate(connection, user, form, contact)
+        .await?
+        .map_err(|errors| AppError::Validation(errors.into_field_errors()))
+}
+
+impl ContactInput {
+    /// Version 1 only has a single phone and email, which the form sees as the primary ones.
+    fn to_form(&self) -> PendingContact::Form {
+        PendingContact::Form {
+            address_book: self.address_book_id,
+            ..PendingContact::Form::new(
+                Some(self.first_name.clone()),
+                Some(self.last_name.clone()),
+                &DetailsInput::primary_only(self.phone.trim(), self.email_address.trim()),
+            )
+        }
+    }
+}
+
+/// Fails before an update's body is looked at, so that validating it can't tell anything about
+/// contacts `user` may not edit: `Forbidden` if their role doesn't allow it, and `NotFound` if
+/// they can't see the contact or it's in the trash.
+async fn check_editable(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    contact_id: ContactId,
+) -> Result<(), AppError> {
+    use crate::schema::contacts;
+
+    books::check(connection, user, contact_id, Permission::Edit).await?;
+    contacts::table
+        .find(contact_id)
+        .filter(contacts::address_book_id.eq_any(books::allowing(user, Permission::Edit)))
+        .filter(contacts::deleted_at.is_null())
+        .select(contacts::id)
+        .first::<ContactId>(connection)
+        .await
+        .optional()?
+        .map(|_| ())
+        .ok_or(AppError::NotFound)
+}
+
+/// A contact that was merged into another one redirects there, anything else is simply not found.
+async fn missing_contact(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    contact_id: ContactId,
+    api_prefix: &str,
+) -> Result<Response<Body>, AppError> {
+    match duplicates::merged_into(connection, user, contact_id).await? {
+        // Temporary, since restoring the merged contact from the trash brings it back.
+        Some(kept) => Ok(
+            Redirect::temporary(&format!("{api_prefix}{}", ViewContact { id: kept }))
+                .into_response(),
+        ),
+        None => Err(AppError::NotFound),
+    }
+}
+
+fn invalid_cursor() -> Response<Body> {
+    Problem::new(StatusCode::BAD_REQUEST, "Bad Request", "Invalid cursor").into_response()
+}
+
+fn etag(version: i32) -> TypedHeader<ETag> {
+    TypedHeader(
+        format!("\"{version}\"")
+            .parse()
+            .expect("a quoted number is a valid entity tag"),
+    )
+}
+
+fn precondition_required() -> Response<Body> {
+    Problem::new(
+        StatusCode::PRECONDITION_REQUIRED,
+        "Precondition Required",
+        "Send the contact's ETag in If-Match",
+    )
+    .into_response()
+}
+
+/// Why a write guarded by `If-Match` didn't go ahead.
+enum Rejection {
+    NotFound,
+    PreconditionFailed,
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response<Body> {
+        match self {
+            Rejection::NotFound => AppError::NotFound.into_response(),
+            Rejection::PreconditionFailed => Problem::new(
+                StatusCode::PRECONDITION_FAILED,
+                "Precondition Failed",
+                "The contact changed since it was fetched",
+            )
+            .into_response(),
+        }
+    }
+}
+
+/// Locks the contact's row for the rest of the transaction and checks its version against `If-Match`,
+/// returning the attributes it's about to be changed from.
+async fn lock_if_match(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    contact_id: ContactId,
+    if_match: &IfMatch,
+) -> Result<Result<ContactAttributes, Rejection>, AppError> {
+    use crate::schema::contacts;
+
+    let current: Option<(i32, ContactAttributes)> = contacts::table
+        .find(contact_id)
+        .filter(contacts::address_book_id.eq_any(books::allowing(user, Permission::Edit)))
+        .filter(contacts::deleted_at.is_null())
+        .select((contacts::version, ContactAttributes::as_select()))
+        .for_update()
+        .first(connection)
+        .await
+        .optional()?;
+    Ok(match current {
+        None => Err(Rejection::NotFound),
+        Some((version, _)) if !if_match.precondition_passes(&etag(version).0) => {
+            Err(Rejection::PreconditionFailed)
+        }
+        Some((_, attributes)) => Ok(attributes),
+    })
+}
+
+fn last_modified(updated_at: DateTime<Utc>) -> TypedHeader<LastModified> {
+    TypedHeader(LastModified::from(std::time::SystemTime::from(updated_at)))
+}
+
+/// HTTP dates only go down to the second, which is what the comparison rounds to.
+fn modified_since(
+    updated_at: DateTime<Utc>,
+    if_modified_since: Option<TypedHeader<IfModifiedSince>>,
+) -> bool {
+    if_modified_since
+        .is_none_or(|TypedHeader(since)| since.is_modified(std::time::SystemTime::from(updated_at)))
+}
+
+async fn with_tags_and_addresses(
+    connection: &mut AsyncPgConnection,
+    contacts: Vec<Contact>,
+) -> Result<Vec<ContactV1>, AppError> {
+    let ids: Vec<ContactId> = contacts.iter().map(|contact| contact.id).collect();
+    let mut details = contact_details::details_for(connection, &ids).await?;
+    Ok(tags::with_tags(connection, contacts)
+        .await?
+        .into_iter()
+        .map(|contact| ContactV1 {
+            addresses: details
+                .remove(&contact.contact.id)
+                .map(|details| details.addresses)
+                .unwrap_or_default(),
+            contact,
+        })
+        .collect())
+}
+
+async fn single(
+    connection: &mut AsyncPgConnection,
+    contact: Contact,
+) -> Result<ContactV1, AppError> {
+    let mut contacts = with_tags_and_addresses(connection, vec![contact]).await?;
+    // One contact in, one contact out.
+    Ok(contacts.remove(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::IF_MATCH;
+
+    use super::*;
+    use crate::auth;
+
+    fn if_match(value: &str) -> IfMatch {
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_MATCH, value.parse().unwrap());
+        headers.typed_get().unwrap()
+    }
+
+    #[test]
+    fn if_match_passes_for_the_current_version_only() {
+        let current = etag(3).0;
+        assert!(if_match("\"3\"").precondition_passes(&current));
+        assert!(if_match("\"2\", \"3\"").precondition_passes(&current));
+        assert!(if_match("*").precondition_passes(&current));
+        assert!(!if_match("\"2\"").precondition_passes(&current));
+        // Weak tags never pass, since `If-Match` compares strongly.
+        assert!(!if_match("W/\"3\"").precondition_passes(&current));
+    }
+
+    async fn test_connection() -> AsyncPgConnection {
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection = AsyncPgConnection::establish(&url).await.unwrap();
+        connection.begin_test_transaction().await.unwrap();
+        connection
+    }
+
+    async fn insert_contact(connection: &mut AsyncPgConnection, book: AddressBookId) -> Contact {
         use crate::schema::contacts;
 
+        let attributes = ContactAttributes {
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            phone: Default::default(),
+            email_address: Default::default(),
+        };
         diesel::insert_into(contacts::table)
-            .values(new_contact)
+            .values((&attributes, contacts::address_book_id.eq(book)))
             .returning(Contact::as_returning())
-            .get_result(&mut connection)
-            .await?
-    };
-    Ok(Json(new_contact))
+            .get_result(connection)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn updates_need_the_current_version_of_a_contact_the_user_can_edit() {
+        use crate::schema::contacts;
+
+        let mut connection = test_connection().await;
+        let owner = auth::create_user(&mut connection, "if-match-owner", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let stranger = auth::create_user(&mut connection, "if-match-stranger", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let book = books::default_book(&mut connection, owner.id)
+            .await
+            .unwrap();
+        let contact = insert_contact(&mut connection, book).await;
+        let current = &format!("\"{}\"", contact.version);
+
+        assert!(check_editable(&mut connection, owner.id, contact.id)
+            .await
+            .is_ok());
+        assert!(matches!(
+            check_editable(&mut connection, stranger.id, contact.id).await,
+            Err(AppError::NotFound)
+        ));
+        assert!(matches!(
+            lock_if_match(&mut connection, stranger.id, contact.id, &if_match(current)).await,
+            Ok(Err(Rejection::NotFound))
+        ));
+        assert!(matches!(
+            lock_if_match(&mut connection, owner.id, contact.id, &if_match(current)).await,
+            Ok(Ok(_))
+        ));
+
+        diesel::update(contacts::table.find(contact.id))
+            .set(contacts::first_name.eq("Augusta"))
+            .execute(&mut connection)
+            .await
+            .unwrap();
+        assert!(matches!(
+            lock_if_match(&mut connection, owner.id, contact.id, &if_match(current)).await,
+            Ok(Err(Rejection::PreconditionFailed))
+        ));
+
+        diesel::update(contacts::table.find(contact.id))
+            .set(contacts::deleted_at.eq(Some(Utc::now())))
+            .execute(&mut connection)
+            .await
+            .unwrap();
+        assert!(matches!(
+            check_editable(&mut connection, owner.id, contact.id).await,
+            Err(AppError::NotFound)
+        ));
+    }
 }
@@ -0,0 +1,333 @@
+//! Version 2 of the API, where contacts carry all of their labelled phones and emails
+//! instead of the single `phone` and `email_address` of version 1.
+
+use axum::body::Body;
+use axum::extract::Query;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::Json;
+use axum_extra::headers::HeaderMapExt;
+use axum_extra::headers::IfMatch;
+use axum_extra::headers::IfModifiedSince;
+use axum_extra::TypedHeader;
+use chrono::DateTime;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::check_editable;
+use super::etag;
+use super::invalid_cursor;
+use super::last_modified;
+use super::load_contacts_page;
+use super::lock_if_match;
+use super::missing_contact;
+use super::modified_since;
+use super::precondition_required;
+use super::validate;
+use super::ContactsPage;
+use crate::auth::ApiUser;
+use crate::auth::ApiWriter;
+use crate::books;
+use crate::contact_details;
+use crate::history;
+use crate::history::Change;
+use crate::history::Source;
+use crate::html_views::Contacts;
+use crate::html_views::GetContactsParams;
+use crate::html_views::ViewContact;
+use crate::model::Address;
+use crate::model::AddressBookId;
+use crate::model::Contact;
+use crate::model::ContactDetails;
+use crate::model::ContactId;
+use crate::model::DetailsInput;
+use crate::model::Email;
+use crate::model::EmailInput;
+use crate::model::EventKind;
+use crate::model::PendingContact;
+use crate::model::Permission;
+use crate::model::Phone;
+use crate::model::PhoneInput;
+use crate::model::UserId;
+use crate::model::ValidContact;
+use crate::tags;
+use crate::AppError;
+use crate::AppState;
+
+/// Where `main` nests these routes, for building links back into the API.
+pub const API_PREFIX: &str = "/api/v2";
+
+#[derive(Serialize)]
+pub struct ContactV2 {
+    pub id: ContactId,
+    pub first_name: String,
+    pub last_name: String,
+    pub phones: Vec<Phone>,
+    pub emails: Vec<Email>,
+    pub addresses: Vec<Address>,
+    pub tags: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub version: i32,
+    pub address_book_id: Option<AddressBookId>,
+}
+
+/// What a contact is created or replaced with.
+/// If none of the phones or emails is marked primary, the first one is.
+#[derive(Deserialize)]
+pub struct ContactInput {
+    pub first_name: String,
+    pub last_name: String,
+    #[serde(default)]
+    pub phones: Vec<PhoneInput>,
+    #[serde(default)]
+    pub emails: Vec<EmailInput>,
+    #[serde(default)]
+    pub addresses: Vec<Address>,
+    /// Where to add a new contact, the user's default book if it's missing.
+    /// Updates don't move contacts between books, so they ignore it.
+    pub address_book_id: Option<AddressBookId>,
+}
+
+impl ContactInput {
+    /// What the contact form would have sent, for going through the same validation.
+    fn to_form(&self) -> PendingContact::Form {
+        PendingContact::Form {
+            address_book: self.address_book_id,
+            ..PendingContact::Form::new(
+                Some(self.first_name.clone()),
+                Some(self.last_name.clone()),
+                &DetailsInput {
+                    phones: self.phones.clone(),
+                    emails: self.emails.clone(),
+                    addresses: self.addresses.clone(),
+                },
+            )
+        }
+    }
+}
+
+async fn with_details(
+    connection: &mut AsyncPgConnection,
+    contacts: Vec<Contact>,
+) -> Result<Vec<ContactV2>, AppError> {
+    let ids: Vec<ContactId> = contacts.iter().map(|contact| contact.id).collect();
+    let mut details = contact_details::details_for(connection, &ids).await?;
+    let tagged = tags::with_tags(connection, contacts).await?;
+    Ok(tagged
+        .into_iter()
+        .map(|tagged| {
+            let ContactDetails {
+                phones,
+                emails,
+                addresses,
+            } = details.remove(&tagged.contact.id).unwrap_or_default();
+            ContactV2 {
+                id: tagged.contact.id,
+                first_name: tagged.contact.attributes.first_name,
+                last_name: tagged.contact.attributes.last_name,
+                phones,
+                emails,
+                addresses,
+                tags: tagged.tags,
+                created_at: tagged.contact.created_at,
+                updated_at: tagged.contact.updated_at,
+                version: tagged.contact.version,
+                address_book_id: tagged.contact.address_book_id,
+            }
+        })
+        .collect())
+}
+
+async fn find(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    contact_id: ContactId,
+) -> Result<Option<ContactV2>, AppError> {
+    let contact: Option<Contact> = {
+        use crate::schema::contacts::dsl::*;
+
+        contacts
+            .find(contact_id)
+            .filter(address_book_id.eq_any(books::allowing(user, Permission::View)))
+            .filter(deleted_at.is_null())
+            .select(Contact::as_select())
+            .first(connection)
+            .await
+            .optional()?
+    };
+    match contact {
+        None => Ok(None),
+        Some(contact) => Ok(with_details(connection, vec![contact]).await?.pop()),
+    }
+}
+
+/// Same parameters and paging as version 1.
+pub async fn get_contacts(
+    _: Contacts,
+    Query(params): Query<GetContactsParams::Form>,
+    State(state): State<AppState>,
+    ApiUser(user): ApiUser,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let Some(ContactsPage {
+        contacts,
+        next,
+        prev,
+    }) = load_contacts_page(&mut connection, user.id, params, API_PREFIX).await?
+    else {
+        return Ok(invalid_cursor());
+    };
+    let contacts = with_details(&mut connection, contacts).await?;
+
+    #[derive(Serialize)]
+    struct Contacts {
+        contacts: Vec<ContactV2>,
+        next: Option<String>,
+        prev: Option<String>,
+    }
+
+    Ok(Json(Contacts {
+        contacts,
+        next,
+        prev,
+    })
+    .into_response())
+}
+
+/// Honours `If-Modified-Since` and hands out an `ETag` like version 1.
+pub async fn get_contact(
+    ViewContact { id: contact_id }: ViewContact,
+    State(state): State<AppState>,
+    ApiUser(user): ApiUser,
+    if_modified_since: Option<TypedHeader<IfModifiedSince>>,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    match find(&mut connection, user.id, contact_id).await? {
+        None => missing_contact(&mut connection, user.id, contact_id, API_PREFIX).await,
+        Some(contact) => {
+            let headers = (etag(contact.version), last_modified(contact.updated_at));
+            if !modified_since(contact.updated_at, if_modified_since) {
+                return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+            }
+            Ok((headers, Json(contact)).into_response())
+        }
+    }
+}
+
+/// Replaces the contact, including every one of its phones and emails.
+/// Like version 1, `If-Match` has to carry the contact's `ETag`.
+pub async fn update_contact(
+    ViewContact { id: contact_id }: ViewContact,
+    State(state): State<AppState>,
+    ApiWriter(user): ApiWriter,
+    headers: HeaderMap,
+    Json(input): Json<ContactInput>,
+) -> Result<Response<Body>, AppError> {
+    // A missing `If-Match` decodes as one that matches nothing, so it's looked up by hand.
+    let Some(if_match) = headers.typed_get::<IfMatch>() else {
+        return Ok(precondition_required());
+    };
+    let mut connection = state.db_pool.get().await?;
+    check_editable(&mut connection, user.id, contact_id).await?;
+    let ValidContact {
+        attributes,
+        details,
+    } = validate(&mut connection, user.id, &input.to_form(), Some(contact_id)).await?;
+    let updated = connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::contacts::dsl::*;
+
+                let before = match lock_if_match(connection, user.id, contact_id, &if_match).await?
+                {
+                    Ok(before) => before,
+                    Err(rejection) => return Ok::<_, AppError>(Err(rejection)),
+                };
+                diesel::update(contacts.find(contact_id))
+                    .set(&attributes)
+                    .execute(connection)
+                    .await?;
+                contact_details::replace_details(connection, contact_id, &details).await?;
+                let change = Change {
+                    contact_id,
+                    before: Some(&before),
+                    after: Some(&attributes),
+                };
+                history::record(
+                    connection,
+                    user.id,
+                    Source::Api,
+                    EventKind::Updated,
+                    &[change],
+                )
+                .await?;
+                Ok(Ok(()))
+            }
+            .scope_boxed()
+        })
+        .await?;
+    if let Err(rejection) = updated {
+        return Ok(rejection.into_response());
+    }
+    match find(&mut connection, user.id, contact_id).await? {
+        Some(contact) => Ok((etag(contact.version), Json(contact)).into_response()),
+        None => Err(AppError::NotFound),
+    }
+}
+
+pub async fn new_contact(
+    _: Contacts,
+    State(state): State<AppState>,
+    ApiWriter(user): ApiWriter,
+    Json(input): Json<ContactInput>,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let ValidContact {
+        attributes,
+        details,
+    } = validate(&mut connection, user.id, &input.to_form(), None).await?;
+    let book = books::book_to_add_to(&mut connection, user.id, input.address_book_id).await?;
+    let contact_id = connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::contacts;
+
+                let contact_id: ContactId = diesel::insert_into(contacts::table)
+                    .values((&attributes, contacts::address_book_id.eq(book)))
+                    .returning(contacts::id)
+                    .get_result(connection)
+                    .await?;
+                contact_details::insert_details(connection, &[(contact_id, &details)]).await?;
+                let change = Change {
+                    contact_id,
+                    before: None,
+                    after: Some(&attributes),
+                };
+                history::record(
+                    connection,
+                    user.id,
+                    Source::Api,
+                    EventKind::Created,
+                    &[change],
+                )
+                .await?;
+                Ok::<_, AppError>(contact_id)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    match find(&mut connection, user.id, contact_id).await? {
+        Some(contact) => Ok(Json(contact).into_response()),
+        None => Err(AppError::NotFound),
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use diesel::prelude::*;
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::books;
+use crate::contact_details;
+use crate::model::Contact;
+use crate::model::ContactDetails;
+use crate::model::ContactId;
+use crate::model::Permission;
+use crate::model::UserId;
+use crate::AppError;
+
+/// How many contacts we pull from the database between progress updates.
+const BATCH_SIZE: i64 = 100;
+
+/// How long a finished archive waits to be downloaded before we throw it away.
+pub const ARCHIVE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How often we look for finished archives that have outlived `ARCHIVE_TTL`.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Each contact in the archive, with all of its phones, emails and addresses.
+#[derive(Serialize)]
+struct ArchivedContact<'a> {
+    #[serde(flatten)]
+    contact: &'a Contact,
+    #[serde(flatten)]
+    details: &'a ContactDetails,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct ArchiveJobId(Uuid);
+
+impl Display for ArchiveJobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ArchiveStatus {
+    /// Fraction of contacts written so far, between 0 and 1.
+    Running(f32),
+    Complete(PathBuf),
+    Failed,
+}
+
+struct ArchiveJob {
+    owner: UserId,
+    status: ArchiveStatus,
+    /// When the job completed or failed, to know when to expire it.
+    finished_at: Option<Instant>,
+}
+
+/// Registry of archive exports, shared between request handlers and the
+/// background tasks doing the actual work.
+///
+/// Removing a job from the registry is how it gets cancelled:
+/// the worker checks in between batches and cleans up after itself.
+/// Each job exports the contacts of the user who started it, and only they can see it.
+/// Finished jobs nobody comes back for are dropped by `expire_periodically`.
+#[derive(Clone, Default)]
+pub struct ArchiveJobs(Arc<Mutex<HashMap<ArchiveJobId, ArchiveJob>>>);
+
+impl ArchiveJobs {
+    pub fn start(&self, pool: Pool<AsyncPgConnection>, owner: UserId) -> ArchiveJobId {
+        let job_id = ArchiveJobId(Uuid::new_v4());
+        self.0.lock().unwrap().insert(
+            job_id,
+            ArchiveJob {
+                owner,
+                status: ArchiveStatus::Running(0.0),
+                finished_at: None,
+            },
+        );
+
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            match jobs.export(job_id, pool, owner).await {
+                Ok(Some(file)) => jobs.finish(job_id, file).await,
+                // Cancelled, nothing left to report.
+                Ok(None) => {}
+                Err(err) => {
+                    tracing::error!(%job_id, %err, "archive failed");
+                    jobs.update(job_id, ArchiveStatus::Failed);
+                }
+            }
+        });
+
+        job_id
+    }
+
+    pub fn status(&self, owner: UserId, job_id: ArchiveJobId) -> Option<ArchiveStatus> {
+        match self.0.lock().unwrap().get(&job_id) {
+            Some(job) if job.owner == owner => Some(job.status.clone()),
+            _ => None,
+        }
+    }
+
+    /// Stops a running job, or throws away the file of a finished one.
+    pub async fn cancel(&self, owner: UserId, job_id: ArchiveJobId) {
+        let removed = {
+            let mut jobs = self.0.lock().unwrap();
+            match jobs.get(&job_id) {
+                Some(job) if job.owner == owner => jobs.remove(&job_id),
+                _ => None,
+            }
+        };
+        if let Some(job) = removed {
+            remove_file(job.status).await;
+        }
+    }
+
+    /// Runs forever, dropping finished jobs that are older than `ttl` along with their files.
+    pub async fn expire_periodically(self, ttl: Duration) {
+        let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.expire_finished_before(Instant::now() - ttl).await;
+        }
+    }
+
+    async fn expire_finished_before(&self, cutoff: Instant) {
+        let expired: Vec<ArchiveJob> = {
+            let mut jobs = self.0.lock().unwrap();
+            let ids: Vec<ArchiveJobId> = jobs
+                .iter()
+                .filter(|(_, job)| job.finished_at.is_some_and(|at| at < cutoff))
+                .map(|(job_id, _)| *job_id)
+                .collect();
+            ids.iter().filter_map(|id| jobs.remove(id)).collect()
+        };
+        for job in expired {
+            remove_file(job.status).await;
+        }
+    }
+
+    /// Only updates jobs that are still registered, so that a cancelled job stays cancelled.
+    /// Returns whether the job is still around.
+    fn update(&self, job_id: ArchiveJobId, status: ArchiveStatus) -> bool {
+        match self.0.lock().unwrap().get_mut(&job_id) {
+            Some(job) => {
+                if !matches!(status, ArchiveStatus::Running(_)) {
+                    job.finished_at = Some(Instant::now());
+                }
+                job.status = status;
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn finish(&self, job_id: ArchiveJobId, file: PathBuf) {
+        if !self.update(job_id, ArchiveStatus::Complete(file.clone())) {
+            let _ = tokio::fs::remove_file(file).await;
+        }
+    }
+
+    /// Writes every contact `owner` can see to a JSON file, returning `None` if the job was cancelled along the way.
+    ///
+    /// The export runs in a single repeatable read transaction so that the archive is a consistent
+    /// snapshot even if contacts are edited while we work through the batches.
+    async fn export(
+        &self,
+        job_id: ArchiveJobId,
+        pool: Pool<AsyncPgConnection>,
+        owner: UserId,
+    ) -> Result<Option<PathBuf>, AppError> {
+        let path = std::env::temp_dir().join(format!("contacts-archive-{job_id}.json"));
+        let mut file = tokio::fs::File::create(&path).await?;
+        let mut connection = pool.get().await?;
+
+        let written = connection
+            .build_transaction()
+            .read_only()
+            .repeatable_read()
+            .run(|connection| {
+                let file = &mut file;
+                async move {
+                    use crate::schema::contacts::dsl::address_book_id;
+                    use crate::schema::contacts::dsl::contacts;
+                    use crate::schema::contacts::dsl::deleted_at;
+                    use crate::schema::contacts::dsl::id;
+
+                    let total: i64 = contacts
+                        .filter(address_book_id.eq_any(books::allowing(owner, Permission::View)))
+                        .filter(deleted_at.is_null())
+                        .count()
+                        .get_result(connection)
+                        .await?;
+                    let mut done = 0;
+                    let mut last_id: Option<ContactId> = None;
+
+                    file.write_all(b"[").await?;
+                    loop {
+                        let mut query = contacts
+                            .filter(
+                                address_book_id.eq_any(books::allowing(owner, Permission::View)),
+                            )
+                            .filter(deleted_at.is_null())
+                            .order(id)
+                            .limit(BATCH_SIZE)
+                            .into_boxed();
+                        if let Some(last_id) = last_id {
+                            query = query.filter(id.gt(last_id));
+                        }
+                        let batch: Vec<Contact> =
+                            query.select(Contact::as_select()).load(connection).await?;
+                        if batch.is_empty() {
+                            break;
+                        }
+
+                        let ids: Vec<ContactId> = batch.iter().map(|contact| contact.id).collect();
+                        let details = contact_details::details_for(connection, &ids).await?;
+                        let no_details = ContactDetails::default();
+                        for contact in &batch {
+                            if done > 0 {
+                                file.write_all(b",\n").await?;
+                            }
+                            let archived = ArchivedContact {
+                                contact,
+                                details: details.get(&contact.id).unwrap_or(&no_details),
+                            };
+                            file.write_all(&serde_json::to_vec(&archived)?).await?;
+                            done += 1;
+                        }
+                        last_id = batch.last().map(|contact| contact.id);
+
+                        let progress = done as f32 / total.max(1) as f32;
+                        if !self.update(job_id, ArchiveStatus::Running(progress)) {
+                            return Ok::<_, AppError>(false);
+                        }
+                    }
+                    file.write_all(b"]").await?;
+                    file.flush().await?;
+
+                    Ok(true)
+                }
+                .scope_boxed()
+            })
+            .await;
+
+        match written {
+            Ok(true) => Ok(Some(path)),
+            Ok(false) => {
+                let _ = tokio::fs::remove_file(path).await;
+                Ok(None)
+            }
+            Err(err) => {
+                let _ = tokio::fs::remove_file(path).await;
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Deletes the archive of a completed job; other jobs have no file to clean up.
+async fn remove_file(status: ArchiveStatus) {
+    if let ArchiveStatus::Complete(file) = status {
+        if let Err(err) = tokio::fs::remove_file(&file).await {
+            tracing::warn!(file = %file.display(), %err, "could not delete archive");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn expiring_drops_finished_jobs_and_their_files() {
+        let jobs = ArchiveJobs::default();
+        let owner: UserId = serde_json::from_str("1").unwrap();
+        let file = std::env::temp_dir().join(format!("contacts-archive-{}.json", Uuid::new_v4()));
+        tokio::fs::write(&file, b"[]").await.unwrap();
+        let finished = ArchiveJobId(Uuid::new_v4());
+        let running = ArchiveJobId(Uuid::new_v4());
+        for job_id in [finished, running] {
+            jobs.0.lock().unwrap().insert(
+                job_id,
+                ArchiveJob {
+                    owner,
+                    status: ArchiveStatus::Running(0.0),
+                    finished_at: None,
+                },
+            );
+        }
+        jobs.finish(finished, file.clone()).await;
+
+        jobs.expire_finished_before(Instant::now() - ARCHIVE_TTL)
+            .await;
+        assert!(jobs.status(owner, finished).is_some());
+
+        jobs.expire_finished_before(Instant::now() + Duration::from_secs(1))
+            .await;
+        assert!(jobs.status(owner, finished).is_none());
+        assert!(!file.exists());
+        assert!(jobs.status(owner, running).is_some());
+    }
+}
@@ -0,0 +1,412 @@
+//! Accounts with argon2-hashed passwords, and the sessions people log in with.
+//!
+//! The session cookie is signed with the key from `SESSION_KEY` and only carries the id of a row
+//! in `sessions`, so logging out ends the session for good.
+//! The API doesn't use sessions but personal tokens, see `tokens`.
+//! Every query for contacts is scoped to the address books of the `CurrentUser`, or of the
+//! `ApiUser` and `ApiWriter` in the API, see `books`.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::rand_core::RngCore;
+use argon2::password_hash::PasswordHash;
+use argon2::password_hash::PasswordHasher;
+use argon2::password_hash::PasswordVerifier;
+use argon2::password_hash::SaltString;
+use argon2::Argon2;
+use axum::extract::FromRequestParts;
+use axum::http::header::WWW_AUTHENTICATE;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Redirect;
+use axum::response::Response;
+use axum_extra::extract::cookie::Cookie;
+use axum_extra::extract::cookie::Key;
+use axum_extra::extract::cookie::SameSite;
+use axum_extra::extract::SignedCookieJar;
+use axum_extra::headers::authorization::Bearer;
+use axum_extra::headers::Authorization;
+use axum_extra::headers::HeaderMapExt;
+use chrono::Duration;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use uuid::Uuid;
+
+use crate::books;
+use crate::errors::Problem;
+use crate::html_views::Login;
+use crate::model::Scope;
+use crate::model::User;
+use crate::model::UserId;
+use crate::tokens;
+use crate::AppError;
+use crate::AppState;
+
+const SESSION_COOKIE: &str = "session";
+
+/// Held while a new user is created, so that only one of several sign-ups at once can be the first.
+const FIRST_USER_LOCK: i64 = 0x6669_7273_7475_7365;
+
+/// How long a login lasts, or until the browser is closed since the cookie doesn't outlive it.
+const SESSION_LENGTH: Duration = Duration::days(30);
+
+/// Decodes the base64 `SESSION_KEY`, which needs at least 64 bytes.
+pub fn cookie_key(encoded: &str) -> Option<Key> {
+    use base64::Engine;
+
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    Key::try_from(bytes.as_slice()).ok()
+}
+
+/// 32 random bytes as URL-safe base64, for anything that has to be impossible to guess.
+pub(crate) fn random_secret() -> String {
+    use base64::Engine;
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(secret)
+}
+
+fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Returns `None` if the username is taken.
+///
+/// Everyone starts out with an address book of their own, and contacts from before there were
+/// any users go to the book of whoever signs up first.
+pub async fn create_user(
+    connection: &mut AsyncPgConnection,
+    username: &str,
+    password: &str,
+) -> Result<Option<User>, AppError> {
+    let password_hash = hash_password(password)?;
+    connection
+        .transaction(|connection| {
+            let password_hash = &password_hash;
+            async move {
+                use crate::schema::contacts;
+                use crate::schema::tags;
+                use crate::schema::users;
+
+                // Sign-ups wait on each other until the end of the transaction, so that each one
+                // counts the users that came before it.
+                diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+                    .bind::<BigInt, _>(FIRST_USER_LOCK)
+                    .execute(connection)
+                    .await?;
+                let user: Option<User> = diesel::insert_into(users::table)
+                    .values((
+                        users::username.eq(username),
+                        users::password_hash.eq(password_hash),
+                    ))
+                    .on_conflict_do_nothing()
+                    .returning(User::as_returning())
+                    .get_result(connection)
+                    .await
+                    .optional()?;
+                let Some(user) = user else {
+                    return Ok::<_, AppError>(None);
+                };
+                let book = books::create(connection, user.id, &books::personal_name(&user)).await?;
+                let users: i64 = users::table.count().get_result(connection).await?;
+                if users == 1 {
+                    diesel::update(contacts::table.filter(contacts::address_book_id.is_null()))
+                        .set(contacts::address_book_id.eq(book.id))
+                        .execute(connection)
+                        .await?;
+                    diesel::update(tags::table.filter(tags::address_book_id.is_null()))
+                        .set(tags::address_book_id.eq(book.id))
+                        .execute(connection)
+                        .await?;
+                }
+                Ok(Some(user))
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// The user with this username and password, if there is one.
+pub async fn authenticate(
+    connection: &mut AsyncPgConnection,
+    username: &str,
+    password: &str,
+) -> Result<Option<User>, AppError> {
+    use crate::schema::users;
+
+    let user: Option<(User, String)> = users::table
+        .filter(users::username.eq(username))
+        .select((User::as_select(), users::password_hash))
+        .first(connection)
+        .await
+        .optional()?;
+    Ok(user
+        .filter(|(_, hash)| verify_password(password, hash))
+        .map(|(user, _)| user))
+}
+
+/// Logs the user in, adding the session cookie to `jar`.
+/// The session gets its own CSRF token, see `csrf`.
+pub async fn start_session(
+    connection: &mut AsyncPgConnection,
+    jar: SignedCookieJar,
+    user_id: UserId,
+) -> Result<SignedCookieJar, AppError> {
+    use crate::schema::sessions;
+
+    let session_id = Uuid::new_v4();
+    diesel::insert_into(sessions::table)
+        .values((
+            sessions::id.eq(session_id),
+            sessions::user_id.eq(user_id),
+            sessions::expires_at.eq(Utc::now() + SESSION_LENGTH),
+            sessions::csrf_token.eq(random_secret()),
+        ))
+        .execute(connection)
+        .await?;
+    let cookie = Cookie::build((SESSION_COOKIE, session_id.to_string()))
+        .path("/")
+        .http_only(true)
+        .secure(!cfg!(debug_assertions))
+        .same_site(SameSite::Lax);
+    Ok(jar.add(cookie))
+}
+
+/// Logs out whoever `jar` belongs to, removing the session cookie.
+pub async fn end_session(
+    connection: &mut AsyncPgConnection,
+    jar: SignedCookieJar,
+) -> Result<SignedCookieJar, AppError> {
+    use crate::schema::sessions;
+
+    if let Some(session_id) = session_id(&jar) {
+        diesel::delete(sessions::table.find(session_id))
+            .execute(connection)
+            .await?;
+    }
+    Ok(jar.remove(Cookie::build(SESSION_COOKIE).path("/")))
+}
+
+pub(crate) fn session_id(jar: &SignedCookieJar) -> Option<Uuid> {
+    jar.get(SESSION_COOKIE)?.value().parse().ok()
+}
+
+/// Who the session cookie belongs to, if it's still valid.
+async fn session_user(parts: &mut Parts, state: &AppState) -> Result<Option<User>, AppError> {
+    use crate::schema::sessions;
+    use crate::schema::users;
+
+    let Ok(jar) = SignedCookieJar::<Key>::from_request_parts(parts, state).await;
+    let Some(session_id) = session_id(&jar) else {
+        return Ok(None);
+    };
+    let mut connection = state.db_pool.get().await?;
+    Ok(sessions::table
+        .inner_join(users::table)
+        .filter(sessions::id.eq(session_id))
+        .filter(sessions::expires_at.gt(Utc::now()))
+        .select(User::as_select())
+        .first(&mut connection)
+        .await
+        .optional()?)
+}
+
+/// The logged in user of a page, sending everyone else to the login page.
+pub struct CurrentUser(pub User);
+
+pub enum LoginRequired {
+    Page,
+    /// htmx would swap the login page into whatever the request was for, so it's told to go there instead.
+    Htmx,
+    Error(AppError),
+}
+
+impl IntoResponse for LoginRequired {
+    fn into_response(self) -> Response {
+        match self {
+            LoginRequired::Page => Redirect::to(&Login.to_string()).into_response(),
+            LoginRequired::Htmx => [("HX-Redirect", Login.to_string())].into_response(),
+            LoginRequired::Error(err) => err.into_response(),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for CurrentUser {
+    type Rejection = LoginRequired;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        match session_user(parts, state).await {
+            Ok(Some(user)) => Ok(CurrentUser(user)),
+            Ok(None) if parts.headers.contains_key("hx-request") => Err(LoginRequired::Htmx),
+            Ok(None) => Err(LoginRequired::Page),
+            Err(err) => Err(LoginRequired::Error(err)),
+        }
+    }
+}
+
+/// What the API answers when there's no valid bearer token for a request.
+pub enum ApiAuthError {
+    Missing,
+    Invalid,
+    /// The token is fine, it just isn't allowed to do this.
+    InsufficientScope(Scope),
+    Error(AppError),
+}
+
+impl IntoResponse for ApiAuthError {
+    fn into_response(self) -> Response {
+        let problem = match self {
+            ApiAuthError::Missing => {
+                AppError::Unauthorized("Send an API token as `Authorization: Bearer <token>`")
+                    .into()
+            }
+            ApiAuthError::Invalid => {
+                AppError::Unauthorized("The API token is invalid or has been revoked").into()
+            }
+            ApiAuthError::InsufficientScope(scope) => Problem::new(
+                StatusCode::FORBIDDEN,
+                "Insufficient Scope",
+                format!("The API token needs the `{}` scope", scope.as_str()),
+            ),
+            ApiAuthError::Error(err) => return err.into_response(),
+        };
+        ([(WWW_AUTHENTICATE, "Bearer")], problem).into_response()
+    }
+}
+
+/// The owner of the request's bearer token, as long as the token has `scope`.
+async fn bearer_user(parts: &Parts, state: &AppState, scope: Scope) -> Result<User, ApiAuthError> {
+    let Some(Authorization(bearer)) = parts.headers.typed_get::<Authorization<Bearer>>() else {
+        return Err(ApiAuthError::Missing);
+    };
+    let mut connection = state
+        .db_pool
+        .get()
+        .await
+        .map_err(|err| ApiAuthError::Error(err.into()))?;
+    match tokens::authenticate(&mut connection, bearer.token()).await {
+        Ok(Some((user, scopes))) if scopes.contains(&scope) => Ok(user),
+        Ok(Some(_)) => Err(ApiAuthError::InsufficientScope(scope)),
+        Ok(None) => Err(ApiAuthError::Invalid),
+        Err(err) => Err(ApiAuthError::Error(err)),
+    }
+}
+
+/// The user behind an API request that only reads, whose token needs the `read` scope.
+pub struct ApiUser(pub User);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for ApiUser {
+    type Rejection = ApiAuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        bearer_user(parts, state, Scope::Read).await.map(ApiUser)
+    }
+}
+
+/// The user behind an API request that changes contacts, whose token needs the `write` scope.
+pub struct ApiWriter(pub User);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for ApiWriter {
+    type Rejection = ApiAuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        bearer_user(parts, state, Scope::Write).await.map(ApiWriter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_connection() -> AsyncPgConnection {
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection = AsyncPgConnection::establish(&url).await.unwrap();
+        connection.begin_test_transaction().await.unwrap();
+        connection
+    }
+
+    #[test]
+    fn passwords_are_salted_and_verified_against_their_hash() {
+        let first = hash_password("correct horse").unwrap();
+        let second = hash_password("correct horse").unwrap();
+        assert_ne!(first, second);
+        assert!(verify_password("correct horse", &first));
+        assert!(!verify_password("battery staple", &first));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[test]
+    fn session_keys_need_64_bytes_of_base64() {
+        use base64::Engine;
+
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        assert!(cookie_key(&encode(&[7; 64])).is_some());
+        assert!(cookie_key(&format!(" {}\n", encode(&[7; 64]))).is_some());
+        assert!(cookie_key(&encode(&[7; 32])).is_none());
+        assert!(cookie_key("not base64!").is_none());
+    }
+
+    #[tokio::test]
+    async fn users_log_in_with_the_password_they_signed_up_with() {
+        let mut connection = test_connection().await;
+        let user = create_user(&mut connection, "auth-ada", "correct horse")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(create_user(&mut connection, "auth-ada", "another one")
+            .await
+            .unwrap()
+            .is_none());
+
+        let logged_in = authenticate(&mut connection, "auth-ada", "correct horse")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(logged_in.id, user.id);
+        assert!(authenticate(&mut connection, "auth-ada", "another one")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(
+            authenticate(&mut connection, "auth-nobody", "correct horse")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        // Everyone starts out with a book of their own.
+        assert!(books::default_book(&mut connection, user.id).await.is_ok());
+    }
+}
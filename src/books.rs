@@ -0,0 +1,377 @@
+//! Address books, which every contact belongs to, and the users who share them.
+//!
+//! Everyone gets a book of their own when they sign up, and can make more and invite others to
+//! them as viewers, editors or admins. Every query for contacts is scoped to the books the user
+//! has the needed `Permission` in, with `contacts::address_book_id.eq_any(books::allowing(..))`.
+
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Int4;
+use diesel::sql_types::Nullable;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+
+use crate::model::AddressBook;
+use crate::model::AddressBookId;
+use crate::model::ContactId;
+use crate::model::Member;
+use crate::model::Membership;
+use crate::model::Permission;
+use crate::model::Role;
+use crate::model::User;
+use crate::model::UserId;
+use crate::AppError;
+
+/// What happened to a request to change who's a member.
+pub enum MembershipChange {
+    Done,
+    UnknownUser,
+    /// Every book needs an admin, so the last one can't leave or step down.
+    LastAdmin,
+}
+
+fn roles_allowing(permission: Permission) -> Vec<&'static str> {
+    Role::ALL
+        .into_iter()
+        .filter(|role| role.can(permission))
+        .map(Role::as_str)
+        .collect()
+}
+
+/// The books `user` has `permission` in, for filtering contacts by their `address_book_id`.
+pub fn allowing(
+    user: UserId,
+    permission: Permission,
+) -> crate::schema::memberships::BoxedQuery<'static, Pg, Nullable<Int4>> {
+    use crate::schema::memberships;
+
+    memberships::table
+        .filter(memberships::user_id.eq(user))
+        .filter(memberships::role.eq_any(roles_allowing(permission)))
+        .select(memberships::address_book_id.nullable())
+        .into_boxed()
+}
+
+/// Makes a new book with `user` as its admin.
+pub async fn create(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    name: &str,
+) -> Result<AddressBook, AppError> {
+    connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::address_books;
+                use crate::schema::memberships;
+
+                let book: AddressBook = diesel::insert_into(address_books::table)
+                    .values(address_books::name.eq(name))
+                    .returning(AddressBook::as_returning())
+                    .get_result(connection)
+                    .await?;
+                diesel::insert_into(memberships::table)
+                    .values((
+                        memberships::address_book_id.eq(book.id),
+                        memberships::user_id.eq(user),
+                        memberships::role.eq(Role::Admin.as_str()),
+                    ))
+                    .execute(connection)
+                    .await?;
+                Ok::<_, AppError>(book)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// The name of the book everyone starts out with.
+pub fn personal_name(user: &User) -> String {
+    format!("{}'s Contacts", user.username)
+}
+
+/// Every book `user` is a member of, oldest first.
+pub async fn memberships_of(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Vec<Membership>, AppError> {
+    use crate::schema::address_books;
+    use crate::schema::memberships;
+
+    Ok(memberships::table
+        .inner_join(address_books::table)
+        .filter(memberships::user_id.eq(user))
+        .order(address_books::id)
+        .select(Membership::as_select())
+        .load(connection)
+        .await?)
+}
+
+/// `user`'s role in `book`, if they're a member.
+pub async fn membership(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    book: AddressBookId,
+) -> Result<Option<Membership>, AppError> {
+    use crate::schema::address_books;
+    use crate::schema::memberships;
+
+    Ok(memberships::table
+        .inner_join(address_books::table)
+        .filter(memberships::user_id.eq(user))
+        .filter(memberships::address_book_id.eq(book))
+        .select(Membership::as_select())
+        .first(connection)
+        .await
+        .optional()?)
+}
+
+/// `user`'s role in the book of the contact, if they can see it at all.
+pub async fn contact_role(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    contact_id: ContactId,
+) -> Result<Option<Role>, AppError> {
+    use crate::schema::contacts;
+    use crate::schema::memberships;
+
+    let role: Option<String> = contacts::table
+        .inner_join(
+            memberships::table.on(memberships::address_book_id
+                .nullable()
+                .eq(contacts::address_book_id)),
+        )
+        .filter(contacts::id.eq(contact_id))
+        .filter(memberships::user_id.eq(user))
+        .select(memberships::role)
+        .first(connection)
+        .await
+        .optional()?;
+    Ok(role.and_then(|role| Role::try_from(role).ok()))
+}
+
+/// Fails with `Forbidden` if `user` can see the contact but their role doesn't allow `permission`,
+/// and returns the role otherwise.
+/// Contacts they can't see at all are left for the scoped queries not to find.
+pub async fn check(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    contact_id: ContactId,
+    permission: Permission,
+) -> Result<Option<Role>, AppError> {
+    match contact_role(connection, user, contact_id).await? {
+        Some(role) if !role.can(permission) => Err(AppError::Forbidden),
+        role => Ok(role),
+    }
+}
+
+/// Where contacts go when nothing says which book they're for:
+/// the oldest book `user` can add to, which is usually their own.
+pub async fn default_book(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<AddressBookId, AppError> {
+    use crate::schema::memberships;
+
+    memberships::table
+        .filter(memberships::user_id.eq(user))
+        .filter(memberships::role.eq_any(roles_allowing(Permission::Edit)))
+        .order(memberships::address_book_id)
+        .select(memberships::address_book_id)
+        .first(connection)
+        .await
+        .optional()?
+        .ok_or(AppError::Forbidden)
+}
+
+/// The book `user` picked to add contacts to, as long as they can, or else their default one.
+pub async fn book_to_add_to(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    book: Option<AddressBookId>,
+) -> Result<AddressBookId, AppError> {
+    let Some(book) = book else {
+        return default_book(connection, user).await;
+    };
+    match membership(connection, user, book).await? {
+        Some(membership) if membership.role.can(Permission::Edit) => Ok(book),
+        _ => Err(AppError::Forbidden),
+    }
+}
+
+/// Everyone in `book`, admins first.
+pub async fn members(
+    connection: &mut AsyncPgConnection,
+    book: AddressBookId,
+) -> Result<Vec<Member>, AppError> {
+    use crate::schema::memberships;
+    use crate::schema::users;
+
+    let mut members: Vec<Member> = memberships::table
+        .inner_join(users::table)
+        .filter(memberships::address_book_id.eq(book))
+        .order(users::username)
+        .select(Member::as_select())
+        .load(connection)
+        .await?;
+    members.sort_by_key(|member| std::cmp::Reverse(member.role));
+    Ok(members)
+}
+
+/// Whether taking `user`'s admin role away would leave `book` without one.
+async fn is_last_admin(
+    connection: &mut AsyncPgConnection,
+    book: AddressBookId,
+    user: UserId,
+) -> Result<bool, AppError> {
+    use crate::schema::memberships;
+
+    let admins: Vec<UserId> = memberships::table
+        .filter(memberships::address_book_id.eq(book))
+        .filter(memberships::role.eq(Role::Admin.as_str()))
+        .select(memberships::user_id)
+        .for_update()
+        .load(connection)
+        .await?;
+    Ok(admins == [user])
+}
+
+/// Adds the user called `username` to `book`, or changes their role if they're already in it.
+pub async fn invite(
+    connection: &mut AsyncPgConnection,
+    book: AddressBookId,
+    username: &str,
+    role: Role,
+) -> Result<MembershipChange, AppError> {
+    connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::memberships;
+                use crate::schema::users;
+
+                let user: Option<UserId> = users::table
+                    .filter(users::username.eq(username))
+                    .select(users::id)
+                    .first(connection)
+                    .await
+                    .optional()?;
+                let Some(user) = user else {
+                    return Ok::<_, AppError>(MembershipChange::UnknownUser);
+                };
+                if role != Role::Admin && is_last_admin(connection, book, user).await? {
+                    return Ok(MembershipChange::LastAdmin);
+                }
+                diesel::insert_into(memberships::table)
+                    .values((
+                        memberships::address_book_id.eq(book),
+                        memberships::user_id.eq(user),
+                        memberships::role.eq(role.as_str()),
+                    ))
+                    .on_conflict((memberships::address_book_id, memberships::user_id))
+                    .do_update()
+                    .set(memberships::role.eq(role.as_str()))
+                    .execute(connection)
+                    .await?;
+                Ok(MembershipChange::Done)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+pub async fn remove_member(
+    connection: &mut AsyncPgConnection,
+    book: AddressBookId,
+    user: UserId,
+) -> Result<MembershipChange, AppError> {
+    connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::memberships;
+
+                if is_last_admin(connection, book, user).await? {
+                    return Ok::<_, AppError>(MembershipChange::LastAdmin);
+                }
+                diesel::delete(memberships::table.find((book, user)))
+                    .execute(connection)
+                    .await?;
+                Ok(MembershipChange::Done)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+
+    async fn test_connection() -> AsyncPgConnection {
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection = AsyncPgConnection::establish(&url).await.unwrap();
+        connection.begin_test_transaction().await.unwrap();
+        connection
+    }
+
+    #[tokio::test]
+    async fn the_last_admin_can_neither_leave_nor_step_down() {
+        let mut connection = test_connection().await;
+        let ada = auth::create_user(&mut connection, "books-ada", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let grace = auth::create_user(&mut connection, "books-grace", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let book = default_book(&mut connection, ada.id).await.unwrap();
+
+        assert!(matches!(
+            invite(&mut connection, book, "books-grace", Role::Editor)
+                .await
+                .unwrap(),
+            MembershipChange::Done
+        ));
+        assert!(matches!(
+            remove_member(&mut connection, book, ada.id).await.unwrap(),
+            MembershipChange::LastAdmin
+        ));
+        assert!(matches!(
+            invite(&mut connection, book, "books-ada", Role::Viewer)
+                .await
+                .unwrap(),
+            MembershipChange::LastAdmin
+        ));
+        assert!(matches!(
+            invite(&mut connection, book, "books-nobody", Role::Viewer)
+                .await
+                .unwrap(),
+            MembershipChange::UnknownUser
+        ));
+
+        // Once someone else is an admin too, the first one can go.
+        invite(&mut connection, book, "books-grace", Role::Admin)
+            .await
+            .unwrap();
+        assert!(matches!(
+            remove_member(&mut connection, book, ada.id).await.unwrap(),
+            MembershipChange::Done
+        ));
+        let members: Vec<UserId> = members(&mut connection, book)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|member| member.user.id)
+            .collect();
+        assert_eq!(members, [grace.id]);
+        assert!(matches!(
+            remove_member(&mut connection, book, grace.id)
+                .await
+                .unwrap(),
+            MembershipChange::LastAdmin
+        ));
+    }
+}
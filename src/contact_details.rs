@@ -0,0 +1,288 @@
+//! The labelled phone numbers, email addresses and postal addresses of contacts.
+//!
+//! The primary number and address are also copied onto the contact itself,
+//! so whatever saves a contact has to save its details alongside it, in the same transaction.
+
+use std::collections::HashMap;
+
+use diesel::prelude::*;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+
+use crate::model::Address;
+use crate::model::ContactAttributes;
+use crate::model::ContactDetails;
+use crate::model::ContactId;
+use crate::model::Email;
+use crate::model::Phone;
+use crate::model::PhoneNumber;
+use crate::AppError;
+
+/// The details of each of `contact_ids`, in one query per table.
+/// Contacts without any details are left out of the map.
+pub async fn details_for(
+    connection: &mut AsyncPgConnection,
+    contact_ids: &[ContactId],
+) -> Result<HashMap<ContactId, ContactDetails>, AppError> {
+    use crate::schema::contact_addresses;
+    use crate::schema::contact_emails;
+    use crate::schema::contact_phones;
+
+    let phones: Vec<(ContactId, Phone)> = contact_phones::table
+        .filter(contact_phones::contact_id.eq_any(contact_ids))
+        .order((contact_phones::is_primary.desc(), contact_phones::id))
+        .select((contact_phones::contact_id, Phone::as_select()))
+        .load(connection)
+        .await?;
+    let emails: Vec<(ContactId, Email)> = contact_emails::table
+        .filter(contact_emails::contact_id.eq_any(contact_ids))
+        .order((contact_emails::is_primary.desc(), contact_emails::id))
+        .select((contact_emails::contact_id, Email::as_select()))
+        .load(connection)
+        .await?;
+    let addresses: Vec<(ContactId, Address)> = contact_addresses::table
+        .filter(contact_addresses::contact_id.eq_any(contact_ids))
+        .order(contact_addresses::id)
+        .select((contact_addresses::contact_id, Address::as_select()))
+        .load(connection)
+        .await?;
+
+    let mut by_contact: HashMap<ContactId, ContactDetails> = HashMap::new();
+    for (contact_id, phone) in phones {
+        by_contact.entry(contact_id).or_default().phones.push(phone);
+    }
+    for (contact_id, email) in emails {
+        by_contact.entry(contact_id).or_default().emails.push(email);
+    }
+    for (contact_id, address) in addresses {
+        by_contact
+            .entry(contact_id)
+            .or_default()
+            .addresses
+            .push(address);
+    }
+    Ok(by_contact)
+}
+
+pub async fn details_of(
+    connection: &mut AsyncPgConnection,
+    contact_id: ContactId,
+) -> Result<ContactDetails, AppError> {
+    Ok(details_for(connection, &[contact_id])
+        .await?
+        .remove(&contact_id)
+        .unwrap_or_default())
+}
+
+/// Adds the details of freshly inserted contacts.
+pub async fn insert_details(
+    connection: &mut AsyncPgConnection,
+    details: &[(ContactId, &ContactDetails)],
+) -> Result<(), AppError> {
+    use crate::schema::contact_addresses;
+    use crate::schema::contact_emails;
+    use crate::schema::contact_phones;
+
+    let phones: Vec<_> = details
+        .iter()
+        .flat_map(|(contact_id, details)| {
+            details.phones.iter().map(move |phone| {
+                (
+                    contact_phones::contact_id.eq(*contact_id),
+                    contact_phones::label.eq(&phone.label),
+                    contact_phones::number.eq(&phone.number),
+                    contact_phones::is_primary.eq(phone.primary),
+                )
+            })
+        })
+        .collect();
+    let emails: Vec<_> = details
+        .iter()
+        .flat_map(|(contact_id, details)| {
+            details.emails.iter().map(move |email| {
+                (
+                    contact_emails::contact_id.eq(*contact_id),
+                    contact_emails::label.eq(&email.label),
+                    contact_emails::address.eq(&email.address),
+                    contact_emails::is_primary.eq(email.primary),
+                )
+            })
+        })
+        .collect();
+    let addresses: Vec<_> = details
+        .iter()
+        .flat_map(|(contact_id, details)| {
+            details.addresses.iter().map(move |address| {
+                (
+                    contact_addresses::contact_id.eq(*contact_id),
+                    contact_addresses::label.eq(&address.label),
+                    contact_addresses::street.eq(&address.street),
+                    contact_addresses::city.eq(&address.city),
+                    contact_addresses::region.eq(&address.region),
+                    contact_addresses::postal_code.eq(&address.postal_code),
+                    contact_addresses::country.eq(&address.country),
+                )
+            })
+        })
+        .collect();
+
+    // Stay well clear of Postgres' limit on bind parameters per statement.
+    for chunk in phones.chunks(1000) {
+        diesel::insert_into(contact_phones::table)
+            .values(chunk)
+            .execute(connection)
+            .await?;
+    }
+    for chunk in emails.chunks(1000) {
+        diesel::insert_into(contact_emails::table)
+            .values(chunk)
+            .execute(connection)
+            .await?;
+    }
+    for chunk in addresses.chunks(1000) {
+        diesel::insert_into(contact_addresses::table)
+            .values(chunk)
+            .execute(connection)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Swaps out every phone, email and address of a contact for `details`.
+///
+/// The contact's row isn't necessarily touched when only its details change,
+/// so this bumps its `updated_at` itself.
+pub async fn replace_details(
+    connection: &mut AsyncPgConnection,
+    contact_id: ContactId,
+    details: &ContactDetails,
+) -> Result<(), AppError> {
+    use crate::schema::contact_addresses;
+    use crate::schema::contact_emails;
+    use crate::schema::contact_phones;
+    use crate::schema::contacts;
+
+    if details_of(connection, contact_id).await? == *details {
+        return Ok(());
+    }
+    diesel::update(contacts::table.find(contact_id))
+        .set(contacts::updated_at.eq(diesel::dsl::now))
+        .execute(connection)
+        .await?;
+
+    diesel::delete(contact_phones::table.filter(contact_phones::contact_id.eq(contact_id)))
+        .execute(connection)
+        .await?;
+    diesel::delete(contact_emails::table.filter(contact_emails::contact_id.eq(contact_id)))
+        .execute(connection)
+        .await?;
+    diesel::delete(contact_addresses::table.filter(contact_addresses::contact_id.eq(contact_id)))
+        .execute(connection)
+        .await?;
+    insert_details(connection, &[(contact_id, details)]).await
+}
+
+/// For writers that only know about one phone and one email, like the v1 API:
+/// the primary entries are brought in line with `contact`, and the others are left alone.
+pub async fn save_primary(
+    connection: &mut AsyncPgConnection,
+    contact_id: ContactId,
+    contact: &ContactAttributes,
+) -> Result<(), AppError> {
+    use crate::schema::contact_emails;
+    use crate::schema::contact_phones;
+
+    let primary_phone = contact_phones::table
+        .filter(contact_phones::contact_id.eq(contact_id))
+        .filter(contact_phones::is_primary);
+    if contact.phone.is_empty() {
+        diesel::delete(primary_phone).execute(connection).await?;
+    } else {
+        let updated = diesel::update(primary_phone)
+            .set(contact_phones::number.eq(&contact.phone))
+            .execute(connection)
+            .await?;
+        if updated == 0 {
+            diesel::insert_into(contact_phones::table)
+                .values((
+                    contact_phones::contact_id.eq(contact_id),
+                    contact_phones::number.eq(&contact.phone),
+                    contact_phones::is_primary.eq(true),
+                ))
+                .execute(connection)
+                .await?;
+        }
+    }
+
+    let primary_email = contact_emails::table
+        .filter(contact_emails::contact_id.eq(contact_id))
+        .filter(contact_emails::is_primary);
+    if contact.email_address.is_empty() {
+        diesel::delete(primary_email).execute(connection).await?;
+    } else {
+        let updated = diesel::update(primary_email)
+            .set(contact_emails::address.eq(&contact.email_address))
+            .execute(connection)
+            .await?;
+        if updated == 0 {
+            diesel::insert_into(contact_emails::table)
+                .values((
+                    contact_emails::contact_id.eq(contact_id),
+                    contact_emails::address.eq(&contact.email_address),
+                    contact_emails::is_primary.eq(true),
+                ))
+                .execute(connection)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the phone numbers saved before they were kept in E.164, reading national numbers as
+/// being from the phone region. Numbers that don't parse are left as they are, and returned
+/// for someone to look at.
+pub async fn normalize_phone_numbers(
+    connection: &mut AsyncPgConnection,
+) -> Result<Vec<String>, AppError> {
+    use crate::schema::contact_phones;
+    use crate::schema::contacts;
+
+    // `SIMILAR TO` has to match the whole number.
+    const E164: &str = r"\+[0-9]+";
+
+    let mut unparsed = vec![];
+    let phones: Vec<(i32, String)> = contact_phones::table
+        .filter(contact_phones::number.ne(""))
+        .filter(contact_phones::number.not_similar_to(E164))
+        .select((contact_phones::id, contact_phones::number))
+        .load(connection)
+        .await?;
+    for (id, number) in phones {
+        match PhoneNumber::parse(&number) {
+            Some(parsed) => {
+                diesel::update(contact_phones::table.find(id))
+                    .set(contact_phones::number.eq(parsed))
+                    .execute(connection)
+                    .await?;
+            }
+            None => unparsed.push(number),
+        }
+    }
+
+    // The copy of the primary number goes the same way as the number it's a copy of.
+    let primaries: Vec<(ContactId, String)> = contacts::table
+        .filter(contacts::phone.ne(""))
+        .filter(contacts::phone.not_similar_to(E164))
+        .select((contacts::id, contacts::phone))
+        .load(connection)
+        .await?;
+    for (id, phone) in primaries {
+        if let Some(parsed) = PhoneNumber::parse(&phone) {
+            diesel::update(contacts::table.find(id))
+                .set(contacts::phone.eq(parsed))
+                .execute(connection)
+                .await?;
+        }
+    }
+    Ok(unparsed)
+}
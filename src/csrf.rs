@@ -0,0 +1,252 @@
+//! Protection against other sites submitting forms on behalf of someone who's logged in.
+//!
+//! Every session has a random token, which every form that posts sends back with `field`, and
+//! `page` puts in an `hx-headers` attribute on `body` for htmx requests. The `verify` layer turns away any request
+//! that isn't a GET or HEAD unless it sends the token back, either as the `X-CSRF-Token` header or
+//! as the `csrf_token` field of a urlencoded or multipart form.
+//! Requests without a session, like logging in or signing up, are checked against a token in a
+//! signed cookie of their own instead, so that another site can't log someone in as the attacker.
+
+use std::convert::Infallible;
+
+use axum::body::Body;
+use axum::body::Bytes;
+use axum::extract::FromRequest;
+use axum::extract::FromRequestParts;
+use axum::extract::Multipart;
+use axum::extract::Request;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::request::Parts;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum_extra::extract::cookie::Cookie;
+use axum_extra::extract::cookie::SameSite;
+use axum_extra::extract::SignedCookieJar;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use maud::html;
+use maud::Markup;
+use serde_json::json;
+use subtle::ConstantTimeEq;
+
+use crate::auth;
+use crate::html_views;
+use crate::AppError;
+use crate::AppState;
+
+const CSRF_HEADER: &str = "X-CSRF-Token";
+const CSRF_FIELD: &str = "csrf_token";
+const PRE_SESSION_COOKIE: &str = "csrf";
+
+/// The same as axum's default limit for extracting a body, which uploads are held to as well.
+pub const MAX_FORM_SIZE: usize = 2 * 1024 * 1024;
+
+/// The current session's token, or the pre-session one when no one is logged in.
+/// There's none outside of `verify`, like in the API.
+#[derive(Clone, Default)]
+pub struct CsrfToken(Option<String>);
+
+impl CsrfToken {
+    /// For the `hx-headers` attribute, so that htmx sends the token with every request.
+    pub fn hx_headers(&self) -> Option<String> {
+        let token = self.0.as_ref()?;
+        Some(json!({ CSRF_HEADER: token }).to_string())
+    }
+
+    /// The hidden field that sends the token back, which every form that posts has to have.
+    pub fn field(&self) -> Markup {
+        html! {
+            @if let Some(token) = &self.0 {
+                input type="hidden" name=(CSRF_FIELD) value=(token);
+            }
+        }
+    }
+}
+
+/// Put there by `verify`.
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CsrfToken {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<CsrfToken>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+async fn session_token(
+    state: &AppState,
+    jar: &SignedCookieJar,
+) -> Result<Option<String>, AppError> {
+    use crate::schema::sessions;
+
+    let Some(session_id) = auth::session_id(jar) else {
+        return Ok(None);
+    };
+    let mut connection = state.db_pool.get().await?;
+    Ok(sessions::table
+        .find(session_id)
+        .filter(sessions::expires_at.gt(Utc::now()))
+        .select(sessions::csrf_token)
+        .first(&mut connection)
+        .await
+        .optional()?)
+}
+
+/// What the request sent in place of the token.
+enum Sent {
+    Token(String),
+    Nothing,
+    /// A form too large to look for the token in, which the handler couldn't read either.
+    TooLarge,
+}
+
+/// The token the request sent, handing the request back since a form's body has to be read for it.
+async fn sent_token(request: Request) -> (Request, Sent) {
+    if let Some(token) = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|token| token.to_str().ok())
+    {
+        let token = token.to_string();
+        return (request, Sent::Token(token));
+    }
+    let Some(content_type) = request.headers().get(CONTENT_TYPE).cloned() else {
+        return (request, Sent::Nothing);
+    };
+    let urlencoded = content_type
+        .as_bytes()
+        .starts_with(b"application/x-www-form-urlencoded");
+    let multipart = content_type.as_bytes().starts_with(b"multipart/form-data");
+    if !urlencoded && !multipart {
+        return (request, Sent::Nothing);
+    }
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_FORM_SIZE).await else {
+        return (Request::from_parts(parts, Body::empty()), Sent::TooLarge);
+    };
+    let token = if urlencoded {
+        form_urlencoded::parse(&bytes)
+            .find(|(name, _)| name == CSRF_FIELD)
+            .map(|(_, token)| token.into_owned())
+    } else {
+        multipart_token(content_type, bytes.clone()).await
+    };
+    let sent = token.map_or(Sent::Nothing, Sent::Token);
+    (Request::from_parts(parts, Body::from(bytes)), sent)
+}
+
+/// The token field of a multipart form, which is read from a copy so the handler still gets all of it.
+async fn multipart_token(content_type: HeaderValue, bytes: Bytes) -> Option<String> {
+    let copy = Request::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(bytes))
+        .ok()?;
+    let mut multipart = Multipart::from_request(copy, &()).await.ok()?;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some(CSRF_FIELD) {
+            return field.text().await.ok();
+        }
+    }
+    None
+}
+
+/// Checks the token of every request that could change something,
+/// and makes the `CsrfToken` available to handlers.
+/// Without a session, the pre-session cookie is set on the way out if the request had none.
+pub async fn verify(
+    State(state): State<AppState>,
+    jar: SignedCookieJar,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let pre_session = jar
+        .get(PRE_SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    let (token, new_cookie) = match session_token(&state, &jar).await? {
+        Some(token) => (token, None),
+        None => match pre_session {
+            Some(token) => (token, None),
+            None => {
+                let token = auth::random_secret();
+                (token.clone(), Some(token))
+            }
+        },
+    };
+    let csrf = CsrfToken(Some(token.clone()));
+    let (mut request, sent) = if request.method().is_safe() {
+        (request, None)
+    } else {
+        let (request, sent) = sent_token(request).await;
+        (request, Some(sent))
+    };
+    let response = match sent {
+        Some(Sent::TooLarge) => html_views::too_large(&csrf),
+        Some(Sent::Token(sent)) if !bool::from(sent.as_bytes().ct_eq(token.as_bytes())) => {
+            html_views::forbidden(&csrf)
+        }
+        Some(Sent::Nothing) => html_views::forbidden(&csrf),
+        _ => {
+            request.extensions_mut().insert(csrf);
+            next.run(request).await
+        }
+    };
+    // Even on a failure, so that the login form can be sent again without loading it first.
+    let Some(token) = new_cookie else {
+        return Ok(response);
+    };
+    let cookie = Cookie::build((PRE_SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .secure(!cfg!(debug_assertions))
+        .same_site(SameSite::Lax);
+    Ok((jar.add(cookie), response).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_sends_the_token_back() {
+        let field = CsrfToken(Some("token".to_string())).field().into_string();
+        assert_eq!(
+            field,
+            r#"<input type="hidden" name="csrf_token" value="token">"#
+        );
+        assert!(CsrfToken::default().field().into_string().is_empty());
+    }
+
+    #[tokio::test]
+    async fn multipart_forms_send_the_token_as_a_field() {
+        let body = "--boundary\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"contacts.vcf\"\r\n\r\n\
+            BEGIN:VCARD\r\n\
+            --boundary\r\n\
+            Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\n\
+            token\r\n\
+            --boundary--\r\n";
+        let content_type = HeaderValue::from_static("multipart/form-data; boundary=boundary");
+        assert_eq!(
+            multipart_token(content_type.clone(), Bytes::from(body))
+                .await
+                .as_deref(),
+            Some("token")
+        );
+        let without = "--boundary\r\n\
+            Content-Disposition: form-data; name=\"file\"\r\n\r\n\
+            BEGIN:VCARD\r\n\
+            --boundary--\r\n";
+        assert_eq!(
+            multipart_token(content_type, Bytes::from(without)).await,
+            None
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Turning an uploaded CSV file into pending contacts.
+//!
+//! The file itself never touches the database until the user has picked which column goes
+//! where and looked at the preview, so everything here is pure and can be re-run on every
+//! change of the mapping. The emails already in the book are looked up once and passed in.
+//! In the meantime the parsed file waits in `CsvUploads`.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::duplicates;
+use crate::model::Address;
+use crate::model::DetailsInput;
+use crate::model::PendingContact;
+use crate::model::UserId;
+use crate::model::ValidContact;
+
+/// How long an uploaded file waits for its columns to be mapped before we throw it away.
+pub const UPLOAD_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How often we look for uploads that have outlived `UPLOAD_TTL`.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub struct CsvTable {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// Reads a CSV file whose first row names the columns.
+pub fn read_csv(input: &str) -> Result<CsvTable, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+    let headers = reader.headers()?.iter().map(str::to_string).collect();
+    let rows = reader
+        .records()
+        .map(|record| record.map(|record| record.iter().map(str::to_string).collect()))
+        .collect::<Result<_, _>>()?;
+    Ok(CsvTable { headers, rows })
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct UploadId(Uuid);
+
+impl Display for UploadId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+struct Upload {
+    owner: UserId,
+    table: Arc<CsvTable>,
+    uploaded_at: Instant,
+}
+
+/// Uploaded files between the mapping form and the import, so that they only go over the wire once.
+/// Each one belongs to the user who uploaded it, and only they can see it.
+/// Files nobody comes back for are dropped by `expire_periodically`.
+#[derive(Clone, Default)]
+pub struct CsvUploads(Arc<Mutex<HashMap<UploadId, Upload>>>);
+
+impl CsvUploads {
+    pub fn store(&self, owner: UserId, table: CsvTable) -> UploadId {
+        let upload_id = UploadId(Uuid::new_v4());
+        self.0.lock().unwrap().insert(
+            upload_id,
+            Upload {
+                owner,
+                table: Arc::new(table),
+                uploaded_at: Instant::now(),
+            },
+        );
+        upload_id
+    }
+
+    pub fn get(&self, owner: UserId, upload_id: UploadId) -> Option<Arc<CsvTable>> {
+        match self.0.lock().unwrap().get(&upload_id) {
+            Some(upload) if upload.owner == owner => Some(upload.table.clone()),
+            _ => None,
+        }
+    }
+
+    /// Forgets a file once it's been imported.
+    pub fn remove(&self, owner: UserId, upload_id: UploadId) {
+        let mut uploads = self.0.lock().unwrap();
+        if uploads
+            .get(&upload_id)
+            .is_some_and(|upload| upload.owner == owner)
+        {
+            uploads.remove(&upload_id);
+        }
+    }
+
+    /// Runs forever, dropping uploads that are older than `ttl`.
+    pub async fn expire_periodically(self, ttl: Duration) {
+        let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cutoff = Instant::now() - ttl;
+            self.0
+                .lock()
+                .unwrap()
+                .retain(|_, upload| upload.uploaded_at >= cutoff);
+        }
+    }
+}
+
+/// Which column, by index, fills in each of the contact's fields.
+/// The address columns make up a single address, labelled by nothing.
+#[derive(Clone, Copy, Default)]
+pub struct ColumnMapping {
+    pub first_name: Option<usize>,
+    pub last_name: Option<usize>,
+    pub phone: Option<usize>,
+    pub email_address: Option<usize>,
+    pub street: Option<usize>,
+    pub city: Option<usize>,
+    pub region: Option<usize>,
+    pub postal_code: Option<usize>,
+    pub country: Option<usize>,
+}
+
+impl ColumnMapping {
+    /// Starting point for the mapping form, matching headers like "First Name" or "e-mail".
+    pub fn guess(headers: &[String]) -> Self {
+        let find = |candidates: &[&str]| {
+            headers.iter().position(|header| {
+                let header: String = header
+                    .chars()
+                    .filter(char::is_ascii_alphanumeric)
+                    .collect::<String>()
+                    .to_ascii_lowercase();
+                candidates.contains(&header.as_str())
+            })
+        };
+        Self {
+            first_name: find(&["first", "firstname", "givenname", "given"]),
+            last_name: find(&["last", "lastname", "surname", "familyname", "family"]),
+            phone: find(&["phone", "phonenumber", "telephone", "tel", "mobile"]),
+            email_address: find(&["email", "emailaddress", "mail"]),
+            street: find(&[
+                "street",
+                "streetaddress",
+                "address",
+                "address1",
+                "addressline1",
+            ]),
+            city: find(&["city", "town", "locality"]),
+            region: find(&["region", "state", "province", "county"]),
+            postal_code: find(&["postalcode", "postcode", "zip", "zipcode"]),
+            country: find(&["country"]),
+        }
+    }
+
+    /// A column that isn't mapped, or that is missing from a short row, leaves the field empty
+    /// so that `to_valid` can report it.
+    pub fn apply(&self, row: &[String]) -> PendingContact::Form {
+        let cell = |column: Option<usize>| column.and_then(|column| row.get(column)).cloned();
+        let mut details = DetailsInput::primary_only(
+            &cell(self.phone).unwrap_or_default(),
+            &cell(self.email_address).unwrap_or_default(),
+        );
+        let address = Address {
+            label: String::new(),
+            street: cell(self.street).unwrap_or_default(),
+            city: cell(self.city).unwrap_or_default(),
+            region: cell(self.region).unwrap_or_default(),
+            postal_code: cell(self.postal_code).unwrap_or_default(),
+            country: cell(self.country).unwrap_or_default(),
+        };
+        if address != Address::default() {
+            details.addresses.push(address);
+        }
+        PendingContact::Form::new(cell(self.first_name), cell(self.last_name), &details)
+    }
+}
+
+pub const EMAIL_REPEATED: &str = "Email must be unique, an earlier row already has it";
+
+/// The dry run of an import: every row alongside what would be inserted for it.
+/// `taken` holds the normalized emails of the contacts already in the book, which,
+/// like the emails of earlier rows, a row can't have again.
+pub fn preview(
+    table: &CsvTable,
+    mapping: ColumnMapping,
+    taken: &HashSet<String>,
+) -> Vec<(
+    PendingContact::Form,
+    Result<ValidContact, PendingContact::Errors>,
+)> {
+    let mut seen = HashSet::new();
+    table
+        .rows
+        .iter()
+        .map(|row| {
+            let pending = mapping.apply(row);
+            let emails: Vec<String> = pending
+                .email_addresses
+                .iter()
+                .filter(|email| !email.trim().is_empty())
+                .map(|email| duplicates::normalize_email(email))
+                .collect();
+            let clash = if emails.iter().any(|email| taken.contains(email)) {
+                Some(duplicates::EMAIL_TAKEN)
+            } else if emails.iter().any(|email| seen.contains(email)) {
+                Some(EMAIL_REPEATED)
+            } else {
+                None
+            };
+            let valid = match (pending.to_valid(), clash) {
+                (Ok(valid), None) => Ok(valid),
+                (Ok(_), Some(clash)) => Err(PendingContact::Errors {
+                    email_addresses: vec![clash.into()],
+                    ..Default::default()
+                }),
+                (Err(mut errors), clash) => {
+                    errors.email_addresses.extend(clash.map(Into::into));
+                    Err(errors)
+                }
+            };
+            // Only rows that will be imported keep later ones from having their emails.
+            if valid.is_ok() {
+                seen.extend(emails);
+            }
+            (pending, valid)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_cannot_reuse_an_email_of_the_book_or_an_earlier_row() {
+        let table = read_csv(
+            "First Name,Last Name,Email\n\
+             Ada,Lovelace,ada@example.com\n\
+             Charles,Babbage,Taken@Example.com\n\
+             Augusta,,grace@example.com\n\
+             Grace,Hopper,grace@example.com\n\
+             Ada,King, ADA@example.com\n",
+        )
+        .unwrap();
+        let mapping = ColumnMapping::guess(&table.headers);
+        let taken = HashSet::from(["taken@example.com".to_string()]);
+
+        let errors: Vec<Vec<String>> = preview(&table, mapping, &taken)
+            .into_iter()
+            .map(|(_, row)| {
+                row.err()
+                    .map(|errors| {
+                        errors
+                            .email_addresses
+                            .iter()
+                            .map(|error| error.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        assert_eq!(
+            errors,
+            [
+                vec![],
+                vec![duplicates::EMAIL_TAKEN.to_string()],
+                // Missing its last name, so it doesn't claim the email.
+                vec![],
+                vec![],
+                vec![EMAIL_REPEATED.to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn address_columns_make_up_one_address() {
+        let table = read_csv(
+            "Name,Surname,E-mail,Street Address,Town,Zip,Country\n\
+             Ada,Lovelace,ada@example.com,12 St James's Square,London,SW1Y 4JH,UK\n\
+             Charles,Babbage,charles@example.com,,,,\n",
+        )
+        .unwrap();
+        let mapping = ColumnMapping {
+            first_name: Some(0),
+            last_name: Some(1),
+            ..ColumnMapping::guess(&table.headers)
+        };
+
+        let addresses: Vec<Vec<Address>> = preview(&table, mapping, &HashSet::new())
+            .into_iter()
+            .map(|(_, row)| {
+                row.unwrap_or_else(|_| panic!("the row is valid"))
+                    .details
+                    .addresses
+            })
+            .collect();
+
+        assert_eq!(
+            addresses,
+            [
+                vec![Address {
+                    label: String::new(),
+                    street: "12 St James's Square".to_string(),
+                    city: "London".to_string(),
+                    region: String::new(),
+                    postal_code: "SW1Y 4JH".to_string(),
+                    country: "UK".to_string(),
+                }],
+                vec![],
+            ]
+        );
+    }
+}
@@ -0,0 +1,634 @@
+//! Finding contacts that are likely the same person, and merging them into one.
+//!
+//! Two contacts are candidates if they share an email address (ignoring case and surrounding
+//! spaces), a phone number (ignoring everything but the digits), or if their names are close
+//! by trigram similarity. Every pair is compared, which is fine at address book scale.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+
+use crate::books;
+use crate::contact_details;
+use crate::history;
+use crate::history::Change;
+use crate::history::Source;
+use crate::model::AddressBookId;
+use crate::model::Contact;
+use crate::model::ContactAttributes;
+use crate::model::ContactDetails;
+use crate::model::ContactId;
+use crate::model::EventKind;
+use crate::model::PendingContact;
+use crate::model::Permission;
+use crate::model::TagId;
+use crate::model::UserId;
+use crate::model::ValidContact;
+use crate::trash;
+use crate::AppError;
+
+/// How close two names have to be, between 0 and 1, to count as the same person.
+const NAME_SIMILARITY: f32 = 0.6;
+
+define_sql_function!(fn lower(text: Text) -> Text);
+define_sql_function!(fn btrim(text: Text) -> Text);
+define_sql_function!(fn regexp_replace(text: Text, pattern: Text, replacement: Text, flags: Text) -> Text);
+define_sql_function!(fn similarity(left: Text, right: Text) -> Float4);
+
+/// Why two contacts look like the same person.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Reasons {
+    pub same_email: bool,
+    pub same_phone: bool,
+    pub similar_name: bool,
+}
+
+impl Reasons {
+    pub fn describe(&self) -> String {
+        [
+            (self.same_email, "same email"),
+            (self.same_phone, "same phone"),
+            (self.similar_name, "similar name"),
+        ]
+        .into_iter()
+        .filter_map(|(applies, reason)| applies.then_some(reason))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+pub struct DuplicatePair {
+    pub left: Contact,
+    pub right: Contact,
+    pub reasons: Reasons,
+}
+
+/// How an email address compares for duplicates.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// How a phone number compares for duplicates.
+pub fn normalize_phone(phone: &str) -> String {
+    phone.chars().filter(char::is_ascii_digit).collect()
+}
+
+/// Every pair of contacts outside the trash in the same book that look like the same person,
+/// the one added first on the left. Only books where `user` could merge them count.
+pub async fn find_duplicates(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Vec<DuplicatePair>, AppError> {
+    use crate::schema::contact_emails;
+    use crate::schema::contact_phones;
+    use crate::schema::contacts;
+
+    let mergeable = || {
+        contacts::table
+            .filter(contacts::address_book_id.eq_any(books::allowing(user, Permission::Delete)))
+            .filter(contacts::deleted_at.is_null())
+            .select(contacts::id)
+    };
+
+    let (left_email, right_email) =
+        diesel::alias!(contact_emails as left_email, contact_emails as right_email);
+    let same_email: Vec<(ContactId, ContactId)> = left_email
+        .inner_join(
+            right_email.on(lower(btrim(left_email.field(contact_emails::address)))
+                .eq(lower(btrim(right_email.field(contact_emails::address))))
+                .and(
+                    left_email
+                        .field(contact_emails::contact_id)
+                        .lt(right_email.field(contact_emails::contact_id)),
+                )),
+        )
+        .filter(btrim(left_email.field(contact_emails::address)).ne(""))
+        .filter(
+            left_email
+                .field(contact_emails::contact_id)
+                .eq_any(mergeable()),
+        )
+        .filter(
+            right_email
+                .field(contact_emails::contact_id)
+                .eq_any(mergeable()),
+        )
+        .select((
+            left_email.field(contact_emails::contact_id),
+            right_email.field(contact_emails::contact_id),
+        ))
+        .distinct()
+        .load(connection)
+        .await?;
+
+    // Macros rather than closures, since the two sides of each join are different types.
+    macro_rules! digits {
+        ($number:expr) => {
+            regexp_replace($number, "\\D", "", "g")
+        };
+    }
+    let (left_phone, right_phone) =
+        diesel::alias!(contact_phones as left_phone, contact_phones as right_phone);
+    let same_phone: Vec<(ContactId, ContactId)> = left_phone
+        .inner_join(
+            right_phone.on(digits!(left_phone.field(contact_phones::number))
+                .eq(digits!(right_phone.field(contact_phones::number)))
+                .and(
+                    left_phone
+                        .field(contact_phones::contact_id)
+                        .lt(right_phone.field(contact_phones::contact_id)),
+                )),
+        )
+        .filter(digits!(left_phone.field(contact_phones::number)).ne(""))
+        .filter(
+            left_phone
+                .field(contact_phones::contact_id)
+                .eq_any(mergeable()),
+        )
+        .filter(
+            right_phone
+                .field(contact_phones::contact_id)
+                .eq_any(mergeable()),
+        )
+        .select((
+            left_phone.field(contact_phones::contact_id),
+            right_phone.field(contact_phones::contact_id),
+        ))
+        .distinct()
+        .load(connection)
+        .await?;
+
+    let (left, right) = diesel::alias!(contacts as left, contacts as right);
+    macro_rules! name {
+        ($alias:expr) => {
+            lower(
+                btrim($alias.field(contacts::first_name))
+                    .concat(" ")
+                    .concat(btrim($alias.field(contacts::last_name))),
+            )
+        };
+    }
+    let similar_name: Vec<(ContactId, ContactId)> = left
+        .inner_join(
+            right.on(left
+                .field(contacts::id)
+                .lt(right.field(contacts::id))
+                .and(similarity(name!(left), name!(right)).ge(NAME_SIMILARITY))),
+        )
+        .filter(
+            left.field(contacts::address_book_id)
+                .eq_any(books::allowing(user, Permission::Delete)),
+        )
+        .filter(
+            right
+                .field(contacts::address_book_id)
+                .eq(left.field(contacts::address_book_id)),
+        )
+        .filter(left.field(contacts::deleted_at).is_null())
+        .filter(right.field(contacts::deleted_at).is_null())
+        .select((left.field(contacts::id), right.field(contacts::id)))
+        .load(connection)
+        .await?;
+
+    let mut pairs: BTreeMap<(ContactId, ContactId), Reasons> = BTreeMap::new();
+    for pair in same_email {
+        pairs.entry(pair).or_default().same_email = true;
+    }
+    for pair in same_phone {
+        pairs.entry(pair).or_default().same_phone = true;
+    }
+    for pair in similar_name {
+        pairs.entry(pair).or_default().similar_name = true;
+    }
+
+    let ids: Vec<ContactId> = pairs
+        .keys()
+        .flat_map(|(left, right)| [*left, *right])
+        .collect();
+    let live: BTreeMap<ContactId, Contact> = contacts::table
+        .filter(contacts::id.eq_any(ids))
+        .filter(contacts::address_book_id.eq_any(books::allowing(user, Permission::Delete)))
+        .filter(contacts::deleted_at.is_null())
+        .select(Contact::as_select())
+        .load(connection)
+        .await?
+        .into_iter()
+        .map(|contact| (contact.id, contact))
+        .collect();
+    Ok(pairs
+        .into_iter()
+        .filter_map(|((left, right), reasons)| {
+            let left = live.get(&left)?;
+            let right = live.get(&right)?;
+            (left.address_book_id == right.address_book_id).then(|| DuplicatePair {
+                left: left.clone(),
+                right: right.clone(),
+                reasons,
+            })
+        })
+        .collect())
+}
+
+/// A contact outside the trash in `book` that `user` can see, other than `except`,
+/// with `email` among its addresses.
+pub async fn email_owner(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    book: AddressBookId,
+    email: &str,
+    except: Option<ContactId>,
+) -> Result<Option<Contact>, AppError> {
+    use crate::schema::contact_emails;
+    use crate::schema::contacts;
+
+    Ok(contacts::table
+        .filter(contacts::address_book_id.eq(book))
+        .filter(contacts::address_book_id.eq_any(books::allowing(user, Permission::View)))
+        .filter(contacts::deleted_at.is_null())
+        .filter(contacts::id.nullable().is_distinct_from(except))
+        .filter(
+            contacts::id.eq_any(
+                contact_emails::table
+                    .filter(lower(btrim(contact_emails::address)).eq(normalize_email(email)))
+                    .select(contact_emails::contact_id),
+            ),
+        )
+        .order(contacts::id)
+        .select(Contact::as_select())
+        .first(connection)
+        .await
+        .optional()?)
+}
+
+/// The normalized emails of every contact outside the trash in `book`,
+/// for checking many new contacts at once.
+pub async fn emails_in_book(
+    connection: &mut AsyncPgConnection,
+    book: AddressBookId,
+) -> Result<HashSet<String>, AppError> {
+    use crate::schema::contact_emails;
+    use crate::schema::contacts;
+
+    let emails: Vec<String> = contact_emails::table
+        .inner_join(contacts::table)
+        .filter(contacts::address_book_id.eq(book))
+        .filter(contacts::deleted_at.is_null())
+        .select(contact_emails::address)
+        .load(connection)
+        .await?;
+    Ok(emails.iter().map(|email| normalize_email(email)).collect())
+}
+
+/// The book the contact in `form` is saved to: the one it's in if it exists already,
+/// or else the one the form picked, as long as `user` can add to it.
+pub async fn book_for(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    form: &PendingContact::Form,
+    contact: Option<ContactId>,
+) -> Result<AddressBookId, AppError> {
+    use crate::schema::contacts;
+
+    let Some(contact_id) = contact else {
+        return books::book_to_add_to(connection, user, form.address_book).await;
+    };
+    contacts::table
+        .find(contact_id)
+        .filter(contacts::address_book_id.eq_any(books::allowing(user, Permission::View)))
+        .select(contacts::address_book_id)
+        .first::<Option<AddressBookId>>(connection)
+        .await?
+        .ok_or(AppError::NotFound)
+}
+
+pub const EMAIL_TAKEN: &str = "Email must be unique, another contact already has it";
+
+/// `to_valid`, along with the rule only the database can check: no other contact in the book
+/// it's saved to may have any of the emails. Every error is reported at once, like the form does.
+pub async fn validate(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    form: &PendingContact::Form,
+    contact: Option<ContactId>,
+) -> Result<Result<ValidContact, PendingContact::Errors>, AppError> {
+    let book = book_for(connection, user, form, contact).await?;
+    let mut taken = None;
+    for email in form
+        .email_addresses
+        .iter()
+        .filter(|email| !email.trim().is_empty())
+    {
+        if email_owner(connection, user, book, email, contact)
+            .await?
+            .is_some()
+        {
+            taken = Some(EMAIL_TAKEN);
+            break;
+        }
+    }
+    let mut errors = match form.to_valid() {
+        Ok(valid) if taken.is_none() => return Ok(Ok(valid)),
+        Ok(_) => PendingContact::Errors::default(),
+        Err(errors) => errors,
+    };
+    errors.email_addresses.extend(taken.map(Cow::from));
+    Ok(Err(errors))
+}
+
+/// Where a contact's details went, if it was merged into another one.
+pub async fn merged_into(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    contact_id: ContactId,
+) -> Result<Option<ContactId>, AppError> {
+    use crate::schema::contacts;
+
+    Ok(contacts::table
+        .find(contact_id)
+        .filter(contacts::address_book_id.eq_any(books::allowing(user, Permission::View)))
+        .select(contacts::merged_into)
+        .first::<Option<ContactId>>(connection)
+        .await
+        .optional()?
+        .flatten())
+}
+
+/// Everything from both contacts, `keep`'s first.
+/// Phones, emails and addresses that both have are only listed once, and `keep`'s primaries win.
+pub fn combined_details(keep: &ContactDetails, other: &ContactDetails) -> ContactDetails {
+    let mut combined = keep.clone();
+    for phone in &other.phones {
+        let number = normalize_phone(&phone.number);
+        if !combined
+            .phones
+            .iter()
+            .any(|existing| normalize_phone(&existing.number) == number)
+        {
+            combined.phones.push(crate::model::Phone {
+                primary: phone.primary && combined.phones.is_empty(),
+                ..phone.clone()
+            });
+        }
+    }
+    for email in &other.emails {
+        let address = normalize_email(&email.address);
+        if !combined
+            .emails
+            .iter()
+            .any(|existing| normalize_email(&existing.address) == address)
+        {
+            combined.emails.push(crate::model::Email {
+                primary: email.primary && combined.emails.is_empty(),
+                ..email.clone()
+            });
+        }
+    }
+    for address in &other.addresses {
+        if !combined
+            .addresses
+            .iter()
+            .any(|existing| existing.lines() == address.lines())
+        {
+            combined.addresses.push(address.clone());
+        }
+    }
+    combined
+}
+
+/// Saves `attributes` and `details` to `keep`, gives it `other`'s tags,
+/// and moves `other` to the trash pointing at `keep`.
+/// Returns `false` if either contact is gone, already in the trash, in a book where `user` isn't
+/// an admin, or if they're in different books.
+pub async fn merge(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    keep: ContactId,
+    other: ContactId,
+    attributes: &ContactAttributes,
+    details: &ContactDetails,
+) -> Result<bool, AppError> {
+    connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::contact_tags;
+                use crate::schema::contacts;
+
+                // Always lock in the same order, so that two merges of the same pair can't deadlock.
+                let mut locked = vec![];
+                for contact_id in [keep.min(other), keep.max(other)] {
+                    match history::lock_attributes(connection, user, contact_id, Permission::Delete)
+                        .await?
+                    {
+                        Some(attributes) => locked.push((contact_id, attributes)),
+                        None => return Ok::<_, AppError>(false),
+                    }
+                }
+                let books: Vec<Option<AddressBookId>> = contacts::table
+                    .filter(contacts::id.eq_any([keep, other]))
+                    .select(contacts::address_book_id)
+                    .distinct()
+                    .load(connection)
+                    .await?;
+                if books.len() != 1 {
+                    return Ok(false);
+                }
+                let Some((_, before)) = locked.into_iter().find(|(id, _)| *id == keep) else {
+                    return Ok(false);
+                };
+
+                // `other` goes to the trash first, so that the email it shares with `keep` is free by
+                // the time `keep` takes it over.
+                trash::trash(connection, user, Source::Web, &[other]).await?;
+                diesel::update(contacts::table.find(other))
+                    .set(contacts::merged_into.eq(keep))
+                    .execute(connection)
+                    .await?;
+
+                diesel::update(contacts::table.find(keep))
+                    .set(attributes)
+                    .execute(connection)
+                    .await?;
+                contact_details::replace_details(connection, keep, details).await?;
+
+                let other_tags: Vec<TagId> = contact_tags::table
+                    .filter(contact_tags::contact_id.eq(other))
+                    .select(contact_tags::tag_id)
+                    .load(connection)
+                    .await?;
+                let tags: Vec<_> = other_tags
+                    .into_iter()
+                    .map(|tag_id| {
+                        (
+                            contact_tags::contact_id.eq(keep),
+                            contact_tags::tag_id.eq(tag_id),
+                        )
+                    })
+                    .collect();
+                diesel::insert_into(contact_tags::table)
+                    .values(tags)
+                    .on_conflict_do_nothing()
+                    .execute(connection)
+                    .await?;
+
+                let change = Change {
+                    contact_id: keep,
+                    before: Some(&before),
+                    after: Some(attributes),
+                };
+                history::record(connection, user, Source::Web, EventKind::Merged, &[change])
+                    .await?;
+                Ok(true)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+    use crate::model::Email;
+    use crate::model::EmailAddress;
+
+    async fn test_connection() -> AsyncPgConnection {
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection = AsyncPgConnection::establish(&url).await.unwrap();
+        connection.begin_test_transaction().await.unwrap();
+        connection
+    }
+
+    async fn insert_contact(
+        connection: &mut AsyncPgConnection,
+        book: AddressBookId,
+        email: &str,
+    ) -> (ContactId, ContactDetails) {
+        use crate::schema::contacts;
+
+        let address = EmailAddress::parse(email).unwrap_or_default();
+        let attributes = ContactAttributes {
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            phone: Default::default(),
+            email_address: address.clone(),
+        };
+        let details = ContactDetails {
+            emails: Some(address)
+                .filter(|address| !address.is_empty())
+                .map(|address| Email {
+                    address,
+                    primary: true,
+                    ..Default::default()
+                })
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let contact_id = diesel::insert_into(contacts::table)
+            .values((&attributes, contacts::address_book_id.eq(book)))
+            .returning(contacts::id)
+            .get_result(connection)
+            .await
+            .unwrap();
+        contact_details::insert_details(connection, &[(contact_id, &details)])
+            .await
+            .unwrap();
+        (contact_id, details)
+    }
+
+    #[tokio::test]
+    async fn merge_takes_over_the_email_of_the_other_contact() {
+        use crate::schema::contacts;
+
+        let mut connection = test_connection().await;
+        let user = auth::create_user(&mut connection, "merge-test", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let book = books::default_book(&mut connection, user.id).await.unwrap();
+        let (keep, keep_details) = insert_contact(&mut connection, book, "").await;
+        let (other, other_details) = insert_contact(&mut connection, book, "ada@example.com").await;
+
+        let details = combined_details(&keep_details, &other_details);
+        let attributes = ContactAttributes {
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            phone: Default::default(),
+            email_address: details.emails[0].address.clone(),
+        };
+        let merged = merge(&mut connection, user.id, keep, other, &attributes, &details)
+            .await
+            .unwrap();
+
+        assert!(merged);
+        let email: EmailAddress = contacts::table
+            .find(keep)
+            .select(contacts::email_address)
+            .get_result(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(&*email, "ada@example.com");
+        assert_eq!(
+            merged_into(&mut connection, user.id, other).await.unwrap(),
+            Some(keep)
+        );
+    }
+
+    #[tokio::test]
+    async fn emails_are_unique_per_address_book() {
+        let mut connection = test_connection().await;
+        let ada = auth::create_user(&mut connection, "unique-test-ada", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let bob = auth::create_user(&mut connection, "unique-test-bob", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let book = books::default_book(&mut connection, ada.id).await.unwrap();
+        insert_contact(&mut connection, book, "ada@example.com").await;
+
+        let form = PendingContact::Form {
+            first_name: Some("Ada".to_string()),
+            last_name: Some("Byron".to_string()),
+            email_keys: vec!["0".to_string(), "1".to_string()],
+            email_addresses: vec![
+                "ada@work.example".to_string(),
+                "ADA@example.com ".to_string(),
+            ],
+            primary_email: Some("0".to_string()),
+            ..Default::default()
+        };
+        let errors = validate(&mut connection, ada.id, &form, None)
+            .await
+            .unwrap()
+            .err()
+            .unwrap();
+        assert_eq!(errors.email_addresses, [EMAIL_TAKEN]);
+        assert!(validate(&mut connection, bob.id, &form, None)
+            .await
+            .unwrap()
+            .is_ok());
+
+        // The index catches what gets past `validate`, whichever of its addresses it is.
+        let (contact_id, _) = insert_contact(&mut connection, book, "ada@work.example").await;
+        let details = ContactDetails {
+            emails: vec![Email {
+                address: EmailAddress::parse(" Ada@Example.com").unwrap(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let clash =
+            contact_details::insert_details(&mut connection, &[(contact_id, &details)]).await;
+        assert!(matches!(clash, Err(AppError::Validation(_))));
+    }
+}
@@ -0,0 +1,255 @@
+//! How a failed request is answered, depending on who asked.
+//!
+//! Every `AppError` becomes a `Problem`, which handlers send back like any other response.
+//! The `negotiate` layer then renders it for whoever made the request: a page for browsers,
+//! a fragment for htmx to show, or `application/problem+json` for scripts.
+//! The API always answers with JSON, which the `problem_json` layer takes care of.
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::header::ACCEPT;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum_flash::IncomingFlashes;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::csrf::CsrfToken;
+use crate::html_views;
+use crate::AppError;
+
+/// What's wrong with one field of what was sent.
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        FieldError {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+/// An error as whoever made the request gets to see it, after RFC 9457.
+#[derive(Clone, Debug)]
+pub struct Problem {
+    pub status: StatusCode,
+    pub title: &'static str,
+    pub detail: String,
+    pub errors: Vec<FieldError>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, title: &'static str, detail: impl Into<String>) -> Self {
+        Problem {
+            status,
+            title,
+            detail: detail.into(),
+            errors: vec![],
+        }
+    }
+
+    fn json(&self) -> Response {
+        let body = json!({
+            "type": "about:blank",
+            "title": self.title,
+            "status": self.status.as_u16(),
+            "detail": self.detail,
+            "errors": self.errors,
+        });
+        (
+            self.status,
+            [(CONTENT_TYPE, "application/problem+json")],
+            body.to_string(),
+        )
+            .into_response()
+    }
+}
+
+impl From<AppError> for Problem {
+    fn from(err: AppError) -> Self {
+        match err {
+            AppError::NotFound => Problem::new(
+                StatusCode::NOT_FOUND,
+                "Not Found",
+                "Could not find what you were looking for.",
+            ),
+            AppError::Validation(errors) => Problem {
+                errors,
+                ..Problem::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Invalid Input",
+                    "Some of the fields need fixing.",
+                )
+            },
+            AppError::Conflict(detail) => Problem::new(StatusCode::CONFLICT, "Conflict", detail),
+            AppError::Unauthorized(detail) => {
+                Problem::new(StatusCode::UNAUTHORIZED, "Unauthorized", detail)
+            }
+            AppError::Forbidden => Problem::new(
+                StatusCode::FORBIDDEN,
+                "Forbidden",
+                "You don't have permission to do that.",
+            ),
+            err => {
+                tracing::error!(%err, "internal error");
+                Problem::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal Server Error",
+                    "An internal error occurred. Please try again later.",
+                )
+            }
+        }
+    }
+}
+
+/// Plain text until a layer renders it, which it finds in the response's extensions.
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, self.detail.clone()).into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Whether the request asked for JSON rather than a page.
+fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("json") && !accept.contains("text/html"))
+}
+
+/// `rendered` with the headers the handler set on the problem, like cookies, but its own body.
+fn keep_headers(original: &HeaderMap, mut rendered: Response) -> Response {
+    for (name, value) in original {
+        if name != CONTENT_TYPE && name != CONTENT_LENGTH {
+            rendered.headers_mut().append(name, value.clone());
+        }
+    }
+    rendered
+}
+
+/// Renders problems from the HTML routes for browsers, htmx or scripts, whichever asked.
+pub async fn negotiate(
+    flashes: IncomingFlashes,
+    csrf: CsrfToken,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    let json = wants_json(request.headers());
+    let htmx = request.headers().contains_key("hx-request");
+    let response = next.run(request).await;
+    let Some(problem) = response.extensions().get::<Problem>().cloned() else {
+        return response;
+    };
+    let rendered = if json {
+        problem.json()
+    } else if htmx {
+        html_views::error_fragment(&problem).into_response()
+    } else {
+        html_views::error_page(&problem, &csrf, flashes).into_response()
+    };
+    keep_headers(response.headers(), rendered)
+}
+
+/// Renders problems from the API as `application/problem+json`.
+pub async fn problem_json(request: Request, next: Next) -> Response<Body> {
+    let response = next.run(request).await;
+    let Some(problem) = response.extensions().get::<Problem>() else {
+        return response;
+    };
+    keep_headers(response.headers(), problem.json())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::SET_COOKIE;
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn app_errors_become_problems_with_matching_statuses() {
+        let status = |err: AppError| Problem::from(err).status;
+        assert_eq!(status(AppError::NotFound), StatusCode::NOT_FOUND);
+        assert_eq!(
+            status(AppError::Validation(vec![])),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            status(AppError::Conflict("taken".to_string())),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            status(AppError::Unauthorized("no token")),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(AppError::Forbidden), StatusCode::FORBIDDEN);
+        assert_eq!(
+            status(AppError::Diesel(diesel::result::Error::RollbackTransaction)),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        // What went wrong inside stays inside.
+        let internal = Problem::from(AppError::Io(std::io::Error::other("disk on fire")));
+        assert!(!internal.detail.contains("disk on fire"));
+    }
+
+    #[tokio::test]
+    async fn problems_render_as_problem_json_with_their_field_errors() {
+        let problem = Problem::from(AppError::Validation(vec![FieldError::new(
+            "email",
+            "Email is required.",
+        )]));
+        let response = problem.json();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 422);
+        assert_eq!(body["title"], "Invalid Input");
+        assert_eq!(
+            body["errors"],
+            json!([{"field": "email", "message": "Email is required."}])
+        );
+    }
+
+    #[test]
+    fn only_requests_that_prefer_json_get_it() {
+        let accepting = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_static(accept));
+            wants_json(&headers)
+        };
+        assert!(accepting("application/json"));
+        assert!(accepting("application/problem+json"));
+        assert!(!accepting(
+            "text/html,application/xhtml+xml,application/json;q=0.9"
+        ));
+        assert!(!accepting("*/*"));
+        assert!(!wants_json(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn rendered_problems_keep_the_headers_but_not_the_body_type() {
+        let mut original = HeaderMap::new();
+        original.insert(SET_COOKIE, HeaderValue::from_static("flash=gone"));
+        original.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        let rendered = keep_headers(&original, Problem::from(AppError::NotFound).json());
+        assert_eq!(rendered.headers()[SET_COOKIE], "flash=gone");
+        assert_eq!(rendered.headers().get_all(CONTENT_TYPE).iter().count(), 1);
+        assert_eq!(rendered.headers()[CONTENT_TYPE], "application/problem+json");
+    }
+}
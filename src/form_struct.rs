@@ -1,9 +1,39 @@
+use std::borrow::Cow;
+
+use maud::html;
+use maud::Markup;
+use regex::Regex;
+
+use crate::model::EmailAddress;
+use crate::model::PhoneNumber;
+
+/// What's wrong with a field, empty if nothing is.
+pub type Messages = Vec<Cow<'static, str>>;
+
+/// Fields can list rules after their type, which `validate` checks:
+///
+/// - `required` or `required("message")`, for at least one value that isn't blank.
+/// - `min_length(n)` and `max_length(n)`, in characters, with a message of their own after `n` if need be.
+/// - `email` and `phone`, for the syntax of email addresses and phone numbers.
+/// - `regex("pattern", "message")`, which every value has to match.
+/// - `custom(path::to::check)`, a function of the field returning `Option<Cow<'static, str>>`.
+///   The path is resolved inside the generated module, so it needs to be a full one.
+///
+/// Every rule but `required` leaves blank values alone, and repeated fields are checked value by value.
+///
+/// Fields shown in a form can give the fields of their `Input` between braces, before the rules:
+/// `first_name("first_name"): Option<String> { label: "First Name" } => [required]`.
+/// They end up in the `inputs` module, and `Form::fields` renders the ones that aren't repeated.
+/// Repeated fields that make up one row together, like the parts of an address, share a `group`:
+/// `Form::group_rows` hands out their inputs row by row and `Errors::group` their messages.
 #[macro_export]
 macro_rules! form_struct {
     (#[derive( $($derive_attributes:path),* $(,)?)]
      $vis:vis struct $struct_name:ident {
          $( $(#[$field_macro:tt($($params:path),* $(,)?)])*
-         $field:ident($rename:expr): $typ:ty),+ $(,)?
+         $field:ident($rename:expr): $typ:ty
+         $({ $($key:ident: $value:expr),* $(,)? })?
+         $(=> [$($rule:ident $(($($arg:tt)*))?),* $(,)?])?),+ $(,)?
      }) => {
         #[allow(non_snake_case)]
         $vis mod $struct_name {
@@ -12,17 +42,614 @@ macro_rules! form_struct {
 
             #[derive($($derive_attributes, )*)]
             $vis struct Form {
-                $($(#[$field_macro($($params,)*)])*)*
-                $(#[serde(rename = $rename)]
+                $($(#[$field_macro($($params,)*)])*
+                #[serde(rename = $rename)]
                 $vis $field: $typ,)+
             }
 
-            $($vis fn $field() -> &'static str { $rename })+
+            $(#[allow(dead_code)] $vis fn $field() -> &'static str { $rename })+
+
+            #[allow(non_upper_case_globals, dead_code)]
+            $vis mod inputs {
+                $($(
+                    $vis const $field: $crate::form_struct::Input =
+                        $crate::form_input!($rename, $($key: $value),*);
+                )?)+
+
+                /// Every input, in the order of the fields.
+                $vis const ALL: &[$crate::form_struct::Input] = &[$($(
+                    $crate::form_input!($rename, $($key: $value),*),
+                )?)+];
+
+                /// The inputs of the fields in `group`, in order.
+                $vis fn group(
+                    group: &'static str,
+                ) -> impl Iterator<Item = $crate::form_struct::Input> {
+                    ALL.iter().copied().filter(move |input| input.group == Some(group))
+                }
+            }
 
             #[derive(Default)]
             $vis struct Errors {
-                $($vis $field: Option<&'static str>,)+
+                $($vis $field: $crate::form_struct::Messages,)+
+            }
+
+            impl Form {
+                /// Checks every field against its rules, collecting all of their messages.
+                #[allow(dead_code)]
+                $vis fn validate(&self) -> Errors {
+                    Errors {
+                        $($field: self.validate_field($rename),)+
+                    }
+                }
+
+                /// Checks just the field named `name`, for when it's edited on its own.
+                #[allow(dead_code)]
+                $vis fn validate_field(&self, name: &str) -> $crate::form_struct::Messages {
+                    #[allow(unused_mut)]
+                    let mut messages = $crate::form_struct::Messages::new();
+                    $(if name == $rename {
+                        $($(
+                            messages.extend($crate::form_rule!(&self.$field, $rule $(($($arg)*))?));
+                        )*)?
+                    })+
+                    messages
+                }
+
+                /// The values of the field named `name`, if it has an input.
+                #[allow(dead_code, unused_variables)]
+                $vis fn values_of(&self, name: &str) -> Vec<&str> {
+                    $($(
+                        if name == $rename {
+                            let _ = [$(stringify!($key)),*];
+                            return $crate::form_struct::FieldValue::values(&self.$field);
+                        }
+                    )?)+
+                    vec![]
+                }
+
+                /// The inputs of `group` along with their values, one row per value.
+                /// Fields of the group with fewer values than the others are blank in the last rows.
+                #[allow(dead_code)]
+                $vis fn group_rows(
+                    &self,
+                    group: &'static str,
+                ) -> Vec<Vec<($crate::form_struct::Input, &str)>> {
+                    let columns: Vec<_> = inputs::group(group)
+                        .map(|input| (input, self.values_of(input.name)))
+                        .collect();
+                    let rows = columns.iter().map(|(_, values)| values.len()).max().unwrap_or(0);
+                    (0..rows)
+                        .map(|row| {
+                            columns
+                                .iter()
+                                .map(|(input, values)| {
+                                    (*input, values.get(row).copied().unwrap_or_default())
+                                })
+                                .collect()
+                        })
+                        .collect()
+                }
+
+                /// The inputs of the fields that have one and aren't repeated, in order.
+                /// `validate` gives the URL a live input is checked against, by its name.
+                #[allow(dead_code, unused_variables)]
+                $vis fn fields(
+                    &self,
+                    errors: &Errors,
+                    validate: impl Fn(&'static str) -> Option<String>,
+                ) -> maud::Markup {
+                    maud::html! {
+                        $(($crate::form_field!(
+                            inputs::$field: $typ,
+                            &self.$field,
+                            &errors.$field,
+                            validate($rename)
+                            $(, { $($key),* })?
+                        )))+
+                    }
+                }
+            }
+
+            impl Errors {
+                #[allow(dead_code)]
+                $vis fn is_empty(&self) -> bool {
+                    true $(&& self.$field.is_empty())+
+                }
+
+                /// The messages of the field named `name`.
+                #[allow(dead_code)]
+                $vis fn of(&self, name: &str) -> &$crate::form_struct::Messages {
+                    $(if name == $rename {
+                        return &self.$field;
+                    })+
+                    const NONE: &$crate::form_struct::Messages = &$crate::form_struct::Messages::new();
+                    NONE
+                }
+
+                /// The messages of every field in `group`, in order.
+                #[allow(dead_code)]
+                $vis fn group(
+                    &self,
+                    group: &'static str,
+                ) -> impl Iterator<Item = &std::borrow::Cow<'static, str>> {
+                    inputs::group(group).flat_map(|input| self.of(input.name))
+                }
+
+                /// Every message, in the order of the fields.
+                #[allow(dead_code)]
+                $vis fn iter(&self) -> impl Iterator<Item = &std::borrow::Cow<'static, str>> {
+                    [$(&self.$field),+].into_iter().flatten()
+                }
+
+                /// Every message, under the name of its field in the form.
+                #[allow(dead_code)]
+                $vis fn into_field_errors(self) -> Vec<$crate::errors::FieldError> {
+                    let mut errors = vec![];
+                    $(errors.extend(
+                        self.$field
+                            .into_iter()
+                            .map(|message| $crate::errors::FieldError::new($rename, message)),
+                    );)+
+                    errors
+                }
+            }
+        }
+    };
+}
+
+/// The `Input` of a `form_struct!` field.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! form_input {
+    ($name:expr, $($key:ident: $value:expr),*) => {
+        $crate::form_struct::Input {
+            name: $name,
+            $($key: $value,)*
+            ..$crate::form_struct::Input::DEFAULT
+        }
+    };
+}
+
+/// The label, input and errors of a `form_struct!` field in `Form::fields`,
+/// for fields with an `Input` that aren't repeated, and nothing for the rest.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! form_field {
+    ($input:path: $typ:ty, $value:expr, $errors:expr, $url:expr) => {
+        maud::html! {}
+    };
+    ($input:path: $typ:ty, $value:expr, $errors:expr, $url:expr, { $($key:ident),* }) => {
+        maud::html! {
+            @if !<$typ as $crate::form_struct::FieldValue>::REPEATED {
+                ($input.field(
+                    $crate::form_struct::FieldValue::values($value).first().copied().unwrap_or_default(),
+                    $errors,
+                    $url.as_deref(),
+                ))
             }
         }
     };
 }
+
+/// One rule of a `form_struct!` field, applied to its value.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! form_rule {
+    ($value:expr, required) => {
+        $crate::form_struct::required($value, "This field is required")
+    };
+    ($value:expr, required($message:expr)) => {
+        $crate::form_struct::required($value, $message)
+    };
+    ($value:expr, min_length($min:expr)) => {
+        $crate::form_struct::min_length($value, $min)
+    };
+    ($value:expr, min_length($min:expr, $message:expr)) => {
+        $crate::form_struct::min_length($value, $min).map(|_| $message.into())
+    };
+    ($value:expr, max_length($max:expr)) => {
+        $crate::form_struct::max_length($value, $max)
+    };
+    ($value:expr, max_length($max:expr, $message:expr)) => {
+        $crate::form_struct::max_length($value, $max).map(|_| $message.into())
+    };
+    ($value:expr, email) => {
+        $crate::form_struct::email($value)
+    };
+    ($value:expr, phone) => {
+        $crate::form_struct::phone($value)
+    };
+    ($value:expr, regex($pattern:expr, $message:expr)) => {{
+        static PATTERN: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| {
+            regex::Regex::new($pattern).expect("form_struct! patterns have to be valid")
+        });
+        $crate::form_struct::matches($value, &PATTERN, $message)
+    }};
+    ($value:expr, custom($check:path)) => {
+        $check($value)
+    };
+}
+
+/// The text of a field, for the rules to check.
+pub trait FieldValue {
+    /// Whether the field has an input per row, rather than just one.
+    const REPEATED: bool = false;
+
+    /// One value for a single input, one per input for repeated ones, blank or not.
+    fn values(&self) -> Vec<&str>;
+}
+
+impl FieldValue for String {
+    fn values(&self) -> Vec<&str> {
+        vec![self]
+    }
+}
+
+impl FieldValue for Option<String> {
+    fn values(&self) -> Vec<&str> {
+        self.as_deref().into_iter().collect()
+    }
+}
+
+impl FieldValue for Vec<String> {
+    const REPEATED: bool = true;
+
+    fn values(&self) -> Vec<&str> {
+        self.iter().map(String::as_str).collect()
+    }
+}
+
+/// The values that aren't blank, which are the only ones there's anything to check about.
+fn filled(value: &impl FieldValue) -> impl Iterator<Item = &str> {
+    value
+        .values()
+        .into_iter()
+        .filter(|value| !value.trim().is_empty())
+}
+
+pub fn required(value: &impl FieldValue, message: &'static str) -> Option<Cow<'static, str>> {
+    filled(value).next().is_none().then_some(message.into())
+}
+
+pub fn min_length(value: &impl FieldValue, min: usize) -> Option<Cow<'static, str>> {
+    filled(value)
+        .any(|value| value.trim().chars().count() < min)
+        .then(|| format!("Must be at least {min} characters").into())
+}
+
+pub fn max_length(value: &impl FieldValue, max: usize) -> Option<Cow<'static, str>> {
+    filled(value)
+        .any(|value| value.trim().chars().count() > max)
+        .then(|| format!("Must be at most {max} characters").into())
+}
+
+pub fn email(value: &impl FieldValue) -> Option<Cow<'static, str>> {
+    filled(value)
+        .any(|value| EmailAddress::parse(value).is_none())
+        .then_some("Not a valid email address".into())
+}
+
+pub fn phone(value: &impl FieldValue) -> Option<Cow<'static, str>> {
+    filled(value)
+        .any(|value| PhoneNumber::parse(value).is_none())
+        .then_some("Not a valid phone number".into())
+}
+
+pub fn matches(
+    value: &impl FieldValue,
+    pattern: &Regex,
+    message: &'static str,
+) -> Option<Cow<'static, str>> {
+    filled(value)
+        .any(|value| !pattern.is_match(value.trim()))
+        .then_some(message.into())
+}
+
+/// How a field is shown in a form.
+#[derive(Clone, Copy, Debug)]
+pub struct Input {
+    pub name: &'static str,
+    pub label: &'static str,
+    pub input_type: &'static str,
+    pub placeholder: &'static str,
+    pub size: Option<u32>,
+    /// Whether the server checks the value as it's typed, when there's a URL to check it against.
+    pub live: bool,
+    /// The repeated fields this one makes up a row with.
+    pub group: Option<&'static str>,
+}
+
+impl Input {
+    pub const DEFAULT: Input = Input {
+        name: "",
+        label: "",
+        input_type: "text",
+        placeholder: "",
+        size: None,
+        live: false,
+        group: None,
+    };
+
+    /// The input alone, for rows where it has no label of its own.
+    pub fn input(&self, value: &str, validate: Option<&str>) -> Markup {
+        self.render(None, value, validate)
+    }
+
+    /// The input with its label and what's wrong with it.
+    pub fn field(
+        &self,
+        value: &str,
+        errors: &[Cow<'static, str>],
+        validate: Option<&str>,
+    ) -> Markup {
+        html! {
+            p {
+                label for=(self.name) { (self.label) }
+                (self.render(Some(self.name), value, validate))
+                (field_errors(errors))
+            }
+        }
+    }
+
+    fn render(&self, id: Option<&str>, value: &str, validate: Option<&str>) -> Markup {
+        let validate = validate.filter(|_| self.live);
+        html! {
+            input id=[id] name=(self.name) type=(self.input_type) size=[self.size]
+                placeholder=(self.placeholder) aria-label=[id.is_none().then_some(self.label)]
+                hx-get=[validate]
+                hx-target=[validate.map(|_| "next .error")]
+                hx-swap=[validate.map(|_| "outerHTML")]
+                hx-trigger=[validate.map(|_| "change, keyup delay:200ms changed")]
+                value=(value);
+        }
+    }
+}
+
+/// Every message about a field, one per line.
+pub fn field_errors(messages: &[Cow<'static, str>]) -> Markup {
+    html! {
+        span .error {
+            @for (index, message) in messages.iter().enumerate() {
+                @if index > 0 { br; }
+                (message)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn not_admin(value: &Option<String>) -> Option<Cow<'static, str>> {
+        value
+            .as_deref()
+            .is_some_and(|value| value.contains("admin"))
+            .then_some("That name is taken".into())
+    }
+
+    form_struct! {
+    #[derive(Deserialize, Default)]
+    pub struct SignUp {
+        username("username"): Option<String> { label: "Username", live: true }
+            => [required, min_length(3), max_length(8), custom(crate::form_struct::tests::not_admin)],
+        nickname("nickname"): Option<String>
+            => [required("Pick a nickname"), max_length(4, "Keep it short")],
+        code("code"): Option<String>
+            => [min_length(2, "Too short"), regex("^[A-Z]+$", "Only capital letters")],
+        #[serde(default)]
+        emails("email"): Vec<String> { label: "Email", input_type: "email" } => [email],
+        phone("phone"): String => [phone],
+    }
+    }
+
+    fn valid() -> SignUp::Form {
+        SignUp::Form {
+            username: Some("ada".to_string()),
+            nickname: Some("Ada".to_string()),
+            code: Some("AB".to_string()),
+            emails: vec!["ada@example.com".to_string()],
+            phone: "+1 415-555-0123".to_string(),
+        }
+    }
+
+    #[test]
+    fn a_valid_form_has_no_errors() {
+        assert!(valid().validate().is_empty());
+    }
+
+    #[test]
+    fn required_fields_need_a_value_that_is_not_blank() {
+        let errors = SignUp::Form {
+            username: None,
+            nickname: Some("   ".to_string()),
+            ..valid()
+        }
+        .validate();
+        assert_eq!(errors.username, ["This field is required"]);
+        assert_eq!(errors.nickname, ["Pick a nickname"]);
+    }
+
+    #[test]
+    fn lengths_are_counted_in_characters() {
+        let errors = SignUp::Form {
+            username: Some("al".to_string()),
+            nickname: Some("Ädä".to_string()),
+            code: Some("A".to_string()),
+            ..valid()
+        }
+        .validate();
+        assert_eq!(errors.username, ["Must be at least 3 characters"]);
+        assert!(errors.nickname.is_empty());
+        assert_eq!(errors.code, ["Too short"]);
+
+        let errors = SignUp::Form {
+            username: Some("adalovelace".to_string()),
+            nickname: Some("Adaaa".to_string()),
+            ..valid()
+        }
+        .validate();
+        assert_eq!(errors.username, ["Must be at most 8 characters"]);
+        assert_eq!(errors.nickname, ["Keep it short"]);
+    }
+
+    #[test]
+    fn emails_and_phones_have_to_parse() {
+        let errors = SignUp::Form {
+            emails: vec![
+                "ada@example.com".to_string(),
+                String::new(),
+                "ada".to_string(),
+            ],
+            phone: "call me".to_string(),
+            ..valid()
+        }
+        .validate();
+        assert_eq!(errors.emails, ["Not a valid email address"]);
+        assert_eq!(errors.phone, ["Not a valid phone number"]);
+    }
+
+    #[test]
+    fn regex_and_custom_rules_give_their_own_message() {
+        let errors = SignUp::Form {
+            username: Some("admin".to_string()),
+            code: Some("ab".to_string()),
+            ..valid()
+        }
+        .validate();
+        assert_eq!(errors.username, ["That name is taken"]);
+        assert_eq!(errors.code, ["Only capital letters"]);
+    }
+
+    #[test]
+    fn every_broken_rule_of_a_field_is_reported() {
+        let errors = SignUp::Form {
+            username: Some("sysadmin1".to_string()),
+            code: Some("a".to_string()),
+            ..valid()
+        }
+        .validate();
+        assert_eq!(
+            errors.username,
+            ["Must be at most 8 characters", "That name is taken"]
+        );
+        assert_eq!(errors.code, ["Too short", "Only capital letters"]);
+    }
+
+    #[test]
+    fn only_required_checks_blank_values() {
+        let errors = SignUp::Form {
+            code: None,
+            emails: vec![String::new()],
+            phone: " ".to_string(),
+            ..valid()
+        }
+        .validate();
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn validate_field_checks_the_field_by_its_name_in_the_form() {
+        let form = SignUp::Form {
+            username: None,
+            emails: vec!["ada".to_string()],
+            ..valid()
+        };
+        assert_eq!(form.validate_field("email"), ["Not a valid email address"]);
+        assert_eq!(form.validate_field("username"), ["This field is required"]);
+        assert!(form.validate_field("emails").is_empty());
+        assert!(form.validate_field("nickname").is_empty());
+    }
+
+    #[test]
+    fn field_errors_are_named_like_the_form() {
+        let errors = SignUp::Form {
+            username: None,
+            emails: vec!["ada".to_string()],
+            ..valid()
+        }
+        .validate()
+        .into_field_errors();
+        let fields: Vec<(&str, &str)> = errors
+            .iter()
+            .map(|error| (error.field, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("username", "This field is required"),
+                ("email", "Not a valid email address")
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_render_the_inputs_of_single_fields_escaped() {
+        let form = SignUp::Form {
+            username: Some("<b>ada</b>".to_string()),
+            ..valid()
+        };
+        let errors = SignUp::Errors {
+            username: vec!["Can't use <b>".into()],
+            ..Default::default()
+        };
+        let html = form
+            .fields(&errors, |name| Some(format!("/validate/{name}")))
+            .into_string();
+
+        assert!(html.contains(r#"<label for="username">Username</label>"#));
+        assert!(html.contains(r#"value="&lt;b&gt;ada&lt;/b&gt;""#));
+        assert!(html.contains(r#"hx-get="/validate/username""#));
+        assert!(html.contains("Can't use &lt;b&gt;"));
+        assert!(!html.contains("<b>"));
+        // Emails are repeated, and the rest have no input.
+        assert!(!html.contains("email"));
+        assert!(!html.contains("nickname"));
+    }
+
+    form_struct! {
+    #[derive(Deserialize, Default)]
+    pub struct Shipping {
+        name("name"): Option<String> { label: "Name" } => [required],
+        #[serde(default)]
+        streets("street"): Vec<String> { label: "Street", group: Some("address") } => [required],
+        #[serde(default)]
+        cities("city"): Vec<String> { label: "City", group: Some("address") } => [max_length(3)],
+    }
+    }
+
+    #[test]
+    fn groups_come_in_rows_and_gather_their_errors() {
+        let form = Shipping::Form {
+            name: None,
+            streets: vec!["1 Main St".to_string(), "2 High St".to_string()],
+            cities: vec!["Rome".to_string()],
+        };
+        let rows: Vec<Vec<(&str, &str)>> = form
+            .group_rows("address")
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|(input, value)| (input.name, value))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                [("street", "1 Main St"), ("city", "Rome")],
+                [("street", "2 High St"), ("city", "")]
+            ]
+        );
+
+        let errors = form.validate();
+        let group: Vec<&str> = errors.group("address").map(|message| &**message).collect();
+        assert_eq!(group, ["Must be at most 3 characters"]);
+        let all: Vec<&str> = errors.iter().map(|message| &**message).collect();
+        assert_eq!(
+            all,
+            ["This field is required", "Must be at most 3 characters"]
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! The history of every contact: an append-only log of its name, phone and email
+//! before and after each change.
+//!
+//! Events are written in the same transaction as the change they record,
+//! so whatever creates, edits, deletes or restores contacts has to call `record` alongside.
+
+use diesel::prelude::*;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+
+use crate::books;
+use crate::model::ContactAttributes;
+use crate::model::ContactEvent;
+use crate::model::ContactEventId;
+use crate::model::ContactId;
+use crate::model::EventKind;
+use crate::model::Permission;
+use crate::model::UserId;
+use crate::AppError;
+
+/// Where a change came from, alongside who made it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Web,
+    Api,
+    Import,
+}
+
+impl Source {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Source::Web => "web",
+            Source::Api => "api",
+            Source::Import => "import",
+        }
+    }
+}
+
+/// A contact's attributes on either side of a change.
+pub struct Change<'a> {
+    pub contact_id: ContactId,
+    pub before: Option<&'a ContactAttributes>,
+    pub after: Option<&'a ContactAttributes>,
+}
+
+/// Appends an event for each change, made by `user`.
+/// Edits that leave the attributes as they were aren't worth an entry.
+pub async fn record(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    source: Source,
+    kind: EventKind,
+    changes: &[Change<'_>],
+) -> Result<(), AppError> {
+    use crate::schema::contact_events;
+
+    let mut rows = vec![];
+    for change in changes {
+        let edit = matches!(kind, EventKind::Updated | EventKind::Reverted);
+        if edit && change.before == change.after {
+            continue;
+        }
+        rows.push((
+            contact_events::contact_id.eq(change.contact_id),
+            contact_events::kind.eq(kind.as_str()),
+            contact_events::source.eq(source.as_str()),
+            contact_events::user_id.eq(user),
+            contact_events::before.eq(change.before.map(serde_json::to_value).transpose()?),
+            contact_events::after.eq(change.after.map(serde_json::to_value).transpose()?),
+        ));
+    }
+    // Stay well clear of Postgres' limit on bind parameters per statement.
+    for chunk in rows.chunks(1000) {
+        diesel::insert_into(contact_events::table)
+            .values(chunk)
+            .execute(connection)
+            .await?;
+    }
+    Ok(())
+}
+
+/// The attributes of a contact that's about to change, locking its row until the end of the
+/// transaction so that the event gets the right `before`.
+/// `None` if it's gone, in the trash or in a book where `user` doesn't have `permission`.
+pub async fn lock_attributes(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    contact_id: ContactId,
+    permission: Permission,
+) -> Result<Option<ContactAttributes>, AppError> {
+    use crate::schema::contacts;
+
+    Ok(contacts::table
+        .find(contact_id)
+        .filter(contacts::address_book_id.eq_any(books::allowing(user, permission)))
+        .filter(contacts::deleted_at.is_null())
+        .select(ContactAttributes::as_select())
+        .for_update()
+        .first(connection)
+        .await
+        .optional()?)
+}
+
+/// A contact's history, most recent first.
+pub async fn events_of(
+    connection: &mut AsyncPgConnection,
+    contact_id: ContactId,
+) -> Result<Vec<ContactEvent>, AppError> {
+    use crate::schema::contact_events;
+    use crate::schema::users;
+
+    Ok(contact_events::table
+        .left_join(users::table)
+        .filter(contact_events::contact_id.eq(contact_id))
+        .order(contact_events::id.desc())
+        .select(ContactEvent::as_select())
+        .load(connection)
+        .await?)
+}
+
+pub async fn find_event(
+    connection: &mut AsyncPgConnection,
+    contact_id: ContactId,
+    event_id: ContactEventId,
+) -> Result<Option<ContactEvent>, AppError> {
+    use crate::schema::contact_events;
+    use crate::schema::users;
+
+    Ok(contact_events::table
+        .left_join(users::table)
+        .filter(contact_events::id.eq(event_id))
+        .filter(contact_events::contact_id.eq(contact_id))
+        .select(ContactEvent::as_select())
+        .first(connection)
+        .await
+        .optional()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel_async::AsyncConnection;
+
+    use super::*;
+    use crate::auth;
+
+    async fn test_connection() -> AsyncPgConnection {
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection = AsyncPgConnection::establish(&url).await.unwrap();
+        connection.begin_test_transaction().await.unwrap();
+        connection
+    }
+
+    #[tokio::test]
+    async fn changes_are_recorded_unless_nothing_changed() {
+        use crate::schema::contacts;
+
+        let mut connection = test_connection().await;
+        let user = auth::create_user(&mut connection, "history-test", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let book = books::default_book(&mut connection, user.id).await.unwrap();
+        let before = ContactAttributes {
+            first_name: "Ada".to_string(),
+            last_name: "Byron".to_string(),
+            phone: Default::default(),
+            email_address: Default::default(),
+        };
+        let after = ContactAttributes {
+            last_name: "Lovelace".to_string(),
+            ..before.clone()
+        };
+        let contact_id: ContactId = diesel::insert_into(contacts::table)
+            .values((&before, contacts::address_book_id.eq(book)))
+            .returning(contacts::id)
+            .get_result(&mut connection)
+            .await
+            .unwrap();
+
+        let created = Change {
+            contact_id,
+            before: None,
+            after: Some(&before),
+        };
+        record(
+            &mut connection,
+            user.id,
+            Source::Import,
+            EventKind::Created,
+            &[created],
+        )
+        .await
+        .unwrap();
+        let unchanged = Change {
+            contact_id,
+            before: Some(&before),
+            after: Some(&before),
+        };
+        record(
+            &mut connection,
+            user.id,
+            Source::Web,
+            EventKind::Updated,
+            &[unchanged],
+        )
+        .await
+        .unwrap();
+        let renamed = Change {
+            contact_id,
+            before: Some(&before),
+            after: Some(&after),
+        };
+        record(
+            &mut connection,
+            user.id,
+            Source::Api,
+            EventKind::Updated,
+            &[renamed],
+        )
+        .await
+        .unwrap();
+
+        let events = events_of(&mut connection, contact_id).await.unwrap();
+        let kinds: Vec<(&str, &str)> = events
+            .iter()
+            .map(|event| (event.kind.as_str(), event.source.as_str()))
+            .collect();
+        assert_eq!(kinds, [("updated", "api"), ("created", "import")]);
+        let latest = &events[0];
+        assert_eq!(latest.username.as_deref(), Some("history-test"));
+        assert_eq!(latest.before.as_ref().unwrap()["last_name"], "Byron");
+        assert_eq!(latest.after.as_ref().unwrap()["last_name"], "Lovelace");
+        assert!(events[1].before.is_none());
+
+        let found = find_event(&mut connection, contact_id, latest.id)
+            .await
+            .unwrap();
+        assert_eq!(found.map(|event| event.id), Some(latest.id));
+    }
+}
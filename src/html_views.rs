@@ -1,16 +1,23 @@
 use axum::body::Body;
+use axum::extract::Multipart;
 use axum::extract::Query;
 use axum::extract::State;
+use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::response::Redirect;
 use axum::response::Response;
 use axum_extra::extract::Form;
+use axum_extra::extract::SignedCookieJar;
 use axum_extra::routing::TypedPath;
 use axum_extra::TypedHeader;
 use axum_flash::Flash;
 use axum_flash::IncomingFlashes;
+use chrono::DateTime;
+use chrono::Utc;
 use diesel::prelude::*;
 use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
 use diesel_async::AsyncPgConnection;
 use diesel_async::RunQueryDsl;
 use maud::html;
@@ -18,12 +25,74 @@ use maud::Markup;
 use maud::DOCTYPE;
 use serde::Deserialize;
 use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::ops::Range;
+use std::sync::Arc;
+use uuid::Uuid;
 
+use crate::archiver::ArchiveJobId;
+use crate::archiver::ArchiveStatus;
+use crate::auth;
+use crate::auth::CurrentUser;
+use crate::books;
+use crate::books::MembershipChange;
+use crate::contact_details;
+use crate::csrf;
+use crate::csrf::CsrfToken;
+use crate::csv_import;
+use crate::csv_import::ColumnMapping;
+use crate::csv_import::CsvTable;
+use crate::csv_import::UploadId;
+use crate::duplicates;
+use crate::errors::Problem;
 use crate::form_struct;
+use crate::form_struct::field_errors;
+use crate::form_struct::Input;
+use crate::form_struct::Messages;
+use crate::history;
+use crate::history::Change;
+use crate::history::Source;
 use crate::hx_trigger_variants;
+use crate::hx_triggers::HxTriggerName;
+use crate::model::AddressBook;
+use crate::model::AddressBookId;
+use crate::model::ApiToken;
+use crate::model::ApiTokenId;
 use crate::model::Contact;
+use crate::model::ContactAttributes;
+use crate::model::ContactDetails;
+use crate::model::ContactEvent;
+use crate::model::ContactEventId;
 use crate::model::ContactId;
+use crate::model::DetailsInput;
+use crate::model::EventKind;
+use crate::model::Membership;
 use crate::model::PendingContact;
+use crate::model::Permission;
+use crate::model::PhoneNumber;
+use crate::model::Role;
+use crate::model::Scope;
+use crate::model::Tag;
+use crate::model::TagId;
+use crate::model::UserId;
+use crate::model::ValidContact;
+use crate::model::ADDRESS;
+use crate::pagination;
+use crate::pagination::ContactFilter;
+use crate::pagination::Cursor;
+use crate::pagination::Ordering;
+use crate::pagination::Recent;
+use crate::pagination::SortColumn;
+use crate::pagination::SortDirection;
+use crate::search;
+use crate::tags;
+use crate::tokens;
+use crate::trash;
+use crate::vcard;
+use crate::vcard::VCardVersion;
 use crate::AppError;
 use crate::AppState;
 
@@ -35,564 +104,3618 @@ pub async fn root(_: Root) -> impl IntoResponse {
     Redirect::permanent(&Contacts.to_string())
 }
 
-pub fn page(body: Markup, flashes: IncomingFlashes) -> (IncomingFlashes, Markup) {
+pub fn page(body: Markup, csrf: &CsrfToken, flashes: IncomingFlashes) -> (IncomingFlashes, Markup) {
+    let body = html! {
+        (body)
+
+        @for flash in &flashes {
+            div .flash { (flash.1)}
+        }
+    };
+    (flashes.clone(), layout(body, csrf))
+}
+
+fn layout(body: Markup, csrf: &CsrfToken) -> Markup {
+    html! {
+        (DOCTYPE)
+        head {
+            script src="https://unpkg.com/htmx.org@1.9.5" crossorigin="anonymous" {}
+            script defer src="https://unpkg.com/alpinejs" crossorigin="anonymous" {}
+            script src="//unpkg.com/hyperscript.org" crossorigin="anonymous" {}
+            link rel="stylesheet" href="/dist/output.css";
+            script src="/dist/rsjs.js" {}
+            meta charset="utf-8";
+        }
+        body .p-10.max-w-prose.m-auto hx-boost="true" hx-headers=[csrf.hx_headers()] {
+            (body)
+        }
+    }
+}
+
+fn problem_details(problem: &Problem) -> Markup {
+    html! {
+        p { (problem.detail) }
+        @if !problem.errors.is_empty() {
+            ul {
+                @for error in &problem.errors {
+                    li { (error.field) ": " (error.message) }
+                }
+            }
+        }
+    }
+}
+
+/// What a browser gets when a request fails, see `errors`.
+pub fn error_page(
+    problem: &Problem,
+    csrf: &CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    (
+        problem.status,
+        page(
+            html! {
+                h1 { (problem.title) }
+                (problem_details(problem))
+                p {
+                    a href=(Contacts) { "Back to contacts" }
+                }
+            },
+            csrf,
+            flashes,
+        ),
+    )
+}
+
+/// What htmx gets when a request fails, added to the end of the page like a flash.
+pub fn error_fragment(problem: &Problem) -> impl IntoResponse {
     (
-        flashes.clone(),
+        problem.status,
+        [("HX-Retarget", "body"), ("HX-Reswap", "beforeend")],
         html! {
-            (DOCTYPE)
-            head {
-                script src="https://unpkg.com/htmx.org@1.9.5" crossorigin="anonymous" {}
-                script defer src="https://unpkg.com/alpinejs" crossorigin="anonymous" {}
-                script src="//unpkg.com/hyperscript.org" crossorigin="anonymous" {}
-                link rel="stylesheet" href="/dist/output.css";
-                script src="/dist/rsjs.js" {}
-                meta charset="utf-8";
+            div .flash.error role="alert" {
+                strong { (problem.title) }
+                (problem_details(problem))
             }
-            body .p-10.max-w-prose.m-auto hx-boost="true" {
-                (body)
+        },
+    )
+}
+
+/// For a form too large to check its CSRF token, let alone read.
+pub fn too_large(csrf: &CsrfToken) -> Response<Body> {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        layout(
+            html! {
+                h1 { "Too Large" }
+                p {
+                    "That's more than the " (csrf::MAX_FORM_SIZE / 1024 / 1024)
+                    " MB we can take at once. Try splitting the file up."
+                }
+                p {
+                    a href=(Contacts) { "Back to contacts" }
+                }
+            },
+            csrf,
+        ),
+    )
+        .into_response()
+}
+
+/// For a request that changes something without the session's CSRF token.
+pub fn forbidden(csrf: &CsrfToken) -> Response<Body> {
+    (
+        StatusCode::FORBIDDEN,
+        layout(
+            html! {
+                h1 { "Forbidden" }
+                p {
+                    "This request didn't come from a page of this site, or the page was from an "
+                    "earlier login. Reload the page and try again."
+                }
+                p {
+                    a href=(Contacts) { "Back to contacts" }
+                }
+            },
+            csrf,
+        ),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/login")]
+pub struct Login;
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/logout")]
+pub struct Logout;
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/signup")]
+pub struct SignUp;
+
+form_struct! {
+#[derive(Deserialize)]
+pub struct Credentials {
+    username("username"): Option<String> => [required("Username cannot be empty")],
+    password("password"): Option<String> => [
+        required("Password cannot be empty"),
+        min_length(
+            crate::html_views::MIN_PASSWORD_LENGTH,
+            format!("Password must be at least {} characters", crate::html_views::MIN_PASSWORD_LENGTH)
+        ),
+    ],
+}
+}
+
+/// Passwords shorter than this are turned away when signing up.
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+pub async fn login_get(_: Login, csrf: CsrfToken, flashes: IncomingFlashes) -> impl IntoResponse {
+    credentials_form(Login.to_string(), "Log In", None, None, &csrf, flashes)
+}
 
-                @for flash in &flashes {
-                    div .flash { (flash.1)}
+pub async fn login_post(
+    _: Login,
+    State(state): State<AppState>,
+    jar: SignedCookieJar,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+    Form(Credentials::Form { username, password }): Form<Credentials::Form>,
+) -> Result<Response<Body>, AppError> {
+    let username = username.unwrap_or_default();
+    let mut connection = state.db_pool.get().await?;
+    let user = auth::authenticate(
+        &mut connection,
+        username.trim(),
+        &password.unwrap_or_default(),
+    )
+    .await?;
+    let Some(user) = user else {
+        return Ok(credentials_form(
+            Login.to_string(),
+            "Log In",
+            Some(&username),
+            Some("Wrong username or password"),
+            &csrf,
+            flashes,
+        )
+        .into_response());
+    };
+    let jar = auth::start_session(&mut connection, jar, user.id).await?;
+    Ok((jar, Redirect::to(&Contacts.to_string())).into_response())
+}
+
+pub async fn logout_post(
+    _: Logout,
+    State(state): State<AppState>,
+    jar: SignedCookieJar,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let jar = auth::end_session(&mut connection, jar).await?;
+    Ok((jar, Redirect::to(&Login.to_string())).into_response())
+}
+
+pub async fn signup_get(_: SignUp, csrf: CsrfToken, flashes: IncomingFlashes) -> impl IntoResponse {
+    credentials_form(SignUp.to_string(), "Sign Up", None, None, &csrf, flashes)
+}
+
+pub async fn signup_post(
+    _: SignUp,
+    State(state): State<AppState>,
+    jar: SignedCookieJar,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+    Form(form): Form<Credentials::Form>,
+) -> Result<Response<Body>, AppError> {
+    let errors = form.validate();
+    let Credentials::Form { username, password } = form;
+    let username = username.unwrap_or_default();
+    let password = password.unwrap_or_default();
+    if let Some(error) = errors.into_field_errors().into_iter().next() {
+        return Ok(credentials_form(
+            SignUp.to_string(),
+            "Sign Up",
+            Some(&username),
+            Some(&error.message),
+            &csrf,
+            flashes,
+        )
+        .into_response());
+    }
+    let mut connection = state.db_pool.get().await?;
+    let Some(user) = auth::create_user(&mut connection, username.trim(), &password).await? else {
+        return Ok(credentials_form(
+            SignUp.to_string(),
+            "Sign Up",
+            Some(&username),
+            Some("That username is taken"),
+            &csrf,
+            flashes,
+        )
+        .into_response());
+    };
+    let jar = auth::start_session(&mut connection, jar, user.id).await?;
+    Ok((jar, Redirect::to(&Contacts.to_string())).into_response())
+}
+
+/// The login and sign up pages, which only differ in where they post to.
+fn credentials_form(
+    action: String,
+    title: &str,
+    username: Option<&str>,
+    error: Option<&str>,
+    csrf: &CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    page(
+        html! {
+            h1 { (title) }
+            form action=(action) method="post" {
+                (csrf.field())
+                p {
+                    label for="username" { "Username" }
+                    input #username name=(Credentials::username()) type="text" autocomplete="username"
+                        required value=(username.unwrap_or_default());
+                }
+                p {
+                    label for="password" { "Password" }
+                    input #password name=(Credentials::password()) type="password" required
+                        autocomplete=(if action == Login.to_string() { "current-password" } else { "new-password" });
+                }
+                span .error { (error.unwrap_or_default()) }
+                button { (title) }
+            }
+            p {
+                @if action == Login.to_string() {
+                    "No account yet? " a href=(SignUp) { "Sign up" }
+                } @else {
+                    "Already have an account? " a href=(Login) { "Log in" }
                 }
             }
         },
+        csrf,
+        flashes,
     )
 }
 
-form_struct!(
-#[derive(Debug, Deserialize)]
-pub struct GetContactsParams {
-    query("q"): Option<String>,
-    page("page"): Option<u32>,
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/account/tokens")]
+pub struct ApiTokens;
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/account/tokens/:id")]
+pub struct ApiTokenPath {
+    pub id: ApiTokenId,
+}
+
+form_struct! {
+#[derive(Deserialize)]
+pub struct NewApiToken {
+    name("name"): Option<String> => [required("Name cannot be empty"), max_length(100)],
+    #[serde(default)]
+    scopes("scope"): Vec<crate::model::Scope> => [custom(crate::html_views::at_least_one_scope)],
+}
+}
+
+fn at_least_one_scope(scopes: &[Scope]) -> Option<Cow<'static, str>> {
+    scopes
+        .is_empty()
+        .then_some("Pick at least one scope".into())
+}
+
+pub async fn api_tokens_get(
+    _: ApiTokens,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let api_tokens = tokens::tokens_of(&mut connection, user.id).await?;
+    Ok(api_tokens_page(
+        &api_tokens,
+        None,
+        &NewApiToken::Form {
+            name: None,
+            scopes: vec![Scope::Read],
+        },
+        NewApiToken::Errors::default(),
+        &csrf,
+        flashes,
+    )
+    .into_response())
+}
+
+pub async fn api_tokens_post(
+    _: ApiTokens,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+    Form(form): Form<NewApiToken::Form>,
+) -> Result<Response<Body>, AppError> {
+    let name = form.name.as_deref().unwrap_or_default().trim();
+    let errors = form.validate();
+    let mut connection = state.db_pool.get().await?;
+    let created = if errors.is_empty() {
+        Some(tokens::create(&mut connection, user.id, name, &form.scopes).await?)
+    } else {
+        None
+    };
+    let api_tokens = tokens::tokens_of(&mut connection, user.id).await?;
+    Ok(api_tokens_page(
+        &api_tokens,
+        created.as_deref(),
+        &form,
+        errors,
+        &csrf,
+        flashes,
+    )
+    .into_response())
+}
+
+/// Revokes a token, removing its row from the tokens page.
+pub async fn api_token_delete(
+    ApiTokenPath { id }: ApiTokenPath,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    tokens::revoke(&mut connection, user.id, id).await?;
+    Ok("".into_response())
+}
+
+/// `created` is a token that was just made, which is the only time it can be shown.
+fn api_tokens_page(
+    api_tokens: &[ApiToken],
+    created: Option<&str>,
+    form: &NewApiToken::Form,
+    errors: NewApiToken::Errors,
+    csrf: &CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    page(
+        html! {
+            h1 { "API Tokens" }
+            p {
+                "Tokens let scripts use the API as you. Send one as "
+                code { "Authorization: Bearer <token>" } "."
+            }
+            @if let Some(created) = created {
+                div .flash {
+                    "Here's your new token. Copy it now, it won't be shown again: "
+                    code { (created) }
+                }
+            }
+            @if api_tokens.is_empty() {
+                p { "You don't have any tokens yet." }
+            } @else {
+                table {
+                    thead {
+                        tr { th { "Name" } th { "Scopes" } th { "Created" } th { "Last Used" } th {} }
+                    }
+                    tbody {
+                        @for api_token in api_tokens {
+                            tr {
+                                td { (api_token.name) }
+                                td { (api_token.scopes.join(", ")) }
+                                td { (timestamp(api_token.created_at)) }
+                                td {
+                                    @match api_token.last_used_at {
+                                        Some(at) => (timestamp(at)),
+                                        None => "Never",
+                                    }
+                                }
+                                td {
+                                    button .bad hx-delete=(ApiTokenPath { id: api_token.id })
+                                        hx-target="closest tr"
+                                        hx-swap="outerHTML"
+                                        hx-confirm="Revoke this token? Anything using it will stop working." { "Revoke" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            form action=(ApiTokens) method="post" {
+                (csrf.field())
+                fieldset {
+                    legend { "New Token" }
+                    p {
+                        label for="name" { "Name" }
+                        input #name name=(NewApiToken::name()) type="text" placeholder="What it's for"
+                            value=[created.is_none().then_some(form.name.as_deref()).flatten()];
+                        (field_errors(&errors.name))
+                    }
+                    p {
+                        @for scope in Scope::ALL {
+                            label {
+                                input type="checkbox" name=(NewApiToken::scopes()) value=(scope.as_str())
+                                    checked[form.scopes.contains(&scope)];
+                                " " (scope.title())
+                            }
+                            " "
+                        }
+                        (field_errors(&errors.scopes))
+                    }
+                    button { "Create Token" }
+                }
+            }
+            p {
+                a href=(Contacts) { "Back" }
+            }
+        },
+        csrf,
+        flashes,
+    )
 }
-);
 
-hx_trigger_variants!(ContactsInteraction { Search: "search" });
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/books")]
+pub struct AddressBooks;
 
 #[derive(Deserialize, TypedPath)]
-#[typed_path("/contacts")]
-pub struct Contacts;
+#[typed_path("/books/:id/members")]
+pub struct AddressBookMembers {
+    pub id: AddressBookId,
+}
 
-pub async fn contacts(
-    _: Contacts,
-    Query(GetContactsParams::Form {
-        query,
-        page: page_number,
-    }): Query<GetContactsParams::Form>,
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/books/:id/members/:user_id")]
+pub struct AddressBookMember {
+    pub id: AddressBookId,
+    pub user_id: UserId,
+}
+
+form_struct! {
+#[derive(Deserialize)]
+pub struct NewAddressBook {
+    name("name"): Option<String> => [required("Name cannot be empty"), max_length(100)],
+}
+}
+
+form_struct! {
+#[derive(Deserialize)]
+pub struct InviteMember {
+    username("username"): Option<String>,
+    role("role"): Option<crate::model::Role>,
+}
+}
+
+pub async fn address_books_get(
+    _: AddressBooks,
     State(state): State<AppState>,
-    contacts_action: Option<TypedHeader<ContactsInteraction>>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let memberships = books::memberships_of(&mut connection, user.id).await?;
+    Ok(address_books_page(
+        &memberships,
+        NewAddressBook::Errors::default(),
+        &csrf,
+        flashes,
+    )
+    .into_response())
+}
+
+pub async fn address_books_post(
+    _: AddressBooks,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+    flash: Flash,
+    Form(form): Form<NewAddressBook::Form>,
+) -> Result<Response<Body>, AppError> {
+    let name = form.name.as_deref().unwrap_or_default().trim();
+    let mut connection = state.db_pool.get().await?;
+    let errors = form.validate();
+    if !errors.is_empty() {
+        let memberships = books::memberships_of(&mut connection, user.id).await?;
+        return Ok(address_books_page(&memberships, errors, &csrf, flashes).into_response());
+    }
+    let book = books::create(&mut connection, user.id, name).await?;
+    Ok((
+        flash.success("Created a new address book!"),
+        Redirect::to(&AddressBookMembers { id: book.id }.to_string()),
+    )
+        .into_response())
+}
+
+fn address_books_page(
+    memberships: &[Membership],
+    errors: NewAddressBook::Errors,
+    csrf: &CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    page(
+        html! {
+            h1 { "Address Books" }
+            table {
+                thead {
+                    tr { th { "Name" } th { "Your Role" } }
+                }
+                tbody {
+                    @for membership in memberships {
+                        tr {
+                            td { a href=(AddressBookMembers { id: membership.book.id }) { (membership.book.name) } }
+                            td { (membership.role.title()) }
+                        }
+                    }
+                }
+            }
+            form action=(AddressBooks) method="post" {
+                (csrf.field())
+                fieldset {
+                    legend { "New Address Book" }
+                    p {
+                        label for="name" { "Name" }
+                        input #name name=(NewAddressBook::name()) type="text" placeholder="Team, Family...";
+                        (field_errors(&errors.name))
+                    }
+                    button { "Create Address Book" }
+                }
+            }
+            p {
+                a href=(Contacts) { "Back" }
+            }
+        },
+        csrf,
+        flashes,
+    )
+}
+
+/// Everyone in the book, which only its members get to see and only admins get to change.
+pub async fn address_book_members_get(
+    AddressBookMembers { id }: AddressBookMembers,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let Some(membership) = books::membership(&mut connection, user.id, id).await? else {
+        return Ok((
+            flash.warning("Could not find address book"),
+            Redirect::to(&AddressBooks.to_string()),
+        )
+            .into_response());
+    };
+    let members = books::members(&mut connection, id).await?;
+    let can_manage = membership.role.can(Permission::Manage);
+    Ok(page(
+        html! {
+            h1 { (membership.book.name) }
+            table {
+                thead {
+                    tr { th { "Member" } th { "Role" } th {} }
+                }
+                tbody {
+                    @for member in &members {
+                        tr {
+                            td { (member.user.username) }
+                            td { (member.role.title()) }
+                            td {
+                                @if member.user.id == user.id {
+                                    button .bad hx-delete=(AddressBookMember { id, user_id: member.user.id })
+                                        hx-target="body"
+                                        hx-confirm="Leave this address book?" { "Leave" }
+                                } @else if can_manage {
+                                    button .bad hx-delete=(AddressBookMember { id, user_id: member.user.id })
+                                        hx-target="body"
+                                        hx-confirm=(format!("Remove {} from this address book?", member.user.username)) { "Remove" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            @if can_manage {
+                form action=(AddressBookMembers { id }) method="post" {
+                    (csrf.field())
+                    fieldset {
+                        legend { "Add a Member or Change Their Role" }
+                        p {
+                            label for="username" { "Username" }
+                            input #username name=(InviteMember::username()) type="text" placeholder="Username";
+                        }
+                        p {
+                            label for="role" { "Role" }
+                            select #role name=(InviteMember::role()) {
+                                @for role in Role::ALL {
+                                    option value=(role.as_str()) { (role.title()) }
+                                }
+                            }
+                        }
+                        button { "Save" }
+                    }
+                }
+            }
+            p {
+                a href=(AddressBooks) { "Back" }
+            }
+        },
+        &csrf,
+        flashes,
+    )
+    .into_response())
+}
+
+pub async fn address_book_members_post(
+    AddressBookMembers { id }: AddressBookMembers,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+    Form(form): Form<InviteMember::Form>,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let role = books::membership(&mut connection, user.id, id)
+        .await?
+        .map(|membership| membership.role);
+    if !role.is_some_and(|role| role.can(Permission::Manage)) {
+        return Err(AppError::Forbidden);
+    }
+    let members = AddressBookMembers { id }.to_string();
+    let username = form.username.as_deref().unwrap_or_default().trim();
+    let role = form.role.unwrap_or(Role::Viewer);
+    let flash = match books::invite(&mut connection, id, username, role).await? {
+        MembershipChange::Done => flash.success(format!("{username} is now {}", role.title())),
+        MembershipChange::UnknownUser => {
+            flash.error(format!("There's no user called {username:?}"))
+        }
+        MembershipChange::LastAdmin => flash.error("The address book needs at least one admin"),
+    };
+    Ok((flash, Redirect::to(&members)).into_response())
+}
+
+/// Removes a member, which admins can do to anyone and everyone can do to themselves.
+pub async fn address_book_member_delete(
+    AddressBookMember { id, user_id }: AddressBookMember,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let role = books::membership(&mut connection, user.id, id)
+        .await?
+        .map(|membership| membership.role);
+    if user_id != user.id && !role.is_some_and(|role| role.can(Permission::Manage)) {
+        return Err(AppError::Forbidden);
+    }
+    match books::remove_member(&mut connection, id, user_id).await? {
+        MembershipChange::LastAdmin => Ok((
+            flash.error("The address book needs at least one admin"),
+            Redirect::to(&AddressBookMembers { id }.to_string()),
+        )
+            .into_response()),
+        _ if user_id == user.id => Ok((
+            flash.success("Left the address book"),
+            Redirect::to(&AddressBooks.to_string()),
+        )
+            .into_response()),
+        _ => Ok((
+            flash.success("Removed member"),
+            Redirect::to(&AddressBookMembers { id }.to_string()),
+        )
+            .into_response()),
+    }
+}
+
+form_struct!(
+#[derive(Debug, Deserialize)]
+pub struct GetContactsParams {
+    query("q"): Option<String>,
+    cursor("cursor"): Option<String>,
+    sort("sort"): Option<crate::pagination::SortColumn>,
+    direction("dir"): Option<crate::pagination::SortDirection>,
+    tag("tag"): Option<String>,
+    recent("recent"): Option<crate::pagination::Recent>,
+    // Set after deleting, to offer putting back what was moved to the trash at that time.
+    undo("undo"): Option<chrono::DateTime<chrono::Utc>>,
+}
+);
+
+hx_trigger_variants!(ContactsInteraction {
+    Search: "search",
+    TagFilter: "tag-filter"
+});
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts")]
+pub struct Contacts;
+
+pub async fn contacts(
+    _: Contacts,
+    Query(GetContactsParams::Form {
+        query,
+        cursor,
+        sort,
+        direction,
+        tag,
+        recent,
+        undo,
+    }): Query<GetContactsParams::Form>,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    contacts_action: Option<TypedHeader<ContactsInteraction>>,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<Response<Body>, AppError> {
+    let search_string = query.clone().filter(|q| !q.trim().is_empty());
+    let tag = tag.filter(|tag| !tag.is_empty());
+    // A cursor we can't make sense of just starts from the top.
+    let cursor = cursor.as_deref().and_then(Cursor::decode);
+    let mut connection = state.db_pool.get().await?;
+    let pagination::Page {
+        contacts,
+        next,
+        prev,
+    } = pagination::load_page(
+        &mut connection,
+        user.id,
+        ContactFilter {
+            query: search_string.as_deref(),
+            tag: tag.as_deref(),
+            recent,
+        },
+        Ordering::new(
+            search_string.as_deref(),
+            sort,
+            direction.unwrap_or_default(),
+            recent,
+        ),
+        cursor.as_ref(),
+        PAGE_SIZE,
+    )
+    .await?;
+    let ids: Vec<ContactId> = contacts.iter().map(|contact| contact.id).collect();
+    let contact_tags = tags::tags_for(&mut connection, &ids).await?;
+    let roles: HashMap<AddressBookId, Role> = books::memberships_of(&mut connection, user.id)
+        .await?
+        .into_iter()
+        .map(|membership| (membership.book.id, membership.role))
+        .collect();
+    let can = |contact: &Contact, permission: Permission| {
+        contact
+            .address_book_id
+            .and_then(|book| roles.get(&book))
+            .is_some_and(|role| role.can(permission))
+    };
+    let can_anywhere = |permission: Permission| roles.values().any(|role| role.can(permission));
+
+    let page_link = |cursor: &Cursor| {
+        Contacts.with_query_params(Pagination {
+            q: search_string.clone(),
+            sort,
+            direction,
+            tag: tag.clone(),
+            recent,
+            cursor: Some(cursor.encode()),
+        })
+    };
+    let tag_link = |name: &str| {
+        Contacts.with_query_params(Pagination {
+            q: search_string.clone(),
+            sort,
+            direction,
+            tag: Some(name.to_string()),
+            recent,
+            cursor: None,
+        })
+    };
+    let recent_link = |recent: Option<Recent>| {
+        Contacts.with_query_params(Pagination {
+            q: search_string.clone(),
+            sort,
+            direction,
+            tag: tag.clone(),
+            recent,
+            cursor: None,
+        })
+    };
+    // Clicking the column we're already sorted by flips the direction.
+    let sort_header = |label: &str, column: SortColumn| {
+        let current = (sort == Some(column)).then(|| direction.unwrap_or_default());
+        let direction = Some(current.map_or(SortDirection::Asc, SortDirection::reversed));
+        let link = Contacts.with_query_params(Pagination {
+            q: search_string.clone(),
+            sort: Some(column),
+            direction,
+            tag: tag.clone(),
+            recent,
+            cursor: None,
+        });
+        // htmx picks up whatever is in the search and tag filter by the time of the click.
+        let sort_only = Contacts.with_query_params(Pagination {
+            sort: Some(column),
+            direction,
+            recent,
+            ..Default::default()
+        });
+        html! {
+            th aria-sort=(match current {
+                Some(SortDirection::Asc) => "ascending",
+                Some(SortDirection::Desc) => "descending",
+                None => "none",
+            }) {
+                a href=(link) hx-get=(sort_only)
+                    hx-include=(format!("#{}, #{}", ContactsInteraction::Search.id(), ContactsInteraction::TagFilter.id()))
+                    hx-target="body" hx-push-url="true" {
+                    (label)
+                    @match current {
+                        Some(SortDirection::Asc) => " ▲",
+                        Some(SortDirection::Desc) => " ▼",
+                        None => "",
+                    }
+                }
+            }
+        }
+    };
+    let rows = html! {
+        @for contact in contacts {
+            tr {
+                td {
+                    @if can(&contact, Permission::Delete) {
+                        input type="checkbox" name=(DeleteContactList::selected_contact_ids()) value=(contact.id) x-model="selected" {}
+                    }
+                }
+                td { (highlight(&contact.first_name, search_string.as_deref()))}
+                td { (highlight(&contact.last_name, search_string.as_deref()))}
+                td { (highlight_phone(&contact.phone, search_string.as_deref()))}
+                td { (highlight(&contact.email_address, search_string.as_deref()))}
+                td {
+                    @for tag in contact_tags.get(&contact.id).into_iter().flatten() {
+                        a .chip href=(tag_link(&tag.name)) { (tag.name) }
+                        " "
+                    }
+                }
+                td {
+                    div data-overflow-menu {
+                        button type="button" aria-haspopup="menu" aria-controls=(format!("contact-menu-{}", contact.id)) {"Options"}
+                        div role="menu" hidden id=(format!("contact-menu-{}", contact.id)) {
+                            @if can(&contact, Permission::Edit) {
+                                a role="menuitem" href=(UpdateContact {id: contact.id}) { "Edit" }
+                                " "
+                            }
+                            a role="menuitem" href=(ViewContact {id: contact.id}) { "View" }
+                            @if can(&contact, Permission::Delete) {
+                                " "
+                                a role="menuitem" href="#" hx-delete=(ViewContact {id: contact.id})
+                                    hx-swap="outerHTML swap:1s"
+                                    hx-confirm="Are you sure you want to delete this contact?"
+                                    hx-target="closest tr" { "Delete" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        @if let Some(next) = &next {
+            tr {
+                td colspan="7" style="text-align: center" {
+                    span hx-target="closest tr"
+                        hx-trigger="revealed"
+                        hx-swap="outerHTML"
+                        hx-select="tbody > tr"
+                        hx-get=(page_link(next)) { "Loading More..." }
+                }
+            }
+        }
+    };
+    if matches!(
+        contacts_action.as_deref(),
+        Some(ContactsInteraction::Search | ContactsInteraction::TagFilter)
+    ) {
+        return Ok(rows.into_response());
+    }
+    let all_tags = tags::all_tags(&mut connection, user.id).await?;
+    // todo: investigate adding new tbody when reach end of hte list
+    Ok(page(
+            html! {
+                @if let Some(trashed_at) = undo {
+                    form .flash action=(UndoTrash) method="post" {
+                        (csrf.field())
+                        input type="hidden" name=(UndoTrashParams::trashed_at()) value=(trashed_at.to_rfc3339());
+                        button { "Undo Delete" }
+                    }
+                }
+                (archive_ui(None))
+                form .tool-bar action=(Contacts) method="get" {
+                    label for=(ContactsInteraction::Search.id()) { "Search Term" }
+                    input id=(ContactsInteraction::Search.id()) type="search" name=(GetContactsParams::query()) placeholder="Search Contacts"
+                    _="on keydown[altKey and code is 'KeyS'] from the window me.focus()" value=(query.as_deref().unwrap_or_default())
+                        hx-get=(Contacts)
+                        hx-include="closest form"
+                        hx-trigger="change, keyup delay:200ms changed"
+                        hx-target="tbody"
+                        hx-push-url="true"
+                        hx-indicator="#spinner";
+                    img #spinner .htmx-indicator src="/dist/img/spinning-circles.svg" alt="Request In Flight";
+                    label for=(ContactsInteraction::TagFilter.id()) { "Tag" }
+                    select id=(ContactsInteraction::TagFilter.id()) name=(GetContactsParams::tag())
+                        hx-get=(Contacts)
+                        hx-include="closest form"
+                        hx-target="tbody"
+                        hx-push-url="true"
+                        hx-indicator="#spinner" {
+                        option value="" { "All" }
+                        @for available in &all_tags {
+                            option value=(available) selected[tag.as_deref() == Some(available.as_str())] { (available) }
+                        }
+                    }
+                    @if let Some(sort) = sort {
+                        input type="hidden" name=(GetContactsParams::sort()) value=(sort.as_str());
+                    }
+                    @if let Some(direction) = direction {
+                        input type="hidden" name=(GetContactsParams::direction()) value=(direction.as_str());
+                    }
+                    @if let Some(recent) = recent {
+                        input type="hidden" name=(GetContactsParams::recent()) value=(recent.as_str());
+                    }
+                    input type="submit" value="Search";
+                }
+                nav aria-label="Views" {
+                    @for (label, view) in [("All Contacts", None), ("Recently Added", Some(Recent::Added)), ("Recently Updated", Some(Recent::Updated))] {
+                        @if view == recent {
+                            strong aria-current="page" { (label) }
+                        } @else {
+                            a href=(recent_link(view)) { (label) }
+                        }
+                        " "
+                    }
+                }
+                form x-data="{ selected: [] }" {
+                    template x-if="selected.length > 0" {
+                        div .box.info.tool-bar {
+                            slot x-text="selected.length" {} " contacts selected "
+                            button type="button" .bad.bg.color.border
+                                x-on:click=(format!("confirm(`Delete ${{selected.length}} contacts?`) && htmx.ajax('DELETE', '{}', {{ source: $root, target: document.body }})", Contacts)) { "Delete" }
+                            hr aria-orientation="vertical";
+                            button type="button" x-on:click="selected = []" { "Cancel" }
+                        }
+                    }
+                    @if let Some(prev) = &prev {
+                        p { a href=(page_link(prev)) { "Previous" } }
+                    }
+                    table {
+                        thead {
+                            tr {
+                                th {}
+                                (sort_header("First", SortColumn::FirstName))
+                                (sort_header("Last", SortColumn::LastName))
+                                (sort_header("Phone", SortColumn::Phone))
+                                (sort_header("Email", SortColumn::EmailAddress))
+                                th { "Tags" }
+                            }
+                        }
+                        tbody {
+                            (rows)
+                        }
+                    }
+                }
+                p {
+                    @if can_anywhere(Permission::Edit) {
+                        a href=(AddContact) { "Add Contact" }
+                        " "
+                        a href=(ImportCsv) { "Import CSV" }
+                        " "
+                        a href=(ImportVCard) { "Import vCards" }
+                        " "
+                    }
+                    a hx-boost="false" href=(ContactsVCard) { "Export vCards" }
+                    " "
+                    @if can_anywhere(Permission::Delete) {
+                        a href=(ContactDuplicates) { "Find Duplicates" }
+                        " "
+                        a href=(ContactsTrash) { "Trash" }
+                        " "
+                    }
+                    a href=(AddressBooks) { "Address Books" }
+                    " "
+                    a href=(ApiTokens) { "API Tokens" }
+                    " "
+                    span hx-get=(ContactsCount) hx-trigger="revealed" {
+                        img #spinner .htmx-indicator src="/dist/img/spinning-circles.svg";
+                    }
+                }
+                form action=(Logout) method="post" {
+                    (csrf.field())
+                    "Logged in as " (user.username) " "
+                    button { "Log Out" }
+                }
+            },
+            &csrf,
+            flashes,
+        ).into_response())
+}
+
+/// Wraps the parts of `text` that matched the search in `mark`s.
+fn highlight(text: &str, query: Option<&str>) -> Markup {
+    let ranges = query
+        .map(|query| search::matched_ranges(text, query))
+        .unwrap_or_default();
+    mark(text, ranges)
+}
+
+/// Phones are shown formatted, and matched by their digits as well as their text.
+fn highlight_phone(number: &PhoneNumber, query: Option<&str>) -> Markup {
+    let text = number.formatted();
+    let ranges = query
+        .map(|query| search::matched_phone_ranges(&text, query))
+        .unwrap_or_default();
+    mark(&text, ranges)
+}
+
+fn mark(text: &str, ranges: Vec<Range<usize>>) -> Markup {
+    let mut segments = vec![];
+    let mut last_end = 0;
+    for range in ranges {
+        segments.push((&text[last_end..range.start], false));
+        segments.push((&text[range.clone()], true));
+        last_end = range.end;
+    }
+    segments.push((&text[last_end..], false));
+    html! {
+        @for (segment, matched) in segments {
+            @if matched { mark { (segment) } } @else { (segment) }
+        }
+    }
+}
+
+const PAGE_SIZE: i64 = 10;
+
+#[derive(Serialize)]
+struct UndoParams {
+    undo: DateTime<Utc>,
+}
+
+/// The contact list, offering to undo the delete that moved contacts to the trash at `trashed_at`.
+fn undo_link(trashed_at: DateTime<Utc>) -> String {
+    Contacts
+        .with_query_params(UndoParams { undo: trashed_at })
+        .to_string()
+}
+
+#[derive(Default, Serialize)]
+pub struct Pagination {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<SortColumn>,
+    #[serde(rename = "dir", skip_serializing_if = "Option::is_none")]
+    pub direction: Option<SortDirection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent: Option<Recent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/count")]
+pub struct ContactsCount;
+
+pub async fn contacts_count(
+    _: ContactsCount,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<String, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let count: i64 = {
+        use crate::schema::contacts::dsl::address_book_id;
+        use crate::schema::contacts::dsl::contacts;
+        use crate::schema::contacts::dsl::deleted_at;
+
+        contacts
+            .filter(address_book_id.eq_any(books::allowing(user.id, Permission::View)))
+            .filter(deleted_at.is_null())
+            .count()
+            .get_result(&mut connection)
+            .await?
+    };
+    Ok(format!("({} total Contacts)", count))
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/new")]
+pub struct AddContact;
+
+/// The address books `user` can add contacts to.
+async fn books_to_add_to(
+    pool: &Pool<AsyncPgConnection>,
+    user: UserId,
+) -> Result<Vec<AddressBook>, AppError> {
+    let mut connection = pool.get().await?;
+    Ok(books::memberships_of(&mut connection, user)
+        .await?
+        .into_iter()
+        .filter(|membership| membership.role.can(Permission::Edit))
+        .map(|membership| membership.book)
+        .collect())
+}
+
+pub async fn contacts_new_get(
+    _: AddContact,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<impl IntoResponse, AppError> {
+    let books = books_to_add_to(&state.db_pool, user.id).await?;
+    Ok(new_contact_form(
+        PendingContact::Form::default(),
+        PendingContact::Errors::default(),
+        &books,
+        &csrf,
+        flashes,
+    ))
+}
+
+pub async fn contacts_new_post(
+    _: AddContact,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+    flash: Flash,
+    Form(pending_contact): Form<PendingContact::Form>,
+) -> Result<Response<Body>, AppError> {
+    let contact = {
+        let mut connection = state.db_pool.get().await?;
+        duplicates::validate(&mut connection, user.id, &pending_contact, None).await?
+    };
+    if let Err(errors) = contact {
+        let books = books_to_add_to(&state.db_pool, user.id).await?;
+        return Ok(
+            new_contact_form(pending_contact.clone(), errors, &books, &csrf, flashes)
+                .into_response(),
+        );
+    } else if let Ok(ValidContact {
+        attributes,
+        details,
+    }) = contact
+    {
+        let mut connection = state.db_pool.get().await?;
+        let book =
+            books::book_to_add_to(&mut connection, user.id, pending_contact.address_book).await?;
+        connection
+            .transaction(|connection| {
+                async move {
+                    use crate::schema::contacts;
+
+                    let contact_id: ContactId = diesel::insert_into(contacts::table)
+                        .values((&attributes, contacts::address_book_id.eq(book)))
+                        .returning(contacts::id)
+                        .get_result(connection)
+                        .await?;
+                    contact_details::insert_details(connection, &[(contact_id, &details)]).await?;
+                    let created = Change {
+                        contact_id,
+                        before: None,
+                        after: Some(&attributes),
+                    };
+                    history::record(
+                        connection,
+                        user.id,
+                        Source::Web,
+                        EventKind::Created,
+                        &[created],
+                    )
+                    .await
+                }
+                .scope_boxed()
+            })
+            .await?;
+    }
+    Ok((
+        flash.success("Created a new contact!"),
+        Redirect::to(&Contacts.to_string()),
+    )
+        .into_response())
+}
+
+pub fn new_contact_form(
+    contact: PendingContact::Form,
+    errors: PendingContact::Errors,
+    books: &[AddressBook],
+    csrf: &CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    fn contact_form(
+        contact: PendingContact::Form,
+        errors: PendingContact::Errors,
+        books: &[AddressBook],
+        csrf: &CsrfToken,
+    ) -> maud::PreEscaped<String> {
+        let body = html! {
+            form action=(AddContact) method="post" {
+                (csrf.field())
+                fieldset {
+                    legend { "Contact Values" }
+                    (contact.fields(&errors, validation_url(None)))
+                    (detail_rows(DetailKind::Email, None, &contact, &errors.email_addresses))
+                    (detail_rows(DetailKind::Phone, None, &contact, &errors.phones))
+                    (address_rows(&contact, &errors))
+                    @if books.len() > 1 {
+                        p {
+                            label for="address_book" {"Address Book"}
+                            select name=(PendingContact::address_book()) id="address_book" {
+                                @for book in books {
+                                    option value=(book.id) selected[contact.address_book == Some(book.id)] {(book.name)}
+                                }
+                            }
+                        }
+                    }
+                    button {"Save"}
+                }
+            }
+            p {
+                a href=(Contacts) {"Back"}
+            }
+        };
+        body
+    }
+
+    let body = contact_form(contact, errors, books, csrf);
+    page(body, csrf, flashes)
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/:id")]
+pub struct ViewContact {
+    pub id: ContactId,
+}
+
+/// A contact outside the trash that `user` can see, if there is one.
+pub async fn find_contact(
+    pool: Pool<AsyncPgConnection>,
+    user: UserId,
+    contact_id: ContactId,
+) -> Result<Option<Contact>, AppError> {
+    let mut connection = pool.get().await?;
+    let contact = {
+        use crate::schema::contacts::dsl::address_book_id;
+        use crate::schema::contacts::dsl::contacts;
+        use crate::schema::contacts::dsl::deleted_at;
+
+        contacts
+            .find(contact_id)
+            .filter(address_book_id.eq_any(books::allowing(user, Permission::View)))
+            .filter(deleted_at.is_null())
+            .select(Contact::as_select())
+            .first(&mut connection)
+            .await
+            .optional()?
+    };
+
+    Ok(contact)
+}
+
+fn timestamp(at: DateTime<Utc>) -> Markup {
+    html! {
+        time datetime=(at.to_rfc3339()) { (at.format("%Y-%m-%d %H:%M UTC")) }
+    }
+}
+
+pub async fn contacts_view(
+    ViewContact { id }: ViewContact,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<Response<Body>, AppError> {
+    let contact = find_contact(state.db_pool.clone(), user.id, id).await?;
+    if let Some(contact) = contact {
+        let (contact_tags, details, events, membership) = {
+            let mut connection = state.db_pool.get().await?;
+            let contact_tags = tags::tags_for(&mut connection, &[id])
+                .await?
+                .remove(&id)
+                .unwrap_or_default();
+            let details = contact_details::details_of(&mut connection, id).await?;
+            let events = history::events_of(&mut connection, id).await?;
+            let membership = match contact.address_book_id {
+                Some(book) => books::membership(&mut connection, user.id, book).await?,
+                None => None,
+            };
+            (contact_tags, details, events, membership)
+        };
+        let can_edit = membership
+            .as_ref()
+            .is_some_and(|membership| membership.role.can(Permission::Edit));
+        fn contact_info(
+            contact: Contact,
+            details: ContactDetails,
+            contact_tags: Vec<Tag>,
+            book: Option<&AddressBook>,
+            can_edit: bool,
+            id: ContactId,
+        ) -> maud::PreEscaped<String> {
+            let several_phones = details.phones.len() > 1;
+            let several_emails = details.emails.len() > 1;
+            let body = html! {
+                h1 {
+                    (contact.first_name) " "  (contact.last_name)
+                }
+                div {
+                    @for phone in &details.phones {
+                        div {
+                            "Phone"
+                            @if !phone.label.is_empty() { " (" (phone.label) ")" }
+                            ": " (phone.number.formatted())
+                            @if phone.primary && several_phones { " — primary" }
+                        }
+                    }
+                    @for email in &details.emails {
+                        div {
+                            "Email"
+                            @if !email.label.is_empty() { " (" (email.label) ")" }
+                            ": " (email.address)
+                            @if email.primary && several_emails { " — primary" }
+                        }
+                    }
+                    @for address in &details.addresses {
+                        div {
+                            "Address"
+                            @if !address.label.is_empty() { " (" (address.label) ")" }
+                            ":"
+                            address {
+                                @for line in address.lines() {
+                                    (line) br;
+                                }
+                            }
+                        }
+                    }
+                    @if !contact_tags.is_empty() {
+                        div {
+                            "Tags: "
+                            @for tag in contact_tags {
+                                a .chip href=(Contacts.with_query_params(Pagination {
+                                    tag: Some(tag.name.clone()),
+                                    ..Default::default()
+                                })) { (tag.name) }
+                                " "
+                            }
+                        }
+                    }
+                    div {
+                        small {
+                            @if let Some(book) = book {
+                                "In " a href=(AddressBookMembers { id: book.id }) { (book.name) } ". "
+                            }
+                            "Added " (timestamp(contact.created_at))
+                            @if contact.updated_at != contact.created_at {
+                                ", last updated " (timestamp(contact.updated_at))
+                            }
+                        }
+                    }
+                }
+                p {
+                    @if can_edit {
+                        a href=(UpdateContact {id}) { "Edit"}
+                        " "
+                    }
+                    a hx-boost="false" href=(ContactVCard {id}) { "Download vCard" }
+                    " "
+                    a href=(Contacts) { "Back" }
+                }
+            };
+            body
+        }
+        let body = html! {
+            (contact_info(contact, details, contact_tags, membership.as_ref().map(|membership| &membership.book), can_edit, id))
+            (history_timeline(id, &events, can_edit, &csrf))
+        };
+        Ok(page(body, &csrf, flashes).into_response())
+    } else {
+        let mut connection = state.db_pool.get().await?;
+        if let Some(kept) = duplicates::merged_into(&mut connection, user.id, id).await? {
+            return Ok((
+                flash.info("This contact was merged into another one"),
+                Redirect::to(&ViewContact { id: kept }.to_string()),
+            )
+                .into_response());
+        }
+        Ok((
+            flash.warning("Could not find contact"),
+            Redirect::to(&Contacts.to_string()),
+        )
+            .into_response())
+    }
+}
+
+/// Every change to the contact, newest first, each with a button to go back to how it left the contact
+/// for those who `can_edit` it.
+fn history_timeline(
+    id: ContactId,
+    events: &[ContactEvent],
+    can_edit: bool,
+    csrf: &CsrfToken,
+) -> Markup {
+    fn fields(contact: &ContactAttributes) -> [(&'static str, &str); 4] {
+        [
+            ("First Name", &contact.first_name),
+            ("Last Name", &contact.last_name),
+            ("Phone", &contact.phone),
+            ("Email", &contact.email_address),
+        ]
+    }
+
+    html! {
+        @if !events.is_empty() {
+            section {
+                h2 { "History" }
+                ol reversed {
+                    @for (index, event) in events.iter().enumerate() {
+                        li {
+                            (event.kind.title()) " " (timestamp(event.created_at))
+                            @if let Some(username) = &event.username {
+                                " by " (username)
+                            }
+                            " via " (event.source)
+                            @if let (Some(before), Some(after)) = (event.before(), event.after()) {
+                                ul {
+                                    @for ((field, from), (_, to)) in fields(&before).into_iter().zip(fields(&after)) {
+                                        @if from != to {
+                                            li { (field) ": " del { (from) } " → " ins { (to) } }
+                                        }
+                                    }
+                                }
+                            }
+                            // The newest event is how the contact is now.
+                            @if can_edit && index > 0 && event.after.is_some() {
+                                form action=(RevertContact { id, event_id: event.id }) method="post" {
+                                    (csrf.field())
+                                    button { "Revert to this version" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/:id/history/:event_id/revert")]
+pub struct RevertContact {
+    pub id: ContactId,
+    pub event_id: ContactEventId,
+}
+
+/// Puts the contact's name, phone and email back the way `event_id` left them.
+pub async fn contacts_revert_post(
+    RevertContact { id, event_id }: RevertContact,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    books::check(&mut connection, user.id, id, Permission::Edit).await?;
+    let target = history::find_event(&mut connection, id, event_id)
+        .await?
+        .and_then(|event| event.after());
+    let Some(target) = target else {
+        return Ok((
+            flash.warning("Could not find that version of the contact"),
+            Redirect::to(&ViewContact { id }.to_string()),
+        )
+            .into_response());
+    };
+    // Checked like an edit, since the version we go back to may clash with what was saved since,
+    // or predate the rules.
+    let details = contact_details::details_of(&mut connection, id).await?;
+    let reverted_form = PendingContact::Form::new(
+        Some(target.first_name.clone()),
+        Some(target.last_name.clone()),
+        &DetailsInput::from(&details).with_primary(&target.phone, &target.email_address),
+    );
+    let target =
+        match duplicates::validate(&mut connection, user.id, &reverted_form, Some(id)).await? {
+            Ok(valid) => valid.attributes,
+            Err(errors) => {
+                let messages: Vec<String> = errors
+                    .into_field_errors()
+                    .into_iter()
+                    .map(|error| error.message)
+                    .collect();
+                return Ok((
+                    flash.error(format!(
+                        "Could not revert the contact: {}",
+                        messages.join(", ")
+                    )),
+                    Redirect::to(&ViewContact { id }.to_string()),
+                )
+                    .into_response());
+            }
+        };
+    let reverted = connection
+        .transaction(|connection| {
+            let target = &target;
+            async move {
+                use crate::schema::contacts;
+
+                let Some(before) =
+                    history::lock_attributes(connection, user.id, id, Permission::Edit).await?
+                else {
+                    return Ok::<_, AppError>(false);
+                };
+                diesel::update(contacts::table.find(id))
+                    .set(target)
+                    .execute(connection)
+                    .await?;
+                contact_details::save_primary(connection, id, target).await?;
+                let change = Change {
+                    contact_id: id,
+                    before: Some(&before),
+                    after: Some(target),
+                };
+                history::record(
+                    connection,
+                    user.id,
+                    Source::Web,
+                    EventKind::Reverted,
+                    &[change],
+                )
+                .await?;
+                Ok(true)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    if !reverted {
+        return Ok((
+            flash.warning("Could not find contact"),
+            Redirect::to(&Contacts.to_string()),
+        )
+            .into_response());
+    }
+    Ok((
+        flash.success("Reverted contact"),
+        Redirect::to(&ViewContact { id }.to_string()),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/:id/edit")]
+pub struct UpdateContact {
+    pub id: ContactId,
+}
+
+pub async fn contacts_edit_get(
+    UpdateContact { id }: UpdateContact,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<Response<Body>, AppError> {
+    let role = {
+        let mut connection = state.db_pool.get().await?;
+        books::check(&mut connection, user.id, id, Permission::Edit).await?
+    };
+    let Some(contact) = find_contact(state.db_pool.clone(), user.id, id).await? else {
+        return Ok((
+            flash.warning("Could not find contact"),
+            Redirect::to(&Contacts.to_string()),
+        )
+            .into_response());
+    };
+    let details = {
+        let mut connection = state.db_pool.get().await?;
+        contact_details::details_of(&mut connection, id).await?
+    };
+    let tag_editor = load_tag_editor(state.db_pool, id).await?;
+    Ok(edit_contact_form(
+        id,
+        PendingContact::Form::from_contact(&contact, &details),
+        PendingContact::Errors::default(),
+        None,
+        tag_editor,
+        can_delete(role),
+        &csrf,
+        flashes,
+    )
+    .into_response())
+}
+
+pub async fn contacts_edit_post(
+    UpdateContact { id }: UpdateContact,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+    flash: Flash,
+    Form(pending_contact): Form<PendingContact::Form>,
+) -> Result<Response<Body>, AppError> {
+    let role = {
+        let mut connection = state.db_pool.get().await?;
+        books::check(&mut connection, user.id, id, Permission::Edit).await?
+    };
+    if find_contact(state.db_pool.clone(), user.id, id)
+        .await?
+        .is_none()
+    {
+        return Ok((
+            flash.warning("Could not find contact"),
+            Redirect::to(&Contacts.to_string()),
+        )
+            .into_response());
+    }
+    let pending = pending_contact.clone();
+    let contact = {
+        let mut connection = state.db_pool.get().await?;
+        duplicates::validate(&mut connection, user.id, &pending_contact, Some(id)).await?
+    };
+    match contact {
+        Err(errors) => {
+            let tag_editor = load_tag_editor(state.db_pool, id).await?;
+            return Ok(edit_contact_form(
+                id,
+                pending,
+                errors,
+                None,
+                tag_editor,
+                can_delete(role),
+                &csrf,
+                flashes,
+            )
+            .into_response());
+        }
+        Ok(ValidContact {
+            attributes,
+            details,
+        }) => {
+            let mut connection = state.db_pool.get().await?;
+            let expected_version = pending.version;
+            let saved = connection
+                .transaction(|connection| {
+                    let (attributes, details) = (&attributes, &details);
+                    async move {
+                        use crate::schema::contacts;
+
+                        // Without a version there's no telling what the form would overwrite.
+                        let Some(expected_version) = expected_version else {
+                            return Ok::<_, AppError>(false);
+                        };
+                        let Some(before) =
+                            history::lock_attributes(connection, user.id, id, Permission::Edit)
+                                .await?
+                        else {
+                            return Ok(false);
+                        };
+                        let updated = diesel::update(
+                            contacts::table
+                                .find(id)
+                                .filter(contacts::deleted_at.is_null())
+                                .filter(contacts::version.eq(expected_version)),
+                        )
+                        .set(attributes)
+                        .execute(connection)
+                        .await?;
+                        if updated == 0 {
+                            return Ok(false);
+                        }
+                        contact_details::replace_details(connection, id, details).await?;
+                        let change = Change {
+                            contact_id: id,
+                            before: Some(&before),
+                            after: Some(attributes),
+                        };
+                        history::record(
+                            connection,
+                            user.id,
+                            Source::Web,
+                            EventKind::Updated,
+                            &[change],
+                        )
+                        .await?;
+                        Ok(true)
+                    }
+                    .scope_boxed()
+                })
+                .await?;
+
+            if !saved {
+                let Some(current) = find_contact(state.db_pool.clone(), user.id, id).await? else {
+                    return Ok((
+                        flash.warning("Could not find contact"),
+                        Redirect::to(&Contacts.to_string()),
+                    )
+                        .into_response());
+                };
+                let current_details = contact_details::details_of(&mut connection, id).await?;
+                let conflict = conflict_diff(&attributes, &details, &current, &current_details);
+                // Identical edits don't need anyone to choose between them.
+                if let Some(conflict) = conflict {
+                    let tag_editor = load_tag_editor(state.db_pool, id).await?;
+                    return Ok(edit_contact_form(
+                        id,
+                        PendingContact::Form {
+                            version: Some(current.version),
+                            ..pending
+                        },
+                        PendingContact::Errors::default(),
+                        Some(conflict),
+                        tag_editor,
+                        can_delete(role),
+                        &csrf,
+                        flashes,
+                    )
+                    .into_response());
+                }
+            }
+        }
+    };
+    Ok((
+        flash.success("Updated contact!"),
+        Redirect::to(&ViewContact { id }.to_string()),
+    )
+        .into_response())
+}
+
+/// The fields that someone else changed while the edit form was open, next to the form's values,
+/// or `None` if the two agree.
+fn conflict_diff(
+    yours: &ContactAttributes,
+    your_details: &ContactDetails,
+    current: &ContactAttributes,
+    current_details: &ContactDetails,
+) -> Option<Markup> {
+    fn labelled(label: &str, value: String, primary: bool) -> String {
+        let mut line = if label.is_empty() {
+            value
+        } else {
+            format!("{label}: {value}")
+        };
+        if primary {
+            line.push_str(" (primary)");
+        }
+        line
+    }
+    fn fields(
+        contact: &ContactAttributes,
+        details: &ContactDetails,
+    ) -> [(&'static str, Vec<String>); 5] {
+        [
+            ("First Name", vec![contact.first_name.clone()]),
+            ("Last Name", vec![contact.last_name.clone()]),
+            (
+                "Emails",
+                details
+                    .emails
+                    .iter()
+                    .map(|email| labelled(&email.label, email.address.to_string(), email.primary))
+                    .collect(),
+            ),
+            (
+                "Phones",
+                details
+                    .phones
+                    .iter()
+                    .map(|phone| labelled(&phone.label, phone.number.formatted(), phone.primary))
+                    .collect(),
+            ),
+            (
+                "Addresses",
+                details
+                    .addresses
+                    .iter()
+                    .map(|address| labelled(&address.label, address.lines().join(", "), false))
+                    .collect(),
+            ),
+        ]
+    }
+
+    let changed: Vec<_> = fields(yours, your_details)
+        .into_iter()
+        .zip(fields(current, current_details))
+        .filter(|((_, yours), (_, current))| yours != current)
+        .map(|((field, yours), (_, current))| (field, yours, current))
+        .collect();
+    if changed.is_empty() {
+        return None;
+    }
+    Some(html! {
+        div .conflict role="alert" {
+            p {
+                "Someone else changed this contact while you were editing it. "
+                "Saving again replaces their changes with yours."
+            }
+            table {
+                thead {
+                    tr { th { "Field" } th { "Your changes" } th { "Current" } }
+                }
+                tbody {
+                    @for (field, yours, current) in changed {
+                        tr {
+                            th { (field) }
+                            td { @for line in yours { (line) br; } }
+                            td { @for line in current { (line) br; } }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Whether someone with `role` in a contact's book can also delete it.
+fn can_delete(role: Option<Role>) -> bool {
+    role.is_some_and(|role| role.can(Permission::Delete))
+}
+
+// Everything but the form itself is optional, depending on how the edit went and who is editing.
+#[allow(clippy::too_many_arguments)]
+pub fn edit_contact_form(
+    id: ContactId,
+    contact: PendingContact::Form,
+    errors: PendingContact::Errors,
+    conflict: Option<Markup>,
+    tag_editor: Markup,
+    can_delete: bool,
+    csrf: &CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    page(
+        html! {
+            @if let Some(conflict) = conflict {
+                (conflict)
+            }
+            form action=(UpdateContact{id}) method="post" {
+                (csrf.field())
+                @if let Some(version) = contact.version {
+                    input type="hidden" name=(PendingContact::version()) value=(version);
+                }
+                fieldset {
+                    legend { "Contact Values" }
+                    (contact.fields(&errors, validation_url(Some(id))))
+                    (detail_rows(DetailKind::Email, Some(id), &contact, &errors.email_addresses))
+                    (detail_rows(DetailKind::Phone, Some(id), &contact, &errors.phones))
+                    (address_rows(&contact, &errors))
+                    button {"Save"}
+                }
+            }
+            (tag_editor)
+            @if can_delete {
+                button #(DeleteTrigger::Button.id()) hx-delete=(ViewContact{id})
+                    hx-target="body"
+                    hx-push-url="true"
+                    hx-confirm="Are you sure you want to delete this contact?" {"Delete Contact"}
+            }
+            p {
+                a href=(Contacts) {"Back"}
+            }
+        },
+        csrf,
+        flashes,
+    )
+}
+
+/// The repeated groups of rows in the contact form.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DetailKind {
+    Phone,
+    Email,
+}
+
+impl DetailKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            DetailKind::Phone => "phone",
+            DetailKind::Email => "email",
+        }
+    }
+
+    /// How the group is called in the form.
+    fn title(self) -> &'static str {
+        self.input().label
+    }
+
+    /// The input of a row's value.
+    fn input(self) -> Input {
+        match self {
+            DetailKind::Phone => PendingContact::inputs::phones,
+            DetailKind::Email => PendingContact::inputs::email_addresses,
+        }
+    }
+
+    /// The names of a row's key and label inputs, and of the group's primary radio buttons.
+    fn field_names(self) -> [&'static str; 3] {
+        match self {
+            DetailKind::Phone => [
+                PendingContact::phone_keys(),
+                PendingContact::phone_labels(),
+                PendingContact::primary_phone(),
+            ],
+            DetailKind::Email => [
+                PendingContact::email_keys(),
+                PendingContact::email_labels(),
+                PendingContact::primary_email(),
+            ],
+        }
+    }
+
+    fn suggested_labels(self) -> &'static [&'static str] {
+        match self {
+            DetailKind::Phone => &["mobile", "home", "work", "other"],
+            DetailKind::Email => &["home", "work", "other"],
+        }
+    }
+}
+
+impl Display for DetailKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/rows/:kind")]
+pub struct NewDetailRow {
+    pub kind: DetailKind,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DetailRowParams {
+    /// The contact being edited, if any, so that email rows can check for duplicates.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contact: Option<ContactId>,
+}
+
+/// A blank row for the "Add" buttons of the contact form.
+pub async fn contacts_detail_row_get(
+    NewDetailRow { kind }: NewDetailRow,
+    _: CurrentUser,
+    Query(DetailRowParams { contact }): Query<DetailRowParams>,
+) -> Markup {
+    detail_row(kind, contact, &Uuid::new_v4().to_string(), "", "", false)
+}
+
+/// All the rows of one group, starting with a blank one if there are none yet.
+fn detail_rows(
+    kind: DetailKind,
+    contact_id: Option<ContactId>,
+    contact: &PendingContact::Form,
+    errors: &[Cow<'static, str>],
+) -> Markup {
+    let (keys, labels, values, primary) = match kind {
+        DetailKind::Phone => (
+            &contact.phone_keys,
+            &contact.phone_labels,
+            &contact.phones,
+            &contact.primary_phone,
+        ),
+        DetailKind::Email => (
+            &contact.email_keys,
+            &contact.email_labels,
+            &contact.email_addresses,
+            &contact.primary_email,
+        ),
+    };
+    let add_row = NewDetailRow { kind }.with_query_params(DetailRowParams {
+        contact: contact_id,
+    });
+    html! {
+        fieldset {
+            legend { (kind.title()) "s" }
+            datalist id=(format!("{kind}-labels")) {
+                @for label in kind.suggested_labels() {
+                    option value=(label) {}
+                }
+            }
+            div id=(format!("{kind}-rows")) {
+                @for ((key, label), value) in keys.iter().zip(labels).zip(values) {
+                    (detail_row(kind, contact_id, key, label, value, primary.as_deref() == Some(key.as_str())))
+                }
+                @if keys.is_empty() {
+                    (detail_row(kind, contact_id, &Uuid::new_v4().to_string(), "", "", true))
+                }
+            }
+            (field_errors(errors))
+            button type="button" hx-get=(add_row) hx-target=(format!("#{kind}-rows")) hx-swap="beforeend" {
+                "Add " (kind.title())
+            }
+        }
+    }
+}
+
+fn detail_row(
+    kind: DetailKind,
+    contact_id: Option<ContactId>,
+    key: &str,
+    label: &str,
+    value: &str,
+    primary: bool,
+) -> Markup {
+    let [key_name, label_name, primary_name] = kind.field_names();
+    html! {
+        p .detail-row {
+            input type="hidden" name=(key_name) value=(key);
+            input name=(label_name) type="text" list=(format!("{kind}-labels")) size="8"
+                placeholder="Label" aria-label="Label" value=(label);
+            (live_input(kind.input(), value, contact_id))
+            label {
+                input type="radio" name=(primary_name) value=(key) checked[primary];
+                " Primary"
+            }
+            button type="button" _="on click remove closest .detail-row" { "Remove" }
+            span .error {}
+        }
+    }
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/rows/address")]
+pub struct NewAddressRow;
+
+pub async fn contacts_address_row_get(_: NewAddressRow, _: CurrentUser) -> Markup {
+    let blank: Vec<(Input, &str)> = PendingContact::inputs::group(ADDRESS)
+        .map(|input| (input, ""))
+        .collect();
+    address_row("", &blank)
+}
+
+fn address_rows(contact: &PendingContact::Form, errors: &PendingContact::Errors) -> Markup {
+    html! {
+        fieldset {
+            legend { "Addresses" }
+            datalist #address-labels {
+                @for label in ["home", "work", "other"] {
+                    option value=(label) {}
+                }
+            }
+            div #address-rows {
+                @for (row, inputs) in contact.group_rows(ADDRESS).iter().enumerate() {
+                    (address_row(contact.address_labels.get(row).map_or("", String::as_str), inputs))
+                }
+            }
+            @for error in errors.group(ADDRESS) {
+                span .error { (error) }
+                " "
+            }
+            button type="button" hx-get=(NewAddressRow) hx-target="#address-rows" hx-swap="beforeend" {
+                "Add Address"
+            }
+        }
+    }
+}
+
+/// `inputs` are those of the `ADDRESS` group, with their values.
+fn address_row(label: &str, inputs: &[(Input, &str)]) -> Markup {
+    html! {
+        div .detail-row {
+            p {
+                input name=(PendingContact::address_labels()) type="text" list="address-labels" size="8"
+                    placeholder="Label" aria-label="Label" value=(label);
+                " "
+                button type="button" _="on click remove closest .detail-row" { "Remove" }
+            }
+            p {
+                @for (input, value) in inputs {
+                    (live_input(*input, value, None))
+                    " "
+                }
+            }
+            span .error {}
+        }
+    }
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/:id/tags")]
+pub struct ContactTags {
+    pub id: ContactId,
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/:id/tags/:tag_id")]
+pub struct ContactTag {
+    pub id: ContactId,
+    pub tag_id: TagId,
+}
+
+form_struct! {
+#[derive(Deserialize)]
+pub struct AddTag {
+    name("tag"): Option<String>,
+}
+}
+
+async fn load_tag_editor(pool: Pool<AsyncPgConnection>, id: ContactId) -> Result<Markup, AppError> {
+    let mut connection = pool.get().await?;
+    let contact_tags = tags::tags_for(&mut connection, &[id])
+        .await?
+        .remove(&id)
+        .unwrap_or_default();
+    let book_tags = tags::book_tags(&mut connection, id).await?;
+    Ok(tag_editor(id, contact_tags, book_tags))
+}
+
+/// Lives outside of the contact's form, since tags are saved as soon as they're added or removed.
+fn tag_editor(id: ContactId, contact_tags: Vec<Tag>, book_tags: Vec<Tag>) -> Markup {
+    html! {
+        fieldset #tag-editor hx-target="this" hx-swap="outerHTML" {
+            legend { "Tags" }
+            p {
+                @for tag in &contact_tags {
+                    span .chip {
+                        (tag.name) " "
+                        button type="button" aria-label=(format!("Remove {}", tag.name))
+                            hx-delete=(ContactTag { id, tag_id: tag.id }) { "×" }
+                    }
+                    " "
+                }
+            }
+            form hx-post=(ContactTags { id }) {
+                label for="tag" { "Add Tag" }
+                input #tag name=(AddTag::name()) type="text" list="known-tags" placeholder="team, customer, vendor...";
+                datalist #known-tags {
+                    @for tag in book_tags {
+                        option value=(tag.name) {}
+                    }
+                }
+                button { "Add" }
+            }
+        }
+    }
+}
+
+pub async fn contacts_tags_post(
+    ContactTags { id }: ContactTags,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Form(AddTag::Form { name }): Form<AddTag::Form>,
+) -> Result<Markup, AppError> {
+    find_contact(state.db_pool.clone(), user.id, id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    {
+        let mut connection = state.db_pool.get().await?;
+        books::check(&mut connection, user.id, id, Permission::Edit).await?;
+        tags::add_tag(&mut connection, id, name.as_deref().unwrap_or_default()).await?;
+    }
+    load_tag_editor(state.db_pool, id).await
+}
+
+pub async fn contacts_tag_delete(
+    ContactTag { id, tag_id }: ContactTag,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Markup, AppError> {
+    find_contact(state.db_pool.clone(), user.id, id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    {
+        let mut connection = state.db_pool.get().await?;
+        books::check(&mut connection, user.id, id, Permission::Edit).await?;
+        tags::remove_tag(&mut connection, id, tag_id).await?;
+    }
+    load_tag_editor(state.db_pool, id).await
+}
+
+hx_trigger_variants!(DeleteTrigger {
+    Button: "delete-btn"
+});
+
+pub async fn contacts_delete(
+    ViewContact { id: contact_id }: ViewContact,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+    deleted_trigger: Option<TypedHeader<DeleteTrigger>>,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    books::check(&mut connection, user.id, contact_id, Permission::Delete).await?;
+    let trashed_at = trash::trash(&mut connection, user.id, Source::Web, &[contact_id]).await?;
+
+    if matches!(deleted_trigger.as_deref(), Some(DeleteTrigger::Button)) {
+        Ok((
+            flash.success("Deleted contact, yo!"),
+            Redirect::to(&undo_link(trashed_at)),
+        )
+            .into_response())
+    } else {
+        Ok("".into_response())
+    }
+}
+
+// Use the full path for `ContactId` because we need to put it in the `mod`'s scope.
+form_struct! {
+#[derive(Deserialize)]
+pub struct DeleteContactList {
+    #[serde(default)]
+    selected_contact_ids("selected_contact_ids"): Vec<crate::model::ContactId>,
+}
+}
+
+// This is already at the `Contacts` page,
+// so we don't have to redirect,
+// but unsure if this is what we want.
+// We might want to copy things over, but
+// what if we were searching or navigating through the pages?
+// Would we copy all of that logic over here?
+// The example in the book renders all contacts.
+pub async fn contacts_delete_all(
+    _: Contacts,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+    Form(to_delete): Form<DeleteContactList::Form>,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let trashed_at = trash::trash(
+        &mut connection,
+        user.id,
+        Source::Web,
+        &to_delete.selected_contact_ids,
+    )
+    .await?;
+
+    Ok((
+        flash.success("Deleted contacts!"),
+        Redirect::to(&undo_link(trashed_at)),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/trash")]
+pub struct ContactsTrash;
+
+pub async fn contacts_trash_get(
+    _: ContactsTrash,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<Response<Body>, AppError> {
+    let trashed = {
+        let mut connection = state.db_pool.get().await?;
+        trash::trashed(&mut connection, user.id).await?
+    };
+    Ok(page(
+        html! {
+            h1 { "Trash" }
+            p {
+                "Deleted contacts are kept here for "
+                (state.trash_retention.num_days())
+                " days before they're gone for good."
+            }
+            @if trashed.is_empty() {
+                p { "The trash is empty." }
+            } @else {
+                button .bad hx-delete=(ContactsTrash)
+                    hx-target="body"
+                    hx-confirm="Permanently delete everything in the trash?" { "Empty Trash" }
+                table {
+                    thead {
+                        tr { th { "Name" } th { "Email" } th { "Deleted" } th {} }
+                    }
+                    tbody {
+                        @for (contact, trashed_at) in &trashed {
+                            tr {
+                                td { (contact.first_name) " " (contact.last_name) }
+                                td { (contact.email_address) }
+                                td { (timestamp(*trashed_at)) }
+                                td {
+                                    button hx-post=(RestoreContact { id: contact.id })
+                                        hx-target="closest tr"
+                                        hx-swap="outerHTML" { "Restore" }
+                                    " "
+                                    button .bad hx-delete=(TrashedContact { id: contact.id })
+                                        hx-target="closest tr"
+                                        hx-swap="outerHTML"
+                                        hx-confirm="Permanently delete this contact?" { "Delete Forever" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            p {
+                a href=(Contacts) { "Back" }
+            }
+        },
+        &csrf,
+        flashes,
+    )
+    .into_response())
+}
+
+pub async fn contacts_trash_delete(
+    _: ContactsTrash,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    trash::empty(&mut connection, user.id).await?;
+    Ok((
+        flash.success("Emptied the trash"),
+        Redirect::to(&ContactsTrash.to_string()),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/trash/:id")]
+pub struct TrashedContact {
+    pub id: ContactId,
+}
+
+/// Deletes one contact in the trash for good, removing its row from the trash page.
+pub async fn contacts_trashed_delete(
+    TrashedContact { id }: TrashedContact,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    trash::purge(&mut connection, user.id, &[id]).await?;
+    Ok("".into_response())
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/trash/:id/restore")]
+pub struct RestoreContact {
+    pub id: ContactId,
+}
+
+pub async fn contacts_restore_post(
+    RestoreContact { id }: RestoreContact,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    trash::restore(&mut connection, user.id, Source::Web, &[id]).await?;
+    Ok("".into_response())
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/trash/undo")]
+pub struct UndoTrash;
+
+form_struct! {
+#[derive(Deserialize)]
+pub struct UndoTrashParams {
+    trashed_at("trashed_at"): chrono::DateTime<chrono::Utc>,
+}
+}
+
+/// The "Undo" of the flash after deleting, which restores everything deleted along with it.
+pub async fn contacts_undo_trash_post(
+    _: UndoTrash,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+    Form(UndoTrashParams::Form { trashed_at }): Form<UndoTrashParams::Form>,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let restored =
+        trash::restore_trashed_at(&mut connection, user.id, Source::Web, trashed_at).await?;
+    let flash = match restored {
+        0 => flash.warning("Nothing to restore, the trash was emptied"),
+        1 => flash.success("Restored contact"),
+        _ => flash.success(format!("Restored {restored} contacts")),
+    };
+    Ok((flash, Redirect::to(&Contacts.to_string())).into_response())
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/duplicates")]
+pub struct ContactDuplicates;
+
+/// Pairs of contacts that look like the same person, each with a link to merge them.
+pub async fn contacts_duplicates_get(
+    _: ContactDuplicates,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
     flashes: IncomingFlashes,
 ) -> Result<Response<Body>, AppError> {
-    let page_number = page_number.unwrap_or(0);
-    let contacts = {
+    let pairs = {
         let mut connection = state.db_pool.get().await?;
-        let search_string = query.clone();
-        {
-            use crate::schema::contacts::dsl::contacts;
-            use crate::schema::contacts::dsl::first_name;
-            use crate::schema::contacts::dsl::id;
-            use crate::schema::contacts::dsl::last_name;
-
-            if let Some(q) = search_string.clone() {
-                contacts
-                    .filter(
-                        first_name
-                            .ilike(format!("{}%", q))
-                            .or(last_name.ilike(format!("{}%", q))),
-                    )
-                    .select(Contact::as_select())
-                    .load(&mut connection)
-                    .await?
-            } else {
-                contacts
-                    .order(id)
-                    .limit(10)
-                    .offset(page_number.into())
-                    .select(Contact::as_select())
-                    .load(&mut connection)
-                    .await?
-            }
-        }
+        duplicates::find_duplicates(&mut connection, user.id).await?
     };
-    let contacts_len = contacts.len();
-    let rows = html! {
-        @for contact in contacts {
-            tr {
-                td {
-                    input type="checkbox" name=(DeleteContactList::selected_contact_ids()) value=(contact.id) x-model="selected" {}
-                }
-                td { (contact.first_name)}
-                td { (contact.last_name)}
-                td { (contact.phone)}
-                td { (contact.email_address)}
-                td {
-                    div data-overflow-menu {
-                        button type="button" aria-haspopup="menu" aria-controls=(format!("contact-menu-{}", contact.id)) {"Options"}
-                        div role="menu" hidden id=(format!("contact-menu-{}", contact.id)) {
-                            a role="menuitem" href=(UpdateContact {id: contact.id}) { "Edit" }
-                            " "
-                            a role="menuitem" href=(ViewContact {id: contact.id}) { "View" }
-                            " "
-                            a role="menuitem" href="#" hx-delete=(ViewContact {id: contact.id})
-                                hx-swap="outerHTML swap:1s"
-                                hx-confirm="Are you sure you want to delete this contact?"
-                                hx-target="closest tr" { "Delete" }
-                        }
-                    }
-                }
+    let name = |contact: &Contact| {
+        html! {
+            a href=(ViewContact { id: contact.id }) { (contact.first_name) " " (contact.last_name) }
+            @if !contact.email_address.is_empty() {
+                br;
+                small { (contact.email_address) }
             }
         }
     };
-    if matches!(
-        contacts_action.as_deref(),
-        Some(ContactsInteraction::Search)
-    ) {
-        return Ok(rows.into_response());
-    }
-    // todo: investigate adding new tbody when reach end of hte list
     Ok(page(
-            html! {
-                form .tool-bar action=(Contacts) method="get" {
-                    label for=(ContactsInteraction::Search.id()) { "Search Term" }
-                    input id=(ContactsInteraction::Search.id()) type="search" name=(GetContactsParams::query()) placeholder="Search Contacts"
-                    _="on keydown[altKey and code is 'KeyS'] from the window me.focus()" value=(query.as_deref().unwrap_or_default())
-                        hx-get=(Contacts)
-                        hx-trigger="change, keyup delay:200ms changed"
-                        hx-target="tbody"
-                        hx-push-url="true"
-                        hx-indicator="#spinner";
-                    img #spinner .htmx-indicator src="/dist/img/spinning-circles.svg" alt="Request In Flight";
-                    input type="submit" value="Search";
-                }
-                form x-data="{ selected: [] }" {
-                    template x-if="selected.length > 0" {
-                        div .box.info.tool-bar {
-                            slot x-text="selected.length" {} " contacts selected "
-                            button type="button" .bad.bg.color.border
-                                x-on:click=(format!("confirm(`Delete ${{selected.length}} contacts?`) && htmx.ajax('DELETE', '{}', {{ source: $root, target: document.body }})", Contacts)) { "Delete" }
-                            hr aria-orientation="vertical";
-                            button type="button" x-on:click="selected = []" { "Cancel" }
-                        }
+        html! {
+            h1 { "Possible Duplicates" }
+            @if pairs.is_empty() {
+                p { "No contacts look like duplicates." }
+            } @else {
+                table {
+                    thead {
+                        tr { th { "Contact" } th { "Contact" } th { "Because of" } th {} }
                     }
-                    table {
-                        thead {
+                    tbody {
+                        @for pair in &pairs {
                             tr {
-                                th {} th {"First"} th {"Last"} th {"Phone"} th {"Email"}
-                            }
-                        }
-                        tbody {
-                            (rows)
-                            @if contacts_len >= 10 {
-                                tr {
-                                    td colspan="5" style="text-align: center" {
-                                        span hx-target="closest tr"
-                                            hx-trigger="revealed"
-                                            hx-swap="outerHTML"
-                                            hx-select="tbody > tr"
-                                            hx-get=(Contacts.with_query_params(Pagination{page: page_number + 1})) { "Loading More..." }
-                                    }
+                                td { (name(&pair.left)) }
+                                td { (name(&pair.right)) }
+                                td { (pair.reasons.describe()) }
+                                td {
+                                    a href=(MergeContacts { id: pair.left.id, other: pair.right.id }) { "Merge" }
                                 }
                             }
                         }
                     }
                 }
-                p {
-                    a href=(AddContact) { "Add Contact" }
-                    " "
-                    span hx-get=(ContactsCount) hx-trigger="revealed" {
-                        img #spinner .htmx-indicator src="/dist/img/spinning-circles.svg";
-                    }
-                }
-            },
-            flashes,
-        ).into_response())
-}
-
-#[derive(Serialize)]
-pub struct Pagination {
-    pub page: u32,
+            }
+            p {
+                a href=(Contacts) { "Back" }
+            }
+        },
+        &csrf,
+        flashes,
+    )
+    .into_response())
 }
 
+/// Merging `other` into `id`: `id` keeps the result and `other` goes to the trash.
 #[derive(Deserialize, TypedPath)]
-#[typed_path("/contacts/count")]
-pub struct ContactsCount;
-
-pub async fn contacts_count(
-    _: ContactsCount,
-    State(state): State<AppState>,
-) -> Result<String, AppError> {
-    let mut connection = state.db_pool.get().await?;
-    let count: i64 = {
-        use crate::schema::contacts::dsl::contacts;
+#[typed_path("/contacts/:id/merge/:other")]
+pub struct MergeContacts {
+    pub id: ContactId,
+    pub other: ContactId,
+}
 
-        contacts.count().get_result(&mut connection).await?
+/// Both contacts, or `None` if either one is gone.
+async fn find_pair(
+    pool: Pool<AsyncPgConnection>,
+    user: UserId,
+    id: ContactId,
+    other: ContactId,
+) -> Result<Option<[(Contact, ContactDetails); 2]>, AppError> {
+    if id == other {
+        return Ok(None);
+    }
+    let (Some(keep), Some(merged)) = (
+        find_contact(pool.clone(), user, id).await?,
+        find_contact(pool.clone(), user, other).await?,
+    ) else {
+        return Ok(None);
     };
-    Ok(format!("({} total Contacts)", count))
+    // Only contacts in the same book can be merged.
+    if keep.address_book_id != merged.address_book_id {
+        return Ok(None);
+    }
+    let mut connection = pool.get().await?;
+    books::check(&mut connection, user, id, Permission::Delete).await?;
+    let keep_details = contact_details::details_of(&mut connection, id).await?;
+    let merged_details = contact_details::details_of(&mut connection, other).await?;
+    Ok(Some([(keep, keep_details), (merged, merged_details)]))
 }
 
-#[derive(Deserialize, TypedPath)]
-#[typed_path("/contacts/new")]
-pub struct AddContact;
-
-pub async fn contacts_new_get(_: AddContact, flashes: IncomingFlashes) -> impl IntoResponse {
-    new_contact_form(
-        PendingContact::Form::default(),
+/// Starts out with everything from both contacts, and the names of the one being kept.
+pub async fn contacts_merge_get(
+    MergeContacts { id, other }: MergeContacts,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<Response<Body>, AppError> {
+    let Some([(keep, keep_details), (merged, merged_details)]) =
+        find_pair(state.db_pool, user.id, id, other).await?
+    else {
+        return Ok((
+            flash.warning("Could not find both contacts"),
+            Redirect::to(&ContactDuplicates.to_string()),
+        )
+            .into_response());
+    };
+    let form = PendingContact::Form::new(
+        Some(keep.first_name.clone()),
+        Some(keep.last_name.clone()),
+        &(&duplicates::combined_details(&keep_details, &merged_details)).into(),
+    );
+    Ok(merge_contacts_form(
+        [&keep, &merged],
+        form,
         PendingContact::Errors::default(),
+        &csrf,
         flashes,
     )
+    .into_response())
 }
 
-pub async fn contacts_new_post(
-    _: AddContact,
+pub async fn contacts_merge_post(
+    MergeContacts { id, other }: MergeContacts,
     State(state): State<AppState>,
-    flashes: IncomingFlashes,
+    CurrentUser(user): CurrentUser,
     flash: Flash,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
     Form(pending_contact): Form<PendingContact::Form>,
 ) -> Result<Response<Body>, AppError> {
-    let contact = pending_contact.to_valid();
-    if let Err(errors) = contact {
-        return Ok(new_contact_form(pending_contact.clone(), errors, flashes).into_response());
-    } else if let Ok(contact) = contact {
-        use crate::schema::contacts;
-
-        let mut connection = state.db_pool.get().await?;
-        {
-            diesel::insert_into(contacts::table)
-                .values(contact)
-                .returning(Contact::as_returning())
-                .execute(&mut connection)
-                .await?;
-        };
+    let Some([(keep, _), (merged, _)]) =
+        find_pair(state.db_pool.clone(), user.id, id, other).await?
+    else {
+        return Ok((
+            flash.warning("Could not find both contacts"),
+            Redirect::to(&ContactDuplicates.to_string()),
+        )
+            .into_response());
+    };
+    let ValidContact {
+        attributes,
+        details,
+    } = match pending_contact.to_valid() {
+        Ok(contact) => contact,
+        Err(errors) => {
+            return Ok(merge_contacts_form(
+                [&keep, &merged],
+                pending_contact,
+                errors,
+                &csrf,
+                flashes,
+            )
+            .into_response())
+        }
+    };
+    let mut connection = state.db_pool.get().await?;
+    if !duplicates::merge(&mut connection, user.id, id, other, &attributes, &details).await? {
+        return Ok((
+            flash.warning("Could not find both contacts"),
+            Redirect::to(&ContactDuplicates.to_string()),
+        )
+            .into_response());
     }
     Ok((
-        flash.success("Created a new contact!"),
-        Redirect::to(&Contacts.to_string()),
+        flash.success("Merged contacts"),
+        Redirect::to(&ViewContact { id }.to_string()),
     )
         .into_response())
 }
 
-pub fn new_contact_form(
+/// Names are picked from either contact, and the rows of both can be kept or removed.
+fn merge_contacts_form(
+    [keep, merged]: [&Contact; 2],
     contact: PendingContact::Form,
     errors: PendingContact::Errors,
+    csrf: &CsrfToken,
     flashes: IncomingFlashes,
 ) -> impl IntoResponse {
-    fn contact_form(
-        contact: PendingContact::Form,
-        errors: PendingContact::Errors,
-    ) -> maud::PreEscaped<String> {
-        let body = html! {
-            form action=(AddContact) method="post" {
-                fieldset {
-                    legend { "Contact Values" }
-                    p {
-                        label for="email" {"Email"}
-                        input name=(PendingContact::email_address()) id="email" type="email" placeholder="Email" value=(contact.email_address.unwrap_or_default());
-                        span .error {(errors.email_address.unwrap_or_default())}
-                    }
-                    p {
-                        label for="first_name" {"First Name"}
-                        input name=(PendingContact::first_name()) id="first_name" type="text" placeholder="First Name" value=(contact.first_name.unwrap_or_default());
-                        span .error {(errors.first_name.unwrap_or_default())}
-                    }
-                    p {
-                        label for="last_name" {"Last Name"}
-                        input name=(PendingContact::last_name()) id="last_name" type="text" placeholder="Last Name" value=(contact.last_name.unwrap_or_default());
-                        span .error {(errors.last_name.unwrap_or_default())}
-                    }
-                    p {
-                        label for="phone" {"Phone"}
-                        input name=(PendingContact::phone()) id="phone" type="text" placeholder="Phone" value=(contact.phone.unwrap_or_default());
-                        span .error {(errors.phone.unwrap_or_default())}
+    let name_choice = |legend: &str,
+                       name: &str,
+                       options: [&str; 2],
+                       chosen: Option<&str>,
+                       errors: &Messages| {
+        let mut options = options.to_vec();
+        options.dedup();
+        html! {
+            fieldset {
+                legend { (legend) }
+                @for option in options {
+                    label {
+                        input type="radio" name=(name) value=(option) checked[chosen == Some(option)];
+                        " "
+                        @if option.is_empty() { em { "(none)" } } @else { (option) }
                     }
-                    button {"Save"}
+                    " "
                 }
+                (field_errors(errors))
+            }
+        }
+    };
+    page(
+        html! {
+            h1 { "Merge Contacts" }
+            p {
+                "Everything below is saved to "
+                a href=(ViewContact { id: keep.id }) { (keep.first_name) " " (keep.last_name) }
+                ", along with the tags of both. "
+                a href=(ViewContact { id: merged.id }) { (merged.first_name) " " (merged.last_name) }
+                " is moved to the trash. "
+                a href=(MergeContacts { id: merged.id, other: keep.id }) { "Keep the other one instead" }
+            }
+            form action=(MergeContacts { id: keep.id, other: merged.id }) method="post" {
+                (csrf.field())
+                (name_choice(
+                    "First Name",
+                    PendingContact::first_name(),
+                    [&keep.first_name, &merged.first_name],
+                    contact.first_name.as_deref(),
+                    &errors.first_name,
+                ))
+                (name_choice(
+                    "Last Name",
+                    PendingContact::last_name(),
+                    [&keep.last_name, &merged.last_name],
+                    contact.last_name.as_deref(),
+                    &errors.last_name,
+                ))
+                (detail_rows(DetailKind::Email, None, &contact, &errors.email_addresses))
+                (detail_rows(DetailKind::Phone, None, &contact, &errors.phones))
+                (address_rows(&contact, &errors))
+                button { "Merge" }
             }
             p {
-                a href=(Contacts) {"Back"}
+                a href=(ContactDuplicates) { "Back" }
             }
-        };
-        body
-    }
-
-    let body = contact_form(contact, errors);
-    page(body, flashes)
+        },
+        csrf,
+        flashes,
+    )
 }
 
+hx_trigger_variants!(ContactField {
+    FirstName: PendingContact::first_name(),
+    LastName: PendingContact::last_name(),
+    Phone: PendingContact::phones(),
+    EmailAddress: PendingContact::email_addresses(),
+    Street: PendingContact::streets(),
+    City: PendingContact::cities(),
+    Region: PendingContact::regions(),
+    PostalCode: PendingContact::postal_codes(),
+    Country: PendingContact::countries()
+});
+
 #[derive(Deserialize, TypedPath)]
-#[typed_path("/contacts/:id")]
-pub struct ViewContact {
-    pub id: ContactId,
-}
+#[typed_path("/contacts/validate")]
+pub struct ValidateField;
 
-pub async fn find_contact(
-    pool: Pool<AsyncPgConnection>,
-    contact_id: ContactId,
-) -> Result<Contact, AppError> {
-    let mut connection = pool.get().await?;
-    let contact = {
-        use crate::schema::contacts::dsl::contacts;
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ValidateFieldParams {
+    /// The contact being edited, if any, which can keep its own email addresses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contact: Option<ContactId>,
+}
 
-        contacts
-            .find(contact_id)
-            .select(Contact::as_select())
-            .first(&mut connection)
-            .await?
-    };
+/// Where the contact form's inputs check themselves as they're typed, for the fields that can.
+/// The endpoint tells them apart by the name htmx sends in `HX-Trigger-Name`.
+fn validation_url(contact: Option<ContactId>) -> impl Fn(&'static str) -> Option<String> {
+    move |name| {
+        ContactField::from_id(name).map(|_| {
+            ValidateField
+                .with_query_params(ValidateFieldParams { contact })
+                .to_string()
+        })
+    }
+}
 
-    Ok(contact)
+fn live_input(input: Input, value: &str, contact: Option<ContactId>) -> Markup {
+    input.input(value, validation_url(contact)(input.name).as_deref())
 }
 
-pub async fn contacts_view(
-    ViewContact { id }: ViewContact,
+/// Checks the input that changed with the rules of its field, the same ones as saving the contact.
+/// Email addresses also have to be unique, and point at the other contact using them if they aren't.
+pub async fn contacts_validate_get(
+    _: ValidateField,
+    TypedHeader(HxTriggerName(field)): TypedHeader<HxTriggerName<ContactField>>,
+    Query(ValidateFieldParams { contact }): Query<ValidateFieldParams>,
     State(state): State<AppState>,
-    flash: Flash,
-    flashes: IncomingFlashes,
-) -> Result<Response<Body>, AppError> {
-    let contact = find_contact(state.db_pool, id).await;
-    if let Ok(contact) = contact {
-        fn contact_info(contact: Contact, id: ContactId) -> maud::PreEscaped<String> {
-            let body = html! {
-                h1 {
-                    (contact.first_name) " "  (contact.last_name)
+    CurrentUser(user): CurrentUser,
+    Form(form): Form<PendingContact::Form>,
+) -> Result<Markup, AppError> {
+    let messages = form.validate_field(field.id());
+    let email = match field {
+        ContactField::EmailAddress if messages.is_empty() => {
+            form.email_addresses.as_slice().first()
+        }
+        _ => None,
+    };
+    let Some(email) = email.filter(|email| !email.trim().is_empty()) else {
+        return Ok(field_errors(&messages));
+    };
+
+    let mut connection = state.db_pool.get().await?;
+    let book = duplicates::book_for(&mut connection, user.id, &form, contact).await?;
+    Ok(
+        match duplicates::email_owner(&mut connection, user.id, book, email, contact).await? {
+            None => field_errors(&messages),
+            Some(owner) => html! {
+                span .error {
+                    "Email must be unique, "
+                    a href=(ViewContact { id: owner.id }) { (owner.first_name) " " (owner.last_name) }
+                    " already has it. "
+                    @if let Some(id) = contact {
+                        a href=(MergeContacts { id, other: owner.id }) { "Merge them" }
+                    }
                 }
-                div {
-                    div { "Phone: " (contact.phone)}
-                    div { "Email: " (contact.email_address)}
+            },
+        },
+    )
+}
+
+hx_trigger_variants!(ArchiveInteraction {
+    Poll: "archive-progress"
+});
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/archive")]
+pub struct ContactsArchive;
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/archive/:job_id")]
+pub struct ArchiveJob {
+    pub job_id: ArchiveJobId,
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/archive/:job_id/file")]
+pub struct ArchiveFile {
+    pub job_id: ArchiveJobId,
+}
+
+/// Everything the archive flow swaps in and out lives in this one `div`,
+/// so each endpoint only has to return the next state of it.
+fn archive_ui(job: Option<(ArchiveJobId, ArchiveStatus)>) -> Markup {
+    html! {
+        div #archive-ui hx-target="this" hx-swap="outerHTML" {
+            @match job {
+                Some((job_id, ArchiveStatus::Running(progress))) => {
+                    div #(ArchiveInteraction::Poll.id()) hx-get=(ArchiveJob { job_id }) hx-trigger="load delay:500ms" {
+                        "Creating Archive... "
+                        progress max="100" value=(format!("{:.0}", progress * 100.0)) {}
+                    }
+                    button hx-delete=(ArchiveJob { job_id }) { "Cancel" }
                 }
-                p {
-                    a href=(UpdateContact {id}) { "Edit"}
+                Some((job_id, ArchiveStatus::Complete(_))) => {
+                    a hx-boost="false" href=(ArchiveFile { job_id }) { "Archive Ready! Click here to download." }
                     " "
-                    a href=(Contacts) { "Back" }
+                    button hx-delete=(ArchiveJob { job_id }) { "Clear Download" }
                 }
-            };
-            body
+                Some((_, ArchiveStatus::Failed)) => {
+                    span .error { "Could not create the archive. " }
+                    button hx-post=(ContactsArchive) { "Try Again" }
+                }
+                None => {
+                    button hx-post=(ContactsArchive) { "Download Contact Archive" }
+                }
+            }
         }
-        let body = contact_info(contact, id);
-        Ok(page(body, flashes).into_response())
-    } else {
-        Ok((
-            flash.warning("Could not find contact"),
+    }
+}
+
+pub async fn contacts_archive_post(
+    _: ContactsArchive,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Markup {
+    let job_id = state.archive_jobs.start(state.db_pool, user.id);
+    archive_ui(
+        state
+            .archive_jobs
+            .status(user.id, job_id)
+            .map(|status| (job_id, status)),
+    )
+}
+
+pub async fn contacts_archive_get(
+    ArchiveJob { job_id }: ArchiveJob,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    archive_action: Option<TypedHeader<ArchiveInteraction>>,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> Response<Body> {
+    let ui = archive_ui(
+        state
+            .archive_jobs
+            .status(user.id, job_id)
+            .map(|status| (job_id, status)),
+    );
+    if matches!(archive_action.as_deref(), Some(ArchiveInteraction::Poll)) {
+        return ui.into_response();
+    }
+    page(
+        html! {
+            (ui)
+            p {
+                a href=(Contacts) {"Back"}
+            }
+        },
+        &csrf,
+        flashes,
+    )
+    .into_response()
+}
+
+pub async fn contacts_archive_delete(
+    ArchiveJob { job_id }: ArchiveJob,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Markup {
+    state.archive_jobs.cancel(user.id, job_id).await;
+    archive_ui(None)
+}
+
+pub async fn contacts_archive_file(
+    ArchiveFile { job_id }: ArchiveFile,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    flash: Flash,
+) -> Result<Response<Body>, AppError> {
+    let Some(ArchiveStatus::Complete(file)) = state.archive_jobs.status(user.id, job_id) else {
+        return Ok((
+            flash.warning("Archive is not ready"),
             Redirect::to(&Contacts.to_string()),
         )
-            .into_response())
-    }
+            .into_response());
+    };
+    let contents = tokio::fs::read(file).await?;
+    Ok((
+        [
+            (axum::http::header::CONTENT_TYPE, "application/json"),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                "attachment; filename=\"contacts.json\"",
+            ),
+        ],
+        contents,
+    )
+        .into_response())
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct VCardParams {
+    #[serde(default)]
+    pub version: VCardVersion,
+}
+
+fn vcard_response(file_name: &str, cards: String) -> Response<Body> {
+    (
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                "text/vcard; charset=utf-8".to_string(),
+            ),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}\""),
+            ),
+        ],
+        cards,
+    )
+        .into_response()
 }
 
 #[derive(Deserialize, TypedPath)]
-#[typed_path("/contacts/:id/edit")]
-pub struct UpdateContact {
+#[typed_path("/contacts/:id/vcard")]
+pub struct ContactVCard {
     pub id: ContactId,
 }
 
-pub async fn contacts_edit_get(
-    UpdateContact { id }: UpdateContact,
+pub async fn contacts_vcard_get(
+    ContactVCard { id }: ContactVCard,
+    Query(VCardParams { version }): Query<VCardParams>,
     State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
     flash: Flash,
-    flashes: IncomingFlashes,
-) -> impl IntoResponse {
-    let contact = find_contact(state.db_pool, id).await;
-    if contact.is_err() {
-        return (
+) -> Result<Response<Body>, AppError> {
+    let Some(contact) = find_contact(state.db_pool.clone(), user.id, id).await? else {
+        return Ok((
             flash.warning("Could not find contact"),
             Redirect::to(&Contacts.to_string()),
         )
-            .into_response();
-    }
-    let contact = contact.unwrap();
-    edit_contact_form(
-        id,
-        contact.into(),
-        PendingContact::Errors::default(),
-        flashes,
-    )
-    .into_response()
+            .into_response());
+    };
+    let details = {
+        let mut connection = state.db_pool.get().await?;
+        contact_details::details_of(&mut connection, id).await?
+    };
+    Ok(vcard_response(
+        &format!("contact-{id}.vcf"),
+        vcard::write_vcard(&contact, &details, version),
+    ))
 }
 
-pub async fn contacts_edit_post(
-    UpdateContact { id }: UpdateContact,
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/vcard")]
+pub struct ContactsVCard;
+
+pub async fn contacts_vcards_get(
+    _: ContactsVCard,
+    Query(VCardParams { version }): Query<VCardParams>,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Response<Body>, AppError> {
+    let mut connection = state.db_pool.get().await?;
+    let contacts: Vec<Contact> = {
+        use crate::schema::contacts::dsl::address_book_id;
+        use crate::schema::contacts::dsl::contacts;
+        use crate::schema::contacts::dsl::deleted_at;
+        use crate::schema::contacts::dsl::id;
+
+        contacts
+            .filter(address_book_id.eq_any(books::allowing(user.id, Permission::View)))
+            .filter(deleted_at.is_null())
+            .order(id)
+            .select(Contact::as_select())
+            .load(&mut connection)
+            .await?
+    };
+    let ids: Vec<ContactId> = contacts.iter().map(|contact| contact.id).collect();
+    let details = contact_details::details_for(&mut connection, &ids).await?;
+    let no_details = ContactDetails::default();
+    Ok(vcard_response(
+        "contacts.vcf",
+        vcard::write_vcards(
+            contacts.iter().map(|contact| {
+                (
+                    &contact.attributes,
+                    details.get(&contact.id).unwrap_or(&no_details),
+                )
+            }),
+            version,
+        ),
+    ))
+}
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/import/vcard")]
+pub struct ImportVCard;
+
+/// What happened to each card of an uploaded `.vcf` file.
+#[derive(Default)]
+pub struct VCardImport {
+    pub created: usize,
+    pub updated: usize,
+    /// Position of the card in the file, what we could read of it and why it was turned away.
+    pub rejected: Vec<(usize, PendingContact::Form, PendingContact::Errors)>,
+}
+
+const EMAILS_OF_SEVERAL_CONTACTS: &str =
+    "The email addresses belong to more than one contact, merge them first";
+
+pub async fn contacts_import_vcard_get(
+    _: ImportVCard,
+    _: CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    import_vcard_form(None, None, &csrf, flashes)
+}
+
+/// Cards are matched to existing contacts by any of their email addresses:
+/// a match is updated in place, anything else becomes a new contact.
+/// A card whose emails belong to more than one contact is turned away, since it's unclear which to update.
+pub async fn contacts_import_vcard_post(
+    _: ImportVCard,
     State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
     flashes: IncomingFlashes,
     flash: Flash,
-    Form(pending_contact): Form<PendingContact::Form>,
+    mut multipart: Multipart,
 ) -> Result<Response<Body>, AppError> {
-    let pending = pending_contact.clone();
-    let contact = pending_contact.to_valid();
-    match contact {
-        Err(errors) => return Ok(edit_contact_form(id, pending, errors, flashes).into_response()),
-        Ok(contact) => {
-            let mut connection = state.db_pool.get().await?;
-            {
-                use crate::schema::contacts::dsl::contacts;
-
-                let contact_id = id;
-                diesel::update(contacts.find(contact_id))
-                    .set(contact)
-                    .execute(&mut connection)
-                    .await?
-            };
-        }
+    let Some(upload) = read_upload(&mut multipart).await else {
+        return Ok(import_vcard_form(
+            None,
+            Some("Could not read the uploaded file"),
+            &csrf,
+            flashes,
+        )
+        .into_response());
     };
-    Ok((
-        flash.success("Updated contact!"),
-        Redirect::to(&ViewContact { id }.to_string()),
-    )
-        .into_response())
+
+    let mut import = VCardImport::default();
+    let mut valid: Vec<(usize, PendingContact::Form, ValidContact)> = vec![];
+    for (index, card) in vcard::parse_vcards(&upload).into_iter().enumerate() {
+        match card.to_valid() {
+            Ok(contact) => valid.push((index + 1, card, contact)),
+            Err(errors) => import.rejected.push((index + 1, card, errors)),
+        }
+    }
+
+    let mut connection = state.db_pool.get().await?;
+    let book = books::default_book(&mut connection, user.id).await?;
+    let (created, updated, ambiguous) = connection
+        .transaction(|connection| {
+            async move {
+                use crate::duplicates::btrim;
+                use crate::duplicates::lower;
+                use crate::schema::contact_emails;
+                use crate::schema::contacts;
+
+                let mut created = 0;
+                let mut updated = 0;
+                let mut ambiguous = vec![];
+                for (
+                    index,
+                    card,
+                    ValidContact {
+                        attributes,
+                        details,
+                    },
+                ) in valid
+                {
+                    let emails: Vec<String> = details
+                        .emails
+                        .iter()
+                        .map(|email| duplicates::normalize_email(&email.address))
+                        .collect();
+                    // Read before writing, for the history.
+                    let existing: Vec<(ContactId, ContactAttributes)> = contacts::table
+                        .filter(contacts::address_book_id.eq(book))
+                        .filter(contacts::deleted_at.is_null())
+                        .filter(
+                            contacts::id.eq_any(
+                                contact_emails::table
+                                    .filter(lower(btrim(contact_emails::address)).eq_any(&emails))
+                                    .select(contact_emails::contact_id),
+                            ),
+                        )
+                        .order(contacts::id)
+                        .select((contacts::id, ContactAttributes::as_select()))
+                        .for_update()
+                        .load(connection)
+                        .await?;
+                    match existing.as_slice() {
+                        [] => {
+                            let contact_id: ContactId = diesel::insert_into(contacts::table)
+                                .values((&attributes, contacts::address_book_id.eq(book)))
+                                .returning(contacts::id)
+                                .get_result(connection)
+                                .await?;
+                            contact_details::insert_details(connection, &[(contact_id, &details)])
+                                .await?;
+                            let change = Change {
+                                contact_id,
+                                before: None,
+                                after: Some(&attributes),
+                            };
+                            history::record(
+                                connection,
+                                user.id,
+                                Source::Import,
+                                EventKind::Created,
+                                &[change],
+                            )
+                            .await?;
+                            created += 1;
+                        }
+                        [(contact_id, before)] => {
+                            diesel::update(contacts::table.find(contact_id))
+                                .set(&attributes)
+                                .execute(connection)
+                                .await?;
+                            contact_details::replace_details(connection, *contact_id, &details)
+                                .await?;
+                            let change = Change {
+                                contact_id: *contact_id,
+                                before: Some(before),
+                                after: Some(&attributes),
+                            };
+                            history::record(
+                                connection,
+                                user.id,
+                                Source::Import,
+                                EventKind::Updated,
+                                &[change],
+                            )
+                            .await?;
+                            updated += 1;
+                        }
+                        _ => {
+                            let errors = PendingContact::Errors {
+                                email_addresses: vec![EMAILS_OF_SEVERAL_CONTACTS.into()],
+                                ..Default::default()
+                            };
+                            ambiguous.push((index, card, errors));
+                        }
+                    }
+                }
+                Ok::<_, AppError>((created, updated, ambiguous))
+            }
+            .scope_boxed()
+        })
+        .await?;
+    import.created = created;
+    import.updated = updated;
+    import.rejected.extend(ambiguous);
+    import.rejected.sort_by_key(|(index, _, _)| *index);
+
+    if import.rejected.is_empty() {
+        return Ok((
+            flash.success(format!(
+                "Imported {} new and {} updated contacts!",
+                import.created, import.updated
+            )),
+            Redirect::to(&Contacts.to_string()),
+        )
+            .into_response());
+    }
+    Ok(import_vcard_form(Some(import), None, &csrf, flashes).into_response())
 }
 
-pub fn edit_contact_form(
-    id: ContactId,
-    contact: PendingContact::Form,
-    errors: PendingContact::Errors,
+// Only used for the field name, the contents come in through `Multipart`.
+form_struct! {
+#[derive(Deserialize)]
+pub struct FileUpload {
+    file("file"): Option<String>,
+}
+}
+
+/// Pulls the text of the uploaded file out of a multipart form,
+/// or `None` if there wasn't one we could read.
+async fn read_upload(multipart: &mut Multipart) -> Option<String> {
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some(FileUpload::file()) {
+            return field.text().await.ok();
+        }
+    }
+    None
+}
+
+pub fn import_vcard_form(
+    import: Option<VCardImport>,
+    error: Option<&'static str>,
+    csrf: &CsrfToken,
     flashes: IncomingFlashes,
 ) -> impl IntoResponse {
     page(
         html! {
-            form action=(UpdateContact{id}) method="post" {
+            form action=(ImportVCard) method="post" enctype="multipart/form-data" {
+                (csrf.field())
                 fieldset {
-                    legend { "Contact Values" }
-                    p {
-                        label for="email" {"Email"}
-                        input name=(PendingContact::email_address()) id="email" type="email"
-                        hx-get=(ContactEmail{id})
-                        hx-target="next .error"
-                        hx-trigger="change, keyup delay:200ms changed"
-                        placeholder="Email" value=(contact.email_address.unwrap_or_default());
-                        span .error {(errors.email_address.unwrap_or_default())}
-                    }
-                    p {
-                        label for="first_name" {"First Name"}
-                        input name=(PendingContact::first_name()) id="first_name" type="text" placeholder="First Name" value=(contact.first_name.unwrap_or_default());
-                        span .error {(errors.first_name.unwrap_or_default())}
-                    }
+                    legend { "Import vCards" }
                     p {
-                        label for="last_name" {"Last Name"}
-                        input name=(PendingContact::last_name()) id="last_name" type="text" placeholder="Last Name" value=(contact.last_name.unwrap_or_default());
-                        span .error {(errors.last_name.unwrap_or_default())}
+                        label for="file" {"vCard File"}
+                        input name=(FileUpload::file()) id="file" type="file" accept=".vcf,text/vcard";
+                        span .error {(error.unwrap_or_default())}
                     }
-                    p {
-                        label for="phone" {"Phone"}
-                        input name=(PendingContact::phone()) id="phone" type="text" placeholder="Phone" value=(contact.phone.unwrap_or_default());
-                        span .error {(errors.phone.unwrap_or_default())}
+                    button {"Import"}
+                }
+            }
+            @if let Some(import) = import {
+                p {
+                    "Imported " (import.created) " new and " (import.updated) " updated contacts. "
+                    "These cards could not be imported:"
+                }
+                ul {
+                    @for (index, card, errors) in import.rejected {
+                        li {
+                            "Card " (index) ": "
+                            (card.first_name.unwrap_or_default()) " " (card.last_name.unwrap_or_default())
+                            @for error in errors.iter() {
+                                " "
+                                span .error { (error) }
+                            }
+                        }
                     }
-                    button {"Save"}
                 }
             }
-            button #(DeleteTrigger::Button.id()) hx-delete=(ViewContact{id})
-                hx-target="body"
-                hx-push-url="true"
-                hx-confirm="Are you sure you want to delete this contact?" {"Delete Contact"}
             p {
                 a href=(Contacts) {"Back"}
             }
         },
+        csrf,
         flashes,
     )
 }
 
-hx_trigger_variants!(DeleteTrigger {
-    Button: "delete-btn"
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/import/csv")]
+pub struct ImportCsv;
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/import/csv/preview")]
+pub struct ImportCsvPreview;
+
+#[derive(Deserialize, TypedPath)]
+#[typed_path("/contacts/import/csv/commit")]
+pub struct ImportCsvCommit;
+
+// The uploaded file waits in `CsvUploads`, the form only says which one it is.
+form_struct! {
+#[derive(Deserialize, Default)]
+pub struct CsvMapping {
+    upload("upload"): Option<crate::csv_import::UploadId>,
+    address_book("address_book"): Option<crate::model::AddressBookId>,
+    first_name("first_name_column"): Option<String>,
+    last_name("last_name_column"): Option<String>,
+    phone("phone_column"): Option<String>,
+    email_address("email_address_column"): Option<String>,
+    street("street_column"): Option<String>,
+    city("city_column"): Option<String>,
+    region("region_column"): Option<String>,
+    postal_code("postal_code_column"): Option<String>,
+    country("country_column"): Option<String>,
+}
+}
+
+impl CsvMapping::Form {
+    fn mapping(&self) -> ColumnMapping {
+        let column = |column: &Option<String>| column.as_deref().and_then(|c| c.parse().ok());
+        ColumnMapping {
+            first_name: column(&self.first_name),
+            last_name: column(&self.last_name),
+            phone: column(&self.phone),
+            email_address: column(&self.email_address),
+            street: column(&self.street),
+            city: column(&self.city),
+            region: column(&self.region),
+            postal_code: column(&self.postal_code),
+            country: column(&self.country),
+        }
+    }
+}
+
+hx_trigger_variants!(CsvMappingInteraction {
+    AddressBook: "address_book",
+    FirstName: "first_name_column",
+    LastName: "last_name_column",
+    Phone: "phone_column",
+    EmailAddress: "email_address_column",
+    Street: "street_column",
+    City: "city_column",
+    Region: "region_column",
+    PostalCode: "postal_code_column",
+    Country: "country_column"
 });
 
-pub async fn contacts_delete(
-    ViewContact { id: contact_id }: ViewContact,
+pub async fn contacts_import_csv_get(
+    _: ImportCsv,
+    _: CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    import_csv_form(None, &csrf, flashes)
+}
+
+pub async fn contacts_import_csv_post(
+    _: ImportCsv,
     State(state): State<AppState>,
-    flash: Flash,
-    deleted_trigger: Option<TypedHeader<DeleteTrigger>>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+    mut multipart: Multipart,
 ) -> Result<Response<Body>, AppError> {
-    let mut connection = state.db_pool.get().await?;
-    {
-        use crate::schema::contacts::dsl::contacts;
-
-        diesel::delete(contacts.find(contact_id))
-            .execute(&mut connection)
-            .await?;
+    let Some(upload) = read_upload(&mut multipart).await else {
+        return Ok(import_csv_form(
+            Some("Could not read the uploaded file".to_string()),
+            &csrf,
+            flashes,
+        )
+        .into_response());
+    };
+    let table = match csv_import::read_csv(&upload) {
+        Ok(table) => table,
+        Err(err) => {
+            return Ok(
+                import_csv_form(Some(format!("Could not read CSV: {err}")), &csrf, flashes)
+                    .into_response(),
+            )
+        }
     };
+    let mapping = ColumnMapping::guess(&table.headers);
+    let upload_id = state.csv_uploads.store(user.id, table);
+    let table = state
+        .csv_uploads
+        .get(user.id, upload_id)
+        .ok_or(AppError::NotFound)?;
+    let target = csv_target(&state, user.id, None).await?;
+    Ok(csv_mapping_form(upload_id, &table, mapping, &target, &csrf, flashes).into_response())
+}
 
-    if matches!(deleted_trigger.as_deref(), Some(DeleteTrigger::Button)) {
-        Ok((
-            flash.success("Deleted contact, yo!"),
-            Redirect::to(&Contacts.to_string()),
-        )
-            .into_response())
-    } else {
-        Ok("".into_response())
-    }
+/// The book CSV rows are imported into, the other books they could go to,
+/// and the emails already in the book.
+pub struct CsvTarget {
+    book: AddressBookId,
+    books: Vec<AddressBook>,
+    taken: HashSet<String>,
 }
 
-// Use the full path for `ContactId` because we need to put it in the `mod`'s scope.
-form_struct! {
-#[derive(Deserialize)]
-pub struct DeleteContactList {
-    #[serde(default)]
-    selected_contact_ids("selected_contact_ids"): Vec<crate::model::ContactId>,
+async fn csv_target(
+    state: &AppState,
+    user: UserId,
+    book: Option<AddressBookId>,
+) -> Result<CsvTarget, AppError> {
+    let books = books_to_add_to(&state.db_pool, user).await?;
+    let mut connection = state.db_pool.get().await?;
+    let book = books::book_to_add_to(&mut connection, user, book).await?;
+    let taken = duplicates::emails_in_book(&mut connection, book).await?;
+    Ok(CsvTarget { book, books, taken })
 }
+
+/// The file the form is about, or the page to upload it again if it's been too long.
+fn csv_upload(
+    state: &AppState,
+    user: UserId,
+    form: &CsvMapping::Form,
+    csrf: &CsrfToken,
+    flashes: IncomingFlashes,
+) -> Result<Arc<CsvTable>, Response<Body>> {
+    form.upload
+        .and_then(|upload_id| state.csv_uploads.get(user, upload_id))
+        .ok_or_else(|| {
+            import_csv_form(
+                Some("The uploaded file has expired, please upload it again".to_string()),
+                csrf,
+                flashes,
+            )
+            .into_response()
+        })
 }
 
-// This is already at the `Contacts` page,
-// so we don't have to redirect,
-// but unsure if this is what we want.
-// We might want to copy things over, but
-// what if we were searching or navigating through the pages?
-// Would we copy all of that logic over here?
-// The example in the book renders all contacts.
-pub async fn contacts_delete_all(
-    _: Contacts,
+/// Re-renders the preview whenever one of the column choices, or the book, changes.
+pub async fn contacts_import_csv_preview(
+    _: ImportCsvPreview,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    mapping_action: Option<TypedHeader<CsvMappingInteraction>>,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
+    Form(form): Form<CsvMapping::Form>,
+) -> Result<Response<Body>, AppError> {
+    let table = match csv_upload(&state, user.id, &form, &csrf, flashes.clone()) {
+        Ok(table) => table,
+        Err(response) => return Ok(response),
+    };
+    let target = csv_target(&state, user.id, form.address_book).await?;
+    if mapping_action.is_some() {
+        return Ok(csv_preview(&table, form.mapping(), &target.taken).into_response());
+    }
+    let upload_id = form.upload.ok_or(AppError::NotFound)?;
+    Ok(
+        csv_mapping_form(upload_id, &table, form.mapping(), &target, &csrf, flashes)
+            .into_response(),
+    )
+}
+
+/// Inserts every valid row in one transaction and skips the invalid ones,
+/// which the user has already seen in the preview,
+/// along with any whose email was taken since.
+pub async fn contacts_import_csv_commit(
+    _: ImportCsvCommit,
     State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    csrf: CsrfToken,
+    flashes: IncomingFlashes,
     flash: Flash,
-    Form(to_delete): Form<DeleteContactList::Form>,
+    Form(form): Form<CsvMapping::Form>,
 ) -> Result<Response<Body>, AppError> {
+    let table = match csv_upload(&state, user.id, &form, &csrf, flashes.clone()) {
+        Ok(table) => table,
+        Err(response) => return Ok(response),
+    };
+    let upload_id = form.upload.ok_or(AppError::NotFound)?;
+    let target = csv_target(&state, user.id, form.address_book).await?;
+    let rows = csv_import::preview(&table, form.mapping(), &target.taken);
+    let total = rows.len();
+    let valid: Vec<ValidContact> = rows.into_iter().filter_map(|(_, row)| row.ok()).collect();
+    if valid.is_empty() {
+        return Ok(
+            csv_mapping_form(upload_id, &table, form.mapping(), &target, &csrf, flashes)
+                .into_response(),
+        );
+    }
+    let imported = valid.len();
+    let book = target.book;
+
     let mut connection = state.db_pool.get().await?;
-    {
-        use crate::schema::contacts::dsl::contacts;
-        use crate::schema::contacts::dsl::id;
+    connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::contacts;
 
-        diesel::delete(contacts.filter(id.eq_any(to_delete.selected_contact_ids)))
-            .execute(&mut connection)
-            .await?;
-    }
+                // Stay well clear of Postgres' limit on bind parameters per statement.
+                for chunk in valid.chunks(1000) {
+                    let attributes: Vec<_> = chunk
+                        .iter()
+                        .map(|contact| (&contact.attributes, contacts::address_book_id.eq(book)))
+                        .collect();
+                    let ids: Vec<ContactId> = diesel::insert_into(contacts::table)
+                        .values(attributes)
+                        .returning(contacts::id)
+                        .get_results(connection)
+                        .await?;
+                    // Rows come back in the order they went in.
+                    let details: Vec<(ContactId, &ContactDetails)> = ids
+                        .iter()
+                        .copied()
+                        .zip(chunk.iter().map(|contact| &contact.details))
+                        .collect();
+                    contact_details::insert_details(connection, &details).await?;
+                    let changes: Vec<Change> = ids
+                        .iter()
+                        .zip(chunk)
+                        .map(|(contact_id, contact)| Change {
+                            contact_id: *contact_id,
+                            before: None,
+                            after: Some(&contact.attributes),
+                        })
+                        .collect();
+                    history::record(
+                        connection,
+                        user.id,
+                        Source::Import,
+                        EventKind::Created,
+                        &changes,
+                    )
+                    .await?;
+                }
+                Ok::<_, AppError>(())
+            }
+            .scope_boxed()
+        })
+        .await?;
+    state.csv_uploads.remove(user.id, upload_id);
 
     Ok((
-        flash.success("Deleted contacts!"),
+        flash.success(format!(
+            "Imported {} contacts, skipped {} rows with errors!",
+            imported,
+            total - imported
+        )),
         Redirect::to(&Contacts.to_string()),
     )
         .into_response())
 }
 
-#[derive(Deserialize, TypedPath)]
-#[typed_path("/contacts/:id/email")]
-pub struct ContactEmail {
-    pub id: ContactId,
+pub fn import_csv_form(
+    error: Option<String>,
+    csrf: &CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    page(
+        html! {
+            form action=(ImportCsv) method="post" enctype="multipart/form-data" {
+                (csrf.field())
+                fieldset {
+                    legend { "Import CSV" }
+                    p {
+                        label for="file" {"CSV File"}
+                        input name=(FileUpload::file()) id="file" type="file" accept=".csv,text/csv";
+                        span .error {(error.unwrap_or_default())}
+                    }
+                    button {"Preview"}
+                }
+            }
+            p {
+                a href=(Contacts) {"Back"}
+            }
+        },
+        csrf,
+        flashes,
+    )
 }
 
-#[derive(Debug, Deserialize)]
-pub struct EmailValidationParams {
-    pub email_address: Option<String>,
+pub fn csv_mapping_form(
+    upload: UploadId,
+    table: &CsvTable,
+    mapping: ColumnMapping,
+    target: &CsvTarget,
+    csrf: &CsrfToken,
+    flashes: IncomingFlashes,
+) -> impl IntoResponse {
+    let preview_on_change = |trigger: CsvMappingInteraction, options: Markup| {
+        html! {
+            select name=(trigger.id()) id=(trigger.id())
+                hx-post=(ImportCsvPreview)
+                hx-include="closest form"
+                hx-target="#csv-preview"
+                hx-swap="outerHTML" {
+                (options)
+            }
+        }
+    };
+    let column_select = |label: &str, trigger: CsvMappingInteraction, selected: Option<usize>| {
+        html! {
+            p {
+                label for=(trigger.id()) {(label)}
+                (preview_on_change(trigger, html! {
+                    option value="" { "(not imported)" }
+                    @for (index, header) in table.headers.iter().enumerate() {
+                        option value=(index) selected[selected == Some(index)] { (header) }
+                    }
+                }))
+            }
+        }
+    };
+    page(
+        html! {
+            form action=(ImportCsvCommit) method="post" {
+                (csrf.field())
+                input type="hidden" name=(CsvMapping::upload()) value=(upload);
+                @if target.books.len() > 1 {
+                    p {
+                        label for=(CsvMappingInteraction::AddressBook.id()) {"Address Book"}
+                        (preview_on_change(CsvMappingInteraction::AddressBook, html! {
+                            @for book in &target.books {
+                                option value=(book.id) selected[target.book == book.id] {(book.name)}
+                            }
+                        }))
+                    }
+                }
+                fieldset {
+                    legend { "Columns" }
+                    (column_select("First Name", CsvMappingInteraction::FirstName, mapping.first_name))
+                    (column_select("Last Name", CsvMappingInteraction::LastName, mapping.last_name))
+                    (column_select("Phone", CsvMappingInteraction::Phone, mapping.phone))
+                    (column_select("Email", CsvMappingInteraction::EmailAddress, mapping.email_address))
+                    (column_select("Street", CsvMappingInteraction::Street, mapping.street))
+                    (column_select("City", CsvMappingInteraction::City, mapping.city))
+                    (column_select("Region", CsvMappingInteraction::Region, mapping.region))
+                    (column_select("Postal Code", CsvMappingInteraction::PostalCode, mapping.postal_code))
+                    (column_select("Country", CsvMappingInteraction::Country, mapping.country))
+                }
+                (csv_preview(table, mapping, &target.taken))
+                button {"Import"}
+            }
+            p {
+                a href=(ImportCsv) {"Upload a different file"}
+                " "
+                a href=(Contacts) {"Back"}
+            }
+        },
+        csrf,
+        flashes,
+    )
 }
 
-pub async fn contacts_email_get(
-    _: ContactEmail,
-    Query(query): Query<EmailValidationParams>,
-    State(state): State<AppState>,
-) -> Result<Response<Body>, AppError> {
-    let email = query.email_address.unwrap_or_default();
-    if email.is_empty() {
-        return Ok("Email cannot be empty".into_response());
+fn csv_preview(table: &CsvTable, mapping: ColumnMapping, taken: &HashSet<String>) -> Markup {
+    let rows = csv_import::preview(table, mapping, taken);
+    let invalid = rows.iter().filter(|(_, row)| row.is_err()).count();
+    html! {
+        div #csv-preview {
+            p { (rows.len() - invalid) " rows ready to import, " (invalid) " with errors." }
+            table {
+                thead {
+                    tr {
+                        th {"First"} th {"Last"} th {"Phone"} th {"Email"} th {"Address"}
+                    }
+                }
+                tbody {
+                    @for (pending, row) in rows {
+                        @let errors = row.err().unwrap_or_default();
+                        tr {
+                            td { (pending.first_name.as_deref().unwrap_or_default()) (field_errors(&errors.first_name)) }
+                            td { (pending.last_name.as_deref().unwrap_or_default()) (field_errors(&errors.last_name)) }
+                            td { (pending.phones.join(", ")) (field_errors(&errors.phones)) }
+                            td { (pending.email_addresses.join(", ")) (field_errors(&errors.email_addresses)) }
+                            td {
+                                @for address in pending.addresses() {
+                                    @for line in address.lines() {
+                                        (line) br;
+                                    }
+                                }
+                                (field_errors(&errors.group(ADDRESS).cloned().collect::<Vec<_>>()))
+                            }
+                        }
+                    }
+                }
+            }
+        }
     }
+}
 
-    let mut connection = state.db_pool.get().await?;
-    let contact_count: i64 = {
-        use crate::schema::contacts::dsl::contacts;
-        use crate::schema::contacts::dsl::email_address;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        contacts
-            .filter(email_address.like(email))
-            .count()
-            .get_result(&mut connection)
-            .await?
-    };
-    if contact_count == 0 {
-        Ok("".into_response())
-    } else {
-        Ok("Email must be unique".into_response())
+    #[test]
+    fn every_live_contact_input_can_be_validated() {
+        for input in PendingContact::inputs::ALL
+            .iter()
+            .filter(|input| input.live)
+        {
+            assert!(
+                ContactField::from_id(input.name).is_some(),
+                "{} is live but has no `ContactField`",
+                input.name
+            );
+        }
     }
 }
@@ -1,6 +1,41 @@
 use axum::http::HeaderName;
+use axum::http::HeaderValue;
+use axum_extra::headers::Header;
 
 pub(crate) static HX_TRIGGER: HeaderName = HeaderName::from_static("hx-trigger");
+static HX_TRIGGER_NAME: HeaderName = HeaderName::from_static("hx-trigger-name");
+
+/// What `hx_trigger_variants!` generates, for headers that are generic over it.
+pub trait HxTrigger: Sized {
+    fn id(&self) -> &'static str;
+    fn from_id(id: &str) -> Option<Self>;
+}
+
+/// Like the variants themselves, but read from the `name` of the element that triggered the request
+/// rather than its `id`, which repeated inputs can't share.
+pub struct HxTriggerName<T>(pub T);
+
+impl<T: HxTrigger> Header for HxTriggerName<T> {
+    fn name() -> &'static HeaderName {
+        &HX_TRIGGER_NAME
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, axum_extra::headers::Error>
+    where
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        values
+            .next()
+            .and_then(|value| value.to_str().ok())
+            .and_then(T::from_id)
+            .map(HxTriggerName)
+            .ok_or_else(axum_extra::headers::Error::invalid)
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(std::iter::once(HeaderValue::from_static(self.0.id())));
+    }
+}
 
 // Could put enum declaration outside of macro if more methods are needed.
 // That would mean that we duplicate the variants.
@@ -18,6 +53,38 @@ macro_rules! hx_trigger_variants {
                     $(Self::$variant => $id),+
                 }
             }
+
+            #[allow(dead_code)]
+            pub fn from_id(id: &str) -> Option<Self> {
+                $(if id == $id {
+                    return Some(Self::$variant);
+                })+
+                None
+            }
+        }
+
+        impl $crate::hx_triggers::HxTrigger for $enum_name {
+            fn id(&self) -> &'static str {
+                Self::id(self)
+            }
+
+            fn from_id(id: &str) -> Option<Self> {
+                Self::from_id(id)
+            }
+        }
+
+        impl std::fmt::Display for $enum_name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.id())
+            }
+        }
+
+        // So that the ids can be used in paths too.
+        impl<'de> serde::Deserialize<'de> for $enum_name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let id = <String as serde::Deserialize>::deserialize(deserializer)?;
+                Self::from_id(&id).ok_or_else(|| serde::de::Error::custom(format!("unknown id {id}")))
+            }
         }
 
         impl axum_extra::headers::Header for $enum_name {
@@ -30,14 +97,11 @@ macro_rules! hx_trigger_variants {
                 Self: Sized,
                 I: Iterator<Item = &'i axum::http::HeaderValue>,
             {
-                let value = values
+                values
                     .next()
-                    .ok_or_else(axum_extra::headers::Error::invalid)?;
-
-                $(if value == $id {
-                    return Ok(Self::$variant);
-                })+
-                return Err(axum_extra::headers::Error::invalid())
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(Self::from_id)
+                    .ok_or_else(axum_extra::headers::Error::invalid)
             }
 
             fn encode<E: Extend<axum::http::HeaderValue>>(&self, values: &mut E) {
@@ -48,3 +112,24 @@ macro_rules! hx_trigger_variants {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    hx_trigger_variants!(Field {
+        Email: "email_address",
+        Phone: "phone"
+    });
+
+    #[test]
+    fn trigger_names_decode_to_their_variant() {
+        let decode = |value: &'static str| {
+            HxTriggerName::<Field>::decode(&mut std::iter::once(&HeaderValue::from_static(value)))
+                .map(|HxTriggerName(field)| field.id())
+        };
+        assert_eq!(decode("phone").ok(), Some("phone"));
+        assert_eq!(decode("email_address").ok(), Some("email_address"));
+        assert!(decode("street").is_err());
+    }
+}
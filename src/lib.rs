@@ -3,16 +3,41 @@ use diesel_async::pooled_connection::deadpool::{self, Pool};
 use diesel_async::AsyncPgConnection;
 
 pub mod api;
+pub mod archiver;
+pub mod auth;
+pub(crate) mod books;
+pub(crate) mod contact_details;
+pub mod csrf;
+pub mod csv_import;
+pub(crate) mod duplicates;
+pub mod errors;
 pub(crate) mod form_struct;
+pub(crate) mod history;
 pub mod html_views;
 pub(crate) mod hx_triggers;
 pub(crate) mod model;
+pub(crate) mod pagination;
 pub(crate) mod schema;
+pub(crate) mod search;
+pub(crate) mod tags;
+pub(crate) mod tokens;
+pub mod trash;
+pub(crate) mod vcard;
+
+pub use contact_details::normalize_phone_numbers;
+pub use model::set_phone_region;
+pub use model::DEFAULT_PHONE_REGION;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db_pool: Pool<AsyncPgConnection>,
     pub flash_config: axum_flash::Config,
+    /// Signs the session and flash cookies, so it has to stay the same across restarts.
+    pub cookie_key: axum_flash::Key,
+    pub archive_jobs: archiver::ArchiveJobs,
+    pub csv_uploads: csv_import::CsvUploads,
+    /// How long deleted contacts stay in the trash before they're purged.
+    pub trash_retention: chrono::Duration,
 }
 
 impl axum::extract::FromRef<AppState> for axum_flash::Config {
@@ -21,22 +46,71 @@ impl axum::extract::FromRef<AppState> for axum_flash::Config {
     }
 }
 
+impl axum::extract::FromRef<AppState> for axum_flash::Key {
+    fn from_ref(state: &AppState) -> axum_flash::Key {
+        state.cookie_key.clone()
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
     #[error("Pool error: {0}")]
     Pool(#[from] diesel_async::pooled_connection::PoolError),
     #[error("PostgreSQL error: {0}")]
-    Diesel(#[from] diesel::result::Error),
+    Diesel(diesel::result::Error),
     #[error("Deadpool error: {0}")]
     Deadpool(#[from] deadpool::PoolError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Password hashing error: {0}")]
+    PasswordHash(#[from] argon2::password_hash::Error),
+    /// There's no such thing, or none that the user can see.
+    #[error("Not found")]
+    NotFound,
+    /// What the user sent doesn't pass validation, with everything that's wrong with it.
+    #[error("Validation failed")]
+    Validation(Vec<errors::FieldError>),
+    /// The request clashes with what's already there.
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    /// The request needs credentials it didn't come with.
+    #[error("Unauthorized: {0}")]
+    Unauthorized(&'static str),
+    /// The user's role in the address book doesn't allow this.
+    #[error("Forbidden")]
+    Forbidden,
+}
+
+/// Queries for a single row that find nothing are a `NotFound`, and rows that break a unique
+/// constraint a `Conflict`, so handlers can let them bubble up.
+impl From<diesel::result::Error> for AppError {
+    fn from(err: diesel::result::Error) -> Self {
+        use diesel::result::DatabaseErrorKind;
+        use diesel::result::Error;
+
+        match err {
+            Error::NotFound => AppError::NotFound,
+            // Only when two saves race past `duplicates::validate`, which checks it first.
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)
+                if info.constraint_name() == Some("contact_emails_address_lower_idx") =>
+            {
+                AppError::Validation(vec![errors::FieldError::new(
+                    model::PendingContact::email_addresses(),
+                    duplicates::EMAIL_TAKEN,
+                )])
+            }
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                AppError::Conflict("That already exists.".to_string())
+            }
+            err => AppError::Diesel(err),
+        }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            "An internal error occurred. Please try again later.",
-        )
-            .into_response()
+        errors::Problem::from(self).into_response()
     }
 }
@@ -1,5 +1,6 @@
 use std::env;
 
+use axum::middleware;
 use axum::Router;
 use axum_extra::routing::RouterExt;
 use diesel_async::pooled_connection::deadpool::Pool;
@@ -7,14 +8,25 @@ use diesel_async::pooled_connection::AsyncDieselConnectionManager;
 use diesel_async::AsyncPgConnection;
 use dotenvy::dotenv;
 use hypermedia_systems_rust::api;
+use hypermedia_systems_rust::archiver::ArchiveJobs;
+use hypermedia_systems_rust::archiver::ARCHIVE_TTL;
+use hypermedia_systems_rust::auth;
+use hypermedia_systems_rust::csrf;
+use hypermedia_systems_rust::csv_import::CsvUploads;
+use hypermedia_systems_rust::csv_import::UPLOAD_TTL;
+use hypermedia_systems_rust::errors;
 use hypermedia_systems_rust::html_views;
+use hypermedia_systems_rust::normalize_phone_numbers;
+use hypermedia_systems_rust::set_phone_region;
+use hypermedia_systems_rust::trash;
 use hypermedia_systems_rust::AppState;
+use hypermedia_systems_rust::DEFAULT_PHONE_REGION;
 use tower_http::services::ServeDir;
 
 // TODO:
 // - [ ] test with forms (in the style of zero to prod in rust)
 // - [ ] test with playwright
-// - [ ] include tracing with per-request correlation id
+// - [ ] include per-request correlation id in the tracing output
 // - [x] try using `serde(try_from = "...")` with contacts and user facing contacts.
 //   want to report multiple errors and for errors to be user-facing
 //   Maybe want to use macro for this?
@@ -42,33 +54,145 @@ fn establish_connection() -> Pool<AsyncPgConnection> {
         .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
 }
 
+fn trash_retention() -> chrono::Duration {
+    let days = env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .map(|days| {
+            days.parse()
+                .expect("TRASH_RETENTION_DAYS must be a number of days")
+        })
+        .unwrap_or(trash::DEFAULT_RETENTION_DAYS);
+    chrono::Duration::days(days)
+}
+
+fn phone_region() -> phonenumber::country::Id {
+    env::var("PHONE_REGION")
+        .ok()
+        .map(|region| {
+            region
+                .parse()
+                .expect("PHONE_REGION must be a two-letter country code, like US")
+        })
+        .unwrap_or(DEFAULT_PHONE_REGION)
+}
+
+/// Brings numbers saved before phones were kept in E.164 in line, now that the region is known.
+async fn normalize_phones(pool: &Pool<AsyncPgConnection>) {
+    let mut connection = pool.get().await.expect("Could not connect to the database");
+    let unparsed = normalize_phone_numbers(&mut connection)
+        .await
+        .expect("Could not normalize phone numbers");
+    for number in unparsed {
+        tracing::warn!(
+            number,
+            "could not parse a saved phone number, leaving it as it is"
+        );
+    }
+}
+
+fn cookie_key() -> axum_flash::Key {
+    let key = env::var("SESSION_KEY").expect("SESSION_KEY must be set, see .env.example");
+    auth::cookie_key(&key).expect("SESSION_KEY must be at least 64 bytes of base64")
+}
+
 #[tokio::main]
 async fn main() {
+    tracing_subscriber::fmt::init();
     let pool = establish_connection();
+    set_phone_region(phone_region());
+    normalize_phones(&pool).await;
+    let trash_retention = trash_retention();
+    tokio::spawn(trash::purge_periodically(pool.clone(), trash_retention));
+    let archive_jobs = ArchiveJobs::default();
+    tokio::spawn(archive_jobs.clone().expire_periodically(ARCHIVE_TTL));
+    let csv_uploads = CsvUploads::default();
+    tokio::spawn(csv_uploads.clone().expire_periodically(UPLOAD_TTL));
+    let cookie_key = cookie_key();
     let starting_state = AppState {
         db_pool: pool,
-        flash_config: axum_flash::Config::new(axum_flash::Key::generate()),
+        flash_config: axum_flash::Config::new(cookie_key.clone()),
+        cookie_key,
+        archive_jobs,
+        csv_uploads,
+        trash_retention,
     };
     let api_routes = Router::new()
         .typed_get(api::get_contacts)
         .typed_get(api::get_contact)
         .typed_put(api::update_contact)
         .typed_delete(api::delete_contact)
-        .typed_post(api::new_contact);
+        .typed_post(api::new_contact)
+        .route_layer(middleware::from_fn(errors::problem_json));
+    let api_v2_routes = Router::new()
+        .typed_get(api::v2::get_contacts)
+        .typed_get(api::v2::get_contact)
+        .typed_put(api::v2::update_contact)
+        .typed_delete(api::delete_contact)
+        .typed_post(api::v2::new_contact)
+        .route_layer(middleware::from_fn(errors::problem_json));
 
     let app = Router::new()
         .typed_get(html_views::root)
+        .typed_get(html_views::login_get)
+        .typed_post(html_views::login_post)
+        .typed_post(html_views::logout_post)
+        .typed_get(html_views::signup_get)
+        .typed_post(html_views::signup_post)
+        .typed_get(html_views::api_tokens_get)
+        .typed_post(html_views::api_tokens_post)
+        .typed_delete(html_views::api_token_delete)
+        .typed_get(html_views::address_books_get)
+        .typed_post(html_views::address_books_post)
+        .typed_get(html_views::address_book_members_get)
+        .typed_post(html_views::address_book_members_post)
+        .typed_delete(html_views::address_book_member_delete)
         .typed_get(html_views::contacts)
         .typed_get(html_views::contacts_new_get)
         .typed_get(html_views::contacts_view)
         .typed_get(html_views::contacts_count)
         .typed_get(html_views::contacts_edit_get)
-        .typed_get(html_views::contacts_email_get)
+        .typed_get(html_views::contacts_detail_row_get)
+        .typed_get(html_views::contacts_address_row_get)
+        .typed_get(html_views::contacts_validate_get)
+        .typed_get(html_views::contacts_duplicates_get)
+        .typed_get(html_views::contacts_merge_get)
+        .typed_post(html_views::contacts_merge_post)
+        .typed_get(html_views::contacts_archive_get)
+        .typed_get(html_views::contacts_archive_file)
+        .typed_post(html_views::contacts_archive_post)
+        .typed_delete(html_views::contacts_archive_delete)
+        .typed_get(html_views::contacts_vcard_get)
+        .typed_get(html_views::contacts_vcards_get)
+        .typed_get(html_views::contacts_import_vcard_get)
+        .typed_post(html_views::contacts_import_vcard_post)
+        .typed_get(html_views::contacts_import_csv_get)
+        .typed_post(html_views::contacts_import_csv_post)
+        .typed_post(html_views::contacts_import_csv_preview)
+        .typed_post(html_views::contacts_import_csv_commit)
         .typed_post(html_views::contacts_new_post)
         .typed_post(html_views::contacts_edit_post)
+        .typed_post(html_views::contacts_revert_post)
+        .typed_post(html_views::contacts_tags_post)
+        .typed_delete(html_views::contacts_tag_delete)
         .typed_delete(html_views::contacts_delete)
         .typed_delete(html_views::contacts_delete_all)
-        .nest("/api/v1", api_routes)
+        .typed_get(html_views::contacts_trash_get)
+        .typed_delete(html_views::contacts_trash_delete)
+        .typed_delete(html_views::contacts_trashed_delete)
+        .typed_post(html_views::contacts_restore_post)
+        .typed_post(html_views::contacts_undo_trash_post)
+        // Inside `csrf::verify`, which hands it the token for error pages.
+        .route_layer(middleware::from_fn_with_state(
+            starting_state.clone(),
+            errors::negotiate,
+        ))
+        // Only the HTML routes, since the API authenticates with tokens rather than cookies.
+        .route_layer(middleware::from_fn_with_state(
+            starting_state.clone(),
+            csrf::verify,
+        ))
+        .nest(api::API_PREFIX, api_routes)
+        .nest(api::v2::API_PREFIX, api_v2_routes)
         .with_state(starting_state)
         .nest_service("/dist", ServeDir::new("dist"));
 
@@ -1,17 +1,37 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fmt::Display;
 use std::ops::Deref;
+use std::sync::OnceLock;
 
+use chrono::DateTime;
+use chrono::Utc;
 use diesel::query_builder::AsChangeset;
 use diesel::Insertable;
 use diesel::Queryable;
 use diesel::Selectable;
 use diesel_derive_newtype::DieselNewType;
+use phonenumber::country;
+use phonenumber::Mode;
 use serde::Deserialize;
 use serde::Serialize;
 
 use crate::form_struct;
 
-#[derive(DieselNewType, Clone, Copy, Debug, Deserialize, Default, Serialize, PartialEq, Eq)]
+#[derive(
+    DieselNewType,
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Default,
+    Serialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
 #[serde(transparent)]
 pub struct ContactId(i32);
 
@@ -21,26 +41,573 @@ impl Display for ContactId {
     }
 }
 
-#[derive(AsChangeset, Queryable, Selectable, Insertable, Clone, Debug, Deserialize, Serialize)]
+#[derive(DieselNewType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct TagId(i32);
+
+impl Display for TagId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(DieselNewType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct ContactEventId(i32);
+
+impl Display for ContactEventId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(DieselNewType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct UserId(i32);
+
+impl Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(DieselNewType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct AddressBookId(i32);
+
+impl Display for AddressBookId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(DieselNewType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct ApiTokenId(i32);
+
+impl Display for ApiTokenId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::users)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct User {
+    pub id: UserId,
+    pub username: String,
+}
+
+/// What a member of an address book may do with its contacts, each role allowing everything
+/// the ones before it do.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Editor,
+    /// Also decides who else is a member.
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Viewer, Role::Editor, Role::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Role::Viewer => "Viewer",
+            Role::Editor => "Editor",
+            Role::Admin => "Admin",
+        }
+    }
+
+    pub fn can(self, permission: Permission) -> bool {
+        let needed = match permission {
+            Permission::View => Role::Viewer,
+            Permission::Edit => Role::Editor,
+            Permission::Delete | Permission::Manage => Role::Admin,
+        };
+        self >= needed
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = String;
+
+    fn try_from(role: String) -> Result<Self, Self::Error> {
+        Role::ALL
+            .into_iter()
+            .find(|known| known.as_str() == role)
+            .ok_or_else(|| format!("Unknown role {role:?}"))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    View,
+    /// Adding contacts and changing them.
+    Edit,
+    /// Moving contacts to the trash, and everything to do with the trash.
+    Delete,
+    /// Inviting members and changing their roles.
+    Manage,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::address_books)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AddressBook {
+    pub id: AddressBookId,
+    pub name: String,
+}
+
+/// An address book along with the role of the user it was loaded for.
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::memberships)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Membership {
+    #[diesel(embed)]
+    pub book: AddressBook,
+    #[diesel(deserialize_as = String)]
+    pub role: Role,
+}
+
+/// A member of an address book.
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::memberships)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Member {
+    #[diesel(embed)]
+    pub user: User,
+    #[diesel(deserialize_as = String)]
+    pub role: Role,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::tags)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Tag {
+    pub id: TagId,
+    pub name: String,
+}
+
+#[derive(
+    AsChangeset,
+    Queryable,
+    Selectable,
+    Insertable,
+    Clone,
+    Debug,
+    Deserialize,
+    Serialize,
+    PartialEq,
+    Eq,
+)]
 #[diesel(table_name = crate::schema::contacts)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct ContactAttributes {
     pub first_name: String,
     pub last_name: String,
-    pub phone: String,
-    pub email_address: String,
+    pub phone: PhoneNumber,
+    pub email_address: EmailAddress,
+}
+
+/// Where phone numbers without a country code are from, unless `set_phone_region` says otherwise.
+pub const DEFAULT_PHONE_REGION: country::Id = country::Id::US;
+
+static PHONE_REGION: OnceLock<country::Id> = OnceLock::new();
+
+/// Sets where phone numbers without a country code are from, once at startup.
+pub fn set_phone_region(region: country::Id) {
+    let _ = PHONE_REGION.set(region);
+}
+
+fn phone_region() -> country::Id {
+    PHONE_REGION.get().copied().unwrap_or(DEFAULT_PHONE_REGION)
+}
+
+/// A phone number in E.164, like "+14155550123", which is how contacts store them, or empty for
+/// none. Only `parse` and deserializing make new ones, which both check that.
+/// Numbers that `contact_details::normalize_phone_numbers` couldn't make sense of can be anything,
+/// and are shown as they are.
+#[derive(DieselNewType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct PhoneNumber(String);
+
+impl PhoneNumber {
+    /// Takes national numbers to be from the phone region. `None` if it isn't a valid number.
+    pub fn parse(input: &str) -> Option<Self> {
+        let number = phonenumber::parse(Some(phone_region()), input)
+            .ok()
+            .filter(phonenumber::is_valid)?;
+        Some(PhoneNumber(number.format().mode(Mode::E164).to_string()))
+    }
+
+    /// The digits after the country code, like "4155550123", which every format shows.
+    pub fn national_digits(&self) -> Option<String> {
+        let number = phonenumber::parse(Some(phone_region()), &self.0).ok()?;
+        Some(number.national().to_string())
+    }
+
+    /// The national format for numbers from the phone region, the international one for the rest.
+    pub fn formatted(&self) -> String {
+        let Ok(number) = phonenumber::parse(Some(phone_region()), &self.0) else {
+            return self.0.clone();
+        };
+        let mode = if number.country().id() == Some(phone_region()) {
+            Mode::National
+        } else {
+            Mode::International
+        };
+        number.format().mode(mode).to_string()
+    }
+}
+
+impl TryFrom<String> for PhoneNumber {
+    type Error = &'static str;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        if input.trim().is_empty() {
+            return Ok(PhoneNumber::default());
+        }
+        PhoneNumber::parse(&input).ok_or("not a valid phone number")
+    }
+}
+
+impl Deref for PhoneNumber {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// An email address that is an addr-spec with a dotted domain, like "ada@example.com",
+/// or empty for none. Only `parse` and deserializing make new ones, which both check that.
+/// Addresses saved before they were checked can be anything, like phone numbers.
+#[derive(DieselNewType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct EmailAddress(String);
+
+impl EmailAddress {
+    /// `None` if it isn't a valid address, leaving out the spaces around it.
+    /// Display names like "Ada <ada@example.com>" and domain literals aren't addresses here.
+    pub fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+        let options = email_address::Options::default()
+            .with_required_tld()
+            .without_display_text()
+            .without_domain_literal();
+        email_address::EmailAddress::parse_with_options(input, options).ok()?;
+        Some(EmailAddress(input.to_string()))
+    }
+}
+
+impl TryFrom<String> for EmailAddress {
+    type Error = &'static str;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        if input.trim().is_empty() {
+            return Ok(EmailAddress::default());
+        }
+        EmailAddress::parse(&input).ok_or("not a valid email address")
+    }
+}
+
+impl Display for EmailAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Deref for EmailAddress {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// One of a contact's phone numbers, labelled "work", "mobile" and so on.
+#[derive(Queryable, Selectable, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[diesel(table_name = crate::schema::contact_phones)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Phone {
+    #[serde(default)]
+    pub label: String,
+    pub number: PhoneNumber,
+    #[serde(default)]
+    #[diesel(column_name = is_primary)]
+    pub primary: bool,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[diesel(table_name = crate::schema::contact_emails)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Email {
+    #[serde(default)]
+    pub label: String,
+    pub address: EmailAddress,
+    #[serde(default)]
+    #[diesel(column_name = is_primary)]
+    pub primary: bool,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[diesel(table_name = crate::schema::contact_addresses)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[serde(default)]
+pub struct Address {
+    pub label: String,
+    pub street: String,
+    pub city: String,
+    pub region: String,
+    pub postal_code: String,
+    pub country: String,
 }
 
+impl Address {
+    /// The non-empty lines of the address as it would go on an envelope.
+    pub fn lines(&self) -> Vec<String> {
+        let locality = [&self.city, &self.region, &self.postal_code]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+        [self.street.clone(), locality, self.country.clone()]
+            .into_iter()
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+}
+
+/// Every phone number, email address and postal address of a contact,
+/// primary phone and email first.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ContactDetails {
+    pub phones: Vec<Phone>,
+    pub emails: Vec<Email>,
+    #[serde(default)]
+    pub addresses: Vec<Address>,
+}
+
+/// A phone number as it came in, before the contact form's rules have checked it.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PhoneInput {
+    #[serde(default)]
+    pub label: String,
+    pub number: String,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+/// An email address as it came in, before the contact form's rules have checked it.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EmailInput {
+    #[serde(default)]
+    pub label: String,
+    pub address: String,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+/// What `PendingContact::Form::new` fills the rows of the form with.
+#[derive(Clone, Debug, Default)]
+pub struct DetailsInput {
+    pub phones: Vec<PhoneInput>,
+    pub emails: Vec<EmailInput>,
+    pub addresses: Vec<Address>,
+}
+
+impl DetailsInput {
+    /// For callers that only know about a single phone and email, like the v1 API.
+    /// Empty values are left out.
+    pub fn primary_only(phone: &str, email_address: &str) -> Self {
+        Self {
+            phones: Some(phone)
+                .filter(|phone| !phone.is_empty())
+                .map(|phone| PhoneInput {
+                    number: phone.to_string(),
+                    primary: true,
+                    ..Default::default()
+                })
+                .into_iter()
+                .collect(),
+            emails: Some(email_address)
+                .filter(|email| !email.is_empty())
+                .map(|email| EmailInput {
+                    address: email.to_string(),
+                    primary: true,
+                    ..Default::default()
+                })
+                .into_iter()
+                .collect(),
+            addresses: vec![],
+        }
+    }
+
+    /// The details after `contact_details::save_primary`: the primary phone and email
+    /// replaced by these, or dropped for empty ones, and everything else left alone.
+    pub fn with_primary(mut self, phone: &str, email_address: &str) -> Self {
+        if phone.is_empty() {
+            self.phones.retain(|existing| !existing.primary);
+        } else if let Some(primary) = self.phones.iter_mut().find(|existing| existing.primary) {
+            primary.number = phone.to_string();
+        } else {
+            self.phones.push(PhoneInput {
+                number: phone.to_string(),
+                primary: true,
+                ..Default::default()
+            });
+        }
+        if email_address.is_empty() {
+            self.emails.retain(|existing| !existing.primary);
+        } else if let Some(primary) = self.emails.iter_mut().find(|existing| existing.primary) {
+            primary.address = email_address.to_string();
+        } else {
+            self.emails.push(EmailInput {
+                address: email_address.to_string(),
+                primary: true,
+                ..Default::default()
+            });
+        }
+        self
+    }
+}
+
+impl From<&ContactDetails> for DetailsInput {
+    fn from(details: &ContactDetails) -> Self {
+        Self {
+            phones: details
+                .phones
+                .iter()
+                .map(|phone| PhoneInput {
+                    label: phone.label.clone(),
+                    number: phone.number.formatted(),
+                    primary: phone.primary,
+                })
+                .collect(),
+            emails: details
+                .emails
+                .iter()
+                .map(|email| EmailInput {
+                    label: email.label.clone(),
+                    address: email.address.to_string(),
+                    primary: email.primary,
+                })
+                .collect(),
+            addresses: details.addresses.clone(),
+        }
+    }
+}
+
+// Phones, emails and addresses are repeated groups of inputs, one of each per row.
+// The key ties a row to its "primary" radio button, since rows can be added and removed
+// and their position isn't stable.
 form_struct! {
 #[derive(serde::Deserialize, Default, Debug, Clone)]
 pub struct PendingContact {
-     first_name("first_name"): Option<String>,
-     last_name("last_name"): Option<String>,
-     phone("phonee"): Option<String>,
-     email_address("email_address"): Option<String>,
+     first_name("first_name"): Option<String> { label: "First Name", placeholder: "First Name", live: true }
+         => [required("Missing first name"), max_length(100)],
+     last_name("last_name"): Option<String> { label: "Last Name", placeholder: "Last Name", live: true }
+         => [required("Missing last name"), max_length(100)],
+     #[serde(default)]
+     phone_keys("phone_key"): Vec<String>,
+     #[serde(default)]
+     phone_labels("phone_label"): Vec<String>,
+     #[serde(default)]
+     phones("phone"): Vec<String> { label: "Phone", input_type: "tel", placeholder: "Phone", live: true }
+         => [phone],
+     primary_phone("primary_phone"): Option<String>,
+     #[serde(default)]
+     email_keys("email_key"): Vec<String>,
+     #[serde(default)]
+     email_labels("email_label"): Vec<String>,
+     #[serde(default)]
+     email_addresses("email_address"): Vec<String> {
+         label: "Email",
+         input_type: "email",
+         placeholder: "Email",
+         live: true,
+     } => [
+         required("Missing email address"),
+         email,
+         custom(crate::model::distinct_emails),
+     ],
+     primary_email("primary_email"): Option<String>,
+     #[serde(default)]
+     address_labels("address_label"): Vec<String>,
+     #[serde(default)]
+     streets("street"): Vec<String> {
+         label: "Street",
+         placeholder: "Street",
+         live: true,
+         group: Some(crate::model::ADDRESS),
+     },
+     #[serde(default)]
+     cities("city"): Vec<String> {
+         label: "City",
+         placeholder: "City",
+         live: true,
+         group: Some(crate::model::ADDRESS),
+     },
+     #[serde(default)]
+     regions("region"): Vec<String> {
+         label: "Region",
+         placeholder: "Region",
+         live: true,
+         group: Some(crate::model::ADDRESS),
+     },
+     #[serde(default)]
+     postal_codes("postal_code"): Vec<String> {
+         label: "Postal Code",
+         placeholder: "Postal Code",
+         size: Some(10),
+         live: true,
+         group: Some(crate::model::ADDRESS),
+     } => [
+         regex(r"^[\p{L}\p{N} -]*$", "Postal codes can only have letters, digits, spaces and dashes"),
+     ],
+     #[serde(default)]
+     countries("country"): Vec<String> {
+         label: "Country",
+         placeholder: "Country",
+         live: true,
+         group: Some(crate::model::ADDRESS),
+     },
+     version("version"): Option<i32>,
+     address_book("address_book"): Option<crate::model::AddressBookId>,
 }}
 
-#[derive(Selectable, Queryable, AsChangeset, Clone, Debug, Deserialize, Serialize)]
+/// The group of the inputs that make up one of a contact's postal addresses.
+pub const ADDRESS: &str = "address";
+
+/// The same address can't be a contact's email twice, whatever its case.
+fn distinct_emails(addresses: &[String]) -> Option<Cow<'static, str>> {
+    let mut seen = HashSet::new();
+    (!addresses
+        .iter()
+        .filter(|address| !address.trim().is_empty())
+        .all(|address| seen.insert(address.trim().to_lowercase())))
+    .then_some("Each email address can only be used once".into())
+}
+
+/// A contact that passed validation, ready to be saved.
+pub struct ValidContact {
+    pub attributes: ContactAttributes,
+    pub details: ContactDetails,
+}
+
+#[derive(Selectable, Queryable, Clone, Debug, Serialize)]
 #[diesel(table_name = crate::schema::contacts)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Contact {
@@ -48,26 +615,173 @@ pub struct Contact {
     #[serde(flatten)]
     #[diesel(embed)]
     pub attributes: ContactAttributes,
+    pub created_at: DateTime<Utc>,
+    /// Bumped by a trigger whenever the row changes,
+    /// and by `contact_details` when only the phones, emails or addresses do.
+    pub updated_at: DateTime<Utc>,
+    /// Goes up with every change, for spotting edits made in the meantime.
+    pub version: i32,
+    pub address_book_id: Option<AddressBookId>,
 }
 
-#[derive(Insertable, Deserialize)]
-#[diesel(table_name = crate::schema::contacts)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct NewContact {
+/// A contact as the API hands it out, along with the names of its tags.
+#[derive(Clone, Debug, Serialize)]
+pub struct TaggedContact {
     #[serde(flatten)]
-    #[diesel(embed)]
-    pub attributes: ContactAttributes,
+    pub contact: Contact,
+    pub tags: Vec<String>,
 }
 
-impl Deref for Contact {
-    type Target = ContactAttributes;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    Reverted,
+    Merged,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.attributes
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Updated => "updated",
+            EventKind::Deleted => "deleted",
+            EventKind::Restored => "restored",
+            EventKind::Reverted => "reverted",
+            EventKind::Merged => "merged",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            EventKind::Created => "Created",
+            EventKind::Updated => "Updated",
+            EventKind::Deleted => "Deleted",
+            EventKind::Restored => "Restored",
+            EventKind::Reverted => "Reverted",
+            EventKind::Merged => "Merged",
+        }
+    }
+}
+
+impl TryFrom<String> for EventKind {
+    type Error = String;
+
+    fn try_from(kind: String) -> Result<Self, Self::Error> {
+        [
+            EventKind::Created,
+            EventKind::Updated,
+            EventKind::Deleted,
+            EventKind::Restored,
+            EventKind::Reverted,
+            EventKind::Merged,
+        ]
+        .into_iter()
+        .find(|known| known.as_str() == kind)
+        .ok_or_else(|| format!("Unknown contact event {kind:?}"))
     }
 }
 
-impl Deref for NewContact {
+/// What an API token is allowed to do.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Listing and fetching contacts.
+    Read,
+    /// Creating, updating and deleting them.
+    Write,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 2] = [Scope::Read, Scope::Write];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Scope::Read => "Read contacts",
+            Scope::Write => "Create, change and delete contacts",
+        }
+    }
+}
+
+/// A token as its owner sees it on the tokens page. The token itself is never stored.
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiToken {
+    pub id: ApiTokenId,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// One entry in a contact's history, with its attributes on either side of the change.
+/// `before` is empty for contacts that were just created, `after` for ones that were deleted.
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::contact_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ContactEvent {
+    pub id: ContactEventId,
+    #[diesel(deserialize_as = String)]
+    pub kind: EventKind,
+    /// Where the change came from, like "web" or "api".
+    pub source: String,
+    /// Who made the change, unless it's from before there were accounts or they're gone.
+    #[diesel(select_expression = diesel::NullableExpressionMethods::nullable(crate::schema::users::username))]
+    #[diesel(select_expression_type = diesel::dsl::Nullable<crate::schema::users::username>)]
+    pub username: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// `ContactAttributes` as the history stored them, which are taken as they are like the ones in
+/// `contacts`, even if they were saved before their phone or email was checked.
+#[derive(Deserialize)]
+struct StoredAttributes {
+    first_name: String,
+    last_name: String,
+    phone: String,
+    email_address: String,
+}
+
+impl From<StoredAttributes> for ContactAttributes {
+    fn from(stored: StoredAttributes) -> Self {
+        Self {
+            first_name: stored.first_name,
+            last_name: stored.last_name,
+            phone: PhoneNumber(stored.phone),
+            email_address: EmailAddress(stored.email_address),
+        }
+    }
+}
+
+impl ContactEvent {
+    pub fn before(&self) -> Option<ContactAttributes> {
+        self.before
+            .clone()
+            .and_then(|before| serde_json::from_value::<StoredAttributes>(before).ok())
+            .map(ContactAttributes::from)
+    }
+
+    pub fn after(&self) -> Option<ContactAttributes> {
+        self.after
+            .clone()
+            .and_then(|after| serde_json::from_value::<StoredAttributes>(after).ok())
+            .map(ContactAttributes::from)
+    }
+}
+
+impl Deref for Contact {
     type Target = ContactAttributes;
 
     fn deref(&self) -> &Self::Target {
@@ -75,59 +789,402 @@ impl Deref for NewContact {
     }
 }
 
-impl From<Contact> for PendingContact::Form {
-    fn from(value: Contact) -> Self {
-        let ContactAttributes {
+impl PendingContact::Form {
+    pub fn new(
+        first_name: Option<String>,
+        last_name: Option<String>,
+        details: &DetailsInput,
+    ) -> Self {
+        let key = |index: usize| index.to_string();
+        let primary = |index: Option<usize>| index.map(key);
+        let addresses = |field: fn(&Address) -> &String| -> Vec<String> {
+            details.addresses.iter().map(field).cloned().collect()
+        };
+        Self {
             first_name,
             last_name,
-            phone,
-            email_address,
-        } = value.attributes;
+            phone_keys: (0..details.phones.len()).map(key).collect(),
+            phone_labels: details
+                .phones
+                .iter()
+                .map(|phone| phone.label.clone())
+                .collect(),
+            phones: details
+                .phones
+                .iter()
+                .map(|phone| phone.number.clone())
+                .collect(),
+            primary_phone: primary(details.phones.iter().position(|phone| phone.primary)),
+            email_keys: (0..details.emails.len()).map(key).collect(),
+            email_labels: details
+                .emails
+                .iter()
+                .map(|email| email.label.clone())
+                .collect(),
+            email_addresses: details
+                .emails
+                .iter()
+                .map(|email| email.address.clone())
+                .collect(),
+            primary_email: primary(details.emails.iter().position(|email| email.primary)),
+            address_labels: addresses(|address| &address.label),
+            streets: addresses(|address| &address.street),
+            cities: addresses(|address| &address.city),
+            regions: addresses(|address| &address.region),
+            postal_codes: addresses(|address| &address.postal_code),
+            countries: addresses(|address| &address.country),
+            version: None,
+            address_book: None,
+        }
+    }
+
+    /// The edit form of `contact`, which remembers the version it started from.
+    pub fn from_contact(contact: &Contact, details: &ContactDetails) -> Self {
         Self {
-            first_name: Some(first_name),
-            last_name: Some(last_name),
-            phone: Some(phone),
-            email_address: Some(email_address),
+            version: Some(contact.version),
+            ..Self::new(
+                Some(contact.first_name.clone()),
+                Some(contact.last_name.clone()),
+                &details.into(),
+            )
         }
     }
-}
 
-impl PendingContact::Form {
-    pub fn to_valid(&self) -> Result<ContactAttributes, PendingContact::Errors> {
-        match (
-            &self.first_name,
-            &self.last_name,
-            &self.phone,
-            &self.email_address,
-        ) {
-            (Some(first_name), Some(last_name), Some(phone), Some(email)) if !email.is_empty() => {
-                Ok(ContactAttributes {
-                    first_name: first_name.to_string(),
-                    last_name: last_name.to_string(),
-                    phone: phone.to_string(),
-                    email_address: email.to_string(),
-                })
-            }
-            _ => {
-                let mut errors = PendingContact::Errors::default();
-
-                if self.first_name.is_none() {
-                    errors.first_name = Some("Missing first name");
-                }
-                if self.last_name.is_none() {
-                    errors.last_name = Some("Missing last name");
-                }
-                if self.phone.is_none() {
-                    errors.phone = Some("Missing phone");
-                }
-                if self.email_address.is_none()
-                    || self.email_address.as_ref().is_some_and(|s| s.is_empty())
-                {
-                    errors.email_address = Some("Missing email address");
-                }
-
-                Err(errors)
-            }
+    // `Errors` has a slot for every input, which is what the forms want.
+    #[allow(clippy::result_large_err)]
+    pub fn to_valid(&self) -> Result<ValidContact, PendingContact::Errors> {
+        let phones: Vec<Phone> = rows(
+            &self.phone_keys,
+            &self.phone_labels,
+            &self.phones,
+            self.primary_phone.as_deref(),
+        )
+        .into_iter()
+        // Numbers that don't parse are reported by the `phone` rule.
+        .filter_map(|(label, number, primary)| {
+            Some(Phone {
+                label,
+                number: PhoneNumber::parse(&number)?,
+                primary,
+            })
+        })
+        .collect();
+        let emails: Vec<Email> = rows(
+            &self.email_keys,
+            &self.email_labels,
+            &self.email_addresses,
+            self.primary_email.as_deref(),
+        )
+        .into_iter()
+        // Just like phone numbers, it's the `email` rule that reports the others.
+        .filter_map(|(label, address, primary)| {
+            Some(Email {
+                label,
+                address: EmailAddress::parse(&address)?,
+                primary,
+            })
+        })
+        .collect();
+        let addresses = self.addresses();
+        let mut errors = self.validate();
+        // Only rows that have anything in them are addresses, which the rules of a field can't tell.
+        errors.streets.extend(
+            addresses
+                .iter()
+                .any(|address| address.street.trim().is_empty())
+                .then_some("Every address needs a street".into()),
+        );
+        errors.cities.extend(
+            addresses
+                .iter()
+                .any(|address| address.city.trim().is_empty())
+                .then_some("Every address needs a city".into()),
+        );
+        if !errors.is_empty() {
+            return Err(errors);
         }
+
+        let primary_phone = phones.iter().find(|phone| phone.primary);
+        let primary_email = emails.iter().find(|email| email.primary);
+        Ok(ValidContact {
+            attributes: ContactAttributes {
+                first_name: self.first_name.clone().unwrap_or_default(),
+                last_name: self.last_name.clone().unwrap_or_default(),
+                phone: primary_phone
+                    .map(|phone| phone.number.clone())
+                    .unwrap_or_default(),
+                email_address: primary_email
+                    .map(|email| email.address.clone())
+                    .unwrap_or_default(),
+            },
+            details: ContactDetails {
+                phones,
+                emails,
+                addresses,
+            },
+        })
+    }
+
+    /// Zips the address inputs back into addresses, dropping the rows left blank.
+    pub fn addresses(&self) -> Vec<Address> {
+        let rows = [
+            self.streets.len(),
+            self.cities.len(),
+            self.regions.len(),
+            self.postal_codes.len(),
+            self.countries.len(),
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or_default();
+        let cell = |column: &[String], row: usize| column.get(row).cloned().unwrap_or_default();
+        (0..rows)
+            .map(|row| Address {
+                label: cell(&self.address_labels, row),
+                street: cell(&self.streets, row),
+                city: cell(&self.cities, row),
+                region: cell(&self.regions, row),
+                postal_code: cell(&self.postal_codes, row),
+                country: cell(&self.countries, row),
+            })
+            .filter(|address| {
+                [
+                    &address.street,
+                    &address.city,
+                    &address.region,
+                    &address.postal_code,
+                    &address.country,
+                ]
+                .iter()
+                .any(|part| !part.trim().is_empty())
+            })
+            .collect()
+    }
+}
+
+/// Zips a repeated group of inputs back into `(label, value, primary)` rows.
+/// Rows left blank are dropped, and exactly one of the rest ends up primary:
+/// the one that was picked, or else the first.
+fn rows(
+    keys: &[String],
+    labels: &[String],
+    values: &[String],
+    primary: Option<&str>,
+) -> Vec<(String, String, bool)> {
+    let mut rows: Vec<(String, String, bool)> = keys
+        .iter()
+        .zip(labels)
+        .zip(values)
+        .filter(|(_, value)| !value.trim().is_empty())
+        .map(|((key, label), value)| (label.clone(), value.clone(), Some(key.as_str()) == primary))
+        .collect();
+    let picked = rows
+        .iter()
+        .position(|(_, _, primary)| *primary)
+        .unwrap_or(0);
+    for (index, row) in rows.iter_mut().enumerate() {
+        row.2 = index == picked;
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn email_addresses_are_checked_when_deserialized() {
+        let address: EmailAddress = serde_json::from_str(r#"" ada@example.com ""#).unwrap();
+        assert_eq!(&*address, "ada@example.com");
+        let none: EmailAddress = serde_json::from_str(r#""""#).unwrap();
+        assert!(none.is_empty());
+        assert!(serde_json::from_str::<EmailAddress>(r#""ada""#).is_err());
+        assert!(serde_json::from_str::<EmailAddress>(r#""Ada <ada@example.com>""#).is_err());
+    }
+
+    #[test]
+    fn phone_numbers_are_checked_when_deserialized() {
+        let number: PhoneNumber = serde_json::from_str(r#""(415) 555-0123""#).unwrap();
+        assert_eq!(&*number, "+14155550123");
+        let none: PhoneNumber = serde_json::from_str(r#""""#).unwrap();
+        assert!(none.is_empty());
+        assert!(serde_json::from_str::<PhoneNumber>(r#""555-1234""#).is_err());
+    }
+
+    #[test]
+    fn phone_numbers_parse_to_e164() {
+        let parse = |input| PhoneNumber::parse(input).map(|number| number.to_string());
+        assert_eq!(parse("(415) 555-0123").as_deref(), Some("+14155550123"));
+        assert_eq!(parse("+1 415.555.0123").as_deref(), Some("+14155550123"));
+        assert_eq!(parse("+44 20 7946 0958").as_deref(), Some("+442079460958"));
+        assert_eq!(parse("555-1234"), None);
+        assert_eq!(parse("call me"), None);
+    }
+
+    #[test]
+    fn email_addresses_parse_trimmed() {
+        let parse = |input| EmailAddress::parse(input).map(|address| address.to_string());
+        assert_eq!(
+            parse(" ada@example.com ").as_deref(),
+            Some("ada@example.com")
+        );
+        assert_eq!(parse("ada@localhost"), None);
+        assert_eq!(parse("ada@[127.0.0.1]"), None);
+        assert_eq!(parse("Ada <ada@example.com>"), None);
+        assert_eq!(parse("ada.example.com"), None);
+    }
+
+    fn form(details: DetailsInput) -> PendingContact::Form {
+        PendingContact::Form::new(
+            Some("Ada".to_string()),
+            Some("Lovelace".to_string()),
+            &details,
+        )
+    }
+
+    #[test]
+    fn to_valid_keeps_the_parsed_details_and_their_primaries() {
+        let valid = form(DetailsInput {
+            phones: vec![
+                PhoneInput {
+                    label: "Home".to_string(),
+                    number: "+44 20 7946 0958".to_string(),
+                    primary: false,
+                },
+                PhoneInput {
+                    label: "Work".to_string(),
+                    number: "(415) 555-0123".to_string(),
+                    primary: true,
+                },
+            ],
+            emails: vec![EmailInput {
+                label: String::new(),
+                address: " ada@example.com ".to_string(),
+                primary: true,
+            }],
+            addresses: vec![],
+        })
+        .to_valid()
+        .unwrap_or_else(|_| panic!("the form is valid"));
+
+        assert_eq!(valid.attributes.first_name, "Ada");
+        assert_eq!(&*valid.attributes.phone, "+14155550123");
+        assert_eq!(&*valid.attributes.email_address, "ada@example.com");
+        let phones: Vec<(&str, &str)> = valid
+            .details
+            .phones
+            .iter()
+            .map(|phone| (phone.label.as_str(), &*phone.number))
+            .collect();
+        assert_eq!(
+            phones,
+            [("Home", "+442079460958"), ("Work", "+14155550123")]
+        );
+    }
+
+    #[test]
+    fn to_valid_reports_every_field_at_once() {
+        let mut form = form(DetailsInput {
+            phones: vec![PhoneInput {
+                number: "call me".to_string(),
+                ..Default::default()
+            }],
+            emails: vec![
+                EmailInput {
+                    address: "ada@example.com".to_string(),
+                    primary: true,
+                    ..Default::default()
+                },
+                EmailInput {
+                    address: "ADA@example.com".to_string(),
+                    ..Default::default()
+                },
+            ],
+            addresses: vec![Address {
+                street: "12 St James's Square".to_string(),
+                ..Default::default()
+            }],
+        });
+        form.first_name = None;
+
+        let Err(errors) = form.to_valid() else {
+            panic!("the form has errors");
+        };
+        assert_eq!(errors.first_name, ["Missing first name"]);
+        assert!(errors.last_name.is_empty());
+        assert_eq!(errors.phones, ["Not a valid phone number"]);
+        assert_eq!(
+            errors.email_addresses,
+            ["Each email address can only be used once"]
+        );
+        assert_eq!(errors.cities, ["Every address needs a city"]);
+        assert!(errors.streets.is_empty());
+    }
+
+    #[test]
+    fn history_keeps_attributes_saved_before_they_were_checked() {
+        let event = ContactEvent {
+            id: ContactEventId(1),
+            kind: EventKind::Updated,
+            source: "web".to_string(),
+            username: None,
+            before: None,
+            after: Some(serde_json::json!({
+                "first_name": "Ada",
+                "last_name": "Lovelace",
+                "phone": "call me",
+                "email_address": "ada at example",
+            })),
+            created_at: Utc::now(),
+        };
+        let after = event.after().unwrap();
+        assert_eq!(&*after.email_address, "ada at example");
+        assert_eq!(&*after.phone, "call me");
+    }
+
+    #[test]
+    fn with_primary_replaces_only_the_primary_phone_and_email() {
+        let details = DetailsInput {
+            phones: vec![
+                PhoneInput {
+                    label: "Work".to_string(),
+                    number: "+1 415-555-0100".to_string(),
+                    primary: true,
+                },
+                PhoneInput {
+                    label: "Home".to_string(),
+                    number: "+1 415-555-0101".to_string(),
+                    primary: false,
+                },
+            ],
+            emails: vec![EmailInput {
+                label: String::new(),
+                address: "ada@example.com".to_string(),
+                primary: false,
+            }],
+            addresses: vec![],
+        };
+
+        let reverted = details.with_primary("+14155550199", "");
+        let phones: Vec<(&str, &str, bool)> = reverted
+            .phones
+            .iter()
+            .map(|phone| (phone.label.as_str(), phone.number.as_str(), phone.primary))
+            .collect();
+        assert_eq!(
+            phones,
+            [
+                ("Work", "+14155550199", true),
+                ("Home", "+1 415-555-0101", false)
+            ]
+        );
+        assert_eq!(reverted.emails.len(), 1);
+
+        let reverted = reverted.with_primary("", "grace@example.com");
+        assert_eq!(reverted.phones.len(), 1);
+        assert!(!reverted.phones[0].primary);
+        assert_eq!(reverted.emails.len(), 2);
+        assert!(reverted.emails[1].primary);
+        assert_eq!(reverted.emails[1].address, "grace@example.com");
     }
 }
@@ -0,0 +1,597 @@
+//! Keyset pagination over contacts.
+//!
+//! Instead of an offset, each page hands out opaque cursors pointing at its first and last rows.
+//! Asking for the next page means asking for the rows that sort after the last one,
+//! which stays fast on large tables and doesn't skip or repeat rows when contacts are added
+//! or removed in between requests.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use diesel::define_sql_function;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use diesel::sql_types::Float4;
+use diesel::sql_types::Text;
+use diesel::sql_types::Timestamptz;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::books;
+use crate::model::Contact;
+use crate::model::ContactId;
+use crate::model::Permission;
+use crate::model::UserId;
+use crate::search;
+use crate::search::ContactsExpression;
+use crate::AppError;
+
+define_sql_function!(fn lower(text: Text) -> Text);
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Direction {
+    #[serde(rename = "a")]
+    After,
+    #[serde(rename = "b")]
+    Before,
+}
+
+/// The columns the contacts table can be sorted by.
+/// Anything else in the `sort` parameter is rejected when deserializing.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortColumn {
+    FirstName,
+    LastName,
+    Phone,
+    EmailAddress,
+}
+
+impl SortColumn {
+    /// The name used for the `sort` parameter.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SortColumn::FirstName => "first_name",
+            SortColumn::LastName => "last_name",
+            SortColumn::Phone => "phone",
+            SortColumn::EmailAddress => "email_address",
+        }
+    }
+
+    fn expression(self) -> ContactsExpression<Text> {
+        use crate::schema::contacts::dsl::*;
+
+        match self {
+            SortColumn::FirstName => Box::new(first_name),
+            SortColumn::LastName => Box::new(last_name),
+            SortColumn::Phone => Box::new(phone),
+            SortColumn::EmailAddress => Box::new(email_address),
+        }
+    }
+
+    fn value(self, contact: &Contact) -> &str {
+        match self {
+            SortColumn::FirstName => &contact.first_name,
+            SortColumn::LastName => &contact.last_name,
+            SortColumn::Phone => &contact.phone,
+            SortColumn::EmailAddress => &contact.email_address,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortDirection {
+    /// The name used for the `dir` parameter.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SortDirection::Asc => "asc",
+            SortDirection::Desc => "desc",
+        }
+    }
+
+    pub fn reversed(self) -> Self {
+        match self {
+            SortDirection::Asc => SortDirection::Desc,
+            SortDirection::Desc => SortDirection::Asc,
+        }
+    }
+}
+
+/// How far back the "recently added" and "recently updated" views go.
+pub const RECENT_DAYS: i64 = 7;
+
+/// The views of contacts that were added or updated in the last `RECENT_DAYS`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Recent {
+    Added,
+    Updated,
+}
+
+impl Recent {
+    /// The name used for the `recent` parameter.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Recent::Added => "added",
+            Recent::Updated => "updated",
+        }
+    }
+
+    fn expression(self) -> ContactsExpression<Timestamptz> {
+        use crate::schema::contacts::dsl::*;
+
+        match self {
+            Recent::Added => Box::new(created_at),
+            Recent::Updated => Box::new(updated_at),
+        }
+    }
+
+    fn value(self, contact: &Contact) -> DateTime<Utc> {
+        match self {
+            Recent::Added => contact.created_at,
+            Recent::Updated => contact.updated_at,
+        }
+    }
+}
+
+/// How a page of contacts is ordered. Ties are always broken by id, so the order is total.
+#[derive(Clone, Copy)]
+pub enum Ordering<'a> {
+    Id,
+    /// Best search results first.
+    Rank(&'a str),
+    Column(SortColumn, SortDirection),
+    /// Most recently added or updated first.
+    Recent(Recent),
+}
+
+impl<'a> Ordering<'a> {
+    /// An explicit sort wins over ranking the search results,
+    /// which wins over showing the most recent changes first.
+    pub fn new(
+        query: Option<&'a str>,
+        sort: Option<SortColumn>,
+        direction: SortDirection,
+        recent: Option<Recent>,
+    ) -> Self {
+        match (sort, query, recent) {
+            (Some(column), _, _) => Ordering::Column(column, direction),
+            (None, Some(q), _) => Ordering::Rank(q),
+            (None, None, Some(recent)) => Ordering::Recent(recent),
+            (None, None, None) => Ordering::Id,
+        }
+    }
+}
+
+/// Where a page starts, relative to a row of the previous one.
+///
+/// Alongside the id, cursors carry the value of whatever the rows are ordered by:
+/// the rank for search results, the sorted column, or the timestamp of the recent changes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Cursor {
+    #[serde(rename = "d")]
+    pub direction: Direction,
+    #[serde(rename = "i")]
+    pub id: ContactId,
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub rank: Option<f32>,
+    #[serde(rename = "v", default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        // Serializing a struct of plain values can't fail.
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+pub struct Page {
+    pub contacts: Vec<Contact>,
+    pub next: Option<Cursor>,
+    pub prev: Option<Cursor>,
+}
+
+/// The rows past `(value, cursor_id)` when ordering by `key` and then by id.
+///
+/// `key_greater` and `id_greater` say which way "past" is for each of them.
+/// This is a macro rather than a function because the bounds diesel needs
+/// to compare two arbitrary expressions are more trouble than they're worth.
+macro_rules! keyset {
+    ($key:expr, $value:expr, $key_greater:expr, $id_greater:expr, $cursor_id:expr) => {{
+        let past_key: ContactsExpression<Bool> = match $key_greater {
+            true => Box::new($key.gt($value)),
+            false => Box::new($key.lt($value)),
+        };
+        let past_id: ContactsExpression<Bool> = match $id_greater {
+            true => Box::new(crate::schema::contacts::id.gt($cursor_id)),
+            false => Box::new(crate::schema::contacts::id.lt($cursor_id)),
+        };
+        Box::new(past_key.or($key.eq($value).and(past_id))) as ContactsExpression<Bool>
+    }};
+}
+
+/// What narrows down the contacts being paged through.
+#[derive(Clone, Copy, Default)]
+pub struct ContactFilter<'a> {
+    /// Only contacts matching this search.
+    pub query: Option<&'a str>,
+    /// Only contacts with a tag of this name.
+    pub tag: Option<&'a str>,
+    /// Only contacts added or updated in the last `RECENT_DAYS`.
+    pub recent: Option<Recent>,
+}
+
+/// Loads up to `limit` of the contacts `user` can see matching `filter`, starting from `cursor`.
+pub async fn load_page(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    filter: ContactFilter<'_>,
+    ordering: Ordering<'_>,
+    cursor: Option<&Cursor>,
+    limit: i64,
+) -> Result<Page, AppError> {
+    use crate::schema::contacts::dsl::address_book_id;
+    use crate::schema::contacts::dsl::contacts;
+    use crate::schema::contacts::dsl::deleted_at;
+    use crate::schema::contacts::dsl::id;
+
+    let direction = cursor.map_or(Direction::After, |cursor| cursor.direction);
+    // Going backwards, we flip the order, take the rows closest to the cursor,
+    // and then flip them back around.
+    let forwards = direction == Direction::After;
+
+    // Select the rank alongside each search result so that we can put it in the cursors.
+    let rank: ContactsExpression<Float4> = match ordering {
+        Ordering::Rank(q) => search::rank(q),
+        _ => Box::new(0.0f32.into_sql::<Float4>()),
+    };
+    let mut statement = contacts
+        .filter(address_book_id.eq_any(books::allowing(user, Permission::View)))
+        .filter(deleted_at.is_null())
+        .select((Contact::as_select(), rank))
+        .into_boxed();
+    if let Some(q) = filter.query {
+        statement = statement.filter(search::matches(q));
+    }
+    if let Some(tag) = filter.tag {
+        use crate::schema::contact_tags;
+        use crate::schema::tags;
+
+        statement = statement.filter(
+            id.eq_any(
+                contact_tags::table
+                    .inner_join(tags::table)
+                    .filter(lower(tags::name).eq(tag.to_lowercase()))
+                    .select(contact_tags::contact_id),
+            ),
+        );
+    }
+
+    if let Some(recent) = filter.recent {
+        let cutoff = Utc::now() - Duration::days(RECENT_DAYS);
+        statement = statement.filter(recent.expression().gt(cutoff));
+    }
+
+    match ordering {
+        Ordering::Id => {
+            if let Some(cursor) = cursor {
+                statement = match forwards {
+                    true => statement.filter(id.gt(cursor.id)),
+                    false => statement.filter(id.lt(cursor.id)),
+                };
+            }
+            statement = match forwards {
+                true => statement.order(id.asc()),
+                false => statement.order(id.desc()),
+            };
+        }
+        Ordering::Rank(q) => {
+            if let Some(Cursor {
+                id: cursor_id,
+                rank: Some(rank),
+                ..
+            }) = cursor
+            {
+                statement = statement.filter(keyset!(
+                    search::rank(q),
+                    *rank,
+                    !forwards,
+                    forwards,
+                    *cursor_id
+                ));
+            }
+            statement = match forwards {
+                true => statement.order((search::rank(q).desc(), id.asc())),
+                false => statement.order((search::rank(q).asc(), id.desc())),
+            };
+        }
+        Ordering::Column(column, sort_direction) => {
+            let ascending = sort_direction == SortDirection::Asc;
+            if let Some(Cursor {
+                id: cursor_id,
+                value: Some(value),
+                ..
+            }) = cursor
+            {
+                statement = statement.filter(keyset!(
+                    column.expression(),
+                    value.clone(),
+                    ascending == forwards,
+                    forwards,
+                    *cursor_id
+                ));
+            }
+            statement = match (ascending == forwards, forwards) {
+                (true, true) => statement.order((column.expression().asc(), id.asc())),
+                (false, true) => statement.order((column.expression().desc(), id.asc())),
+                (true, false) => statement.order((column.expression().asc(), id.desc())),
+                (false, false) => statement.order((column.expression().desc(), id.desc())),
+            };
+        }
+        Ordering::Recent(recent) => {
+            // A timestamp we can't read just starts from the top.
+            let since = cursor.and_then(|cursor| {
+                let value = DateTime::parse_from_rfc3339(cursor.value.as_deref()?).ok()?;
+                Some((value.with_timezone(&Utc), cursor.id))
+            });
+            if let Some((value, cursor_id)) = since {
+                statement = statement.filter(keyset!(
+                    recent.expression(),
+                    value,
+                    !forwards,
+                    forwards,
+                    cursor_id
+                ));
+            }
+            statement = match forwards {
+                true => statement.order((recent.expression().desc(), id.asc())),
+                false => statement.order((recent.expression().asc(), id.desc())),
+            };
+        }
+    }
+
+    // Pull one extra row to find out whether there is anything past this page.
+    let mut page: Vec<(Contact, f32)> = statement.limit(limit + 1).load(connection).await?;
+    let has_more = page.len() as i64 > limit;
+    page.truncate(limit as usize);
+    if !forwards {
+        page.reverse();
+    }
+
+    let cursor_at = |direction, row: Option<&(Contact, f32)>| {
+        row.map(|(contact, rank)| Cursor {
+            direction,
+            id: contact.id,
+            rank: matches!(ordering, Ordering::Rank(_)).then_some(*rank),
+            value: match ordering {
+                Ordering::Column(column, _) => Some(column.value(contact).to_string()),
+                Ordering::Recent(recent) => Some(recent.value(contact).to_rfc3339()),
+                _ => None,
+            },
+        })
+    };
+    let (has_next, has_prev) = match direction {
+        Direction::After => (has_more, cursor.is_some()),
+        Direction::Before => (true, has_more),
+    };
+
+    // `first` on the `Vec` itself would be diesel's.
+    let rows = page.as_slice();
+    Ok(Page {
+        next: cursor_at(Direction::After, rows.last()).filter(|_| has_next),
+        prev: cursor_at(Direction::Before, rows.first()).filter(|_| has_prev),
+        contacts: page.into_iter().map(|(contact, _)| contact).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel_async::AsyncConnection;
+
+    use super::*;
+    use crate::auth;
+    use crate::model::AddressBookId;
+    use crate::model::ContactAttributes;
+
+    #[test]
+    fn cursors_decode_to_what_was_encoded() {
+        let id: ContactId = serde_json::from_value(42.into()).unwrap();
+        let cursor = Cursor {
+            direction: Direction::Before,
+            id,
+            rank: Some(0.25),
+            value: Some("Lovelace, Ada".to_string()),
+        };
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.direction, Direction::Before);
+        assert_eq!(decoded.id, id);
+        assert_eq!(decoded.rank, Some(0.25));
+        assert_eq!(decoded.value.as_deref(), Some("Lovelace, Ada"));
+
+        let bare = Cursor {
+            rank: None,
+            value: None,
+            ..cursor
+        };
+        let decoded = Cursor::decode(&bare.encode()).unwrap();
+        assert_eq!((decoded.rank, decoded.value), (None, None));
+    }
+
+    #[test]
+    fn cursors_that_were_not_encoded_by_us_are_rejected() {
+        assert!(Cursor::decode("").is_none());
+        assert!(Cursor::decode("not a cursor!").is_none());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode(b"{\"d\":\"x\",\"i\":1}")).is_none());
+    }
+
+    async fn test_connection() -> AsyncPgConnection {
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection = AsyncPgConnection::establish(&url).await.unwrap();
+        connection.begin_test_transaction().await.unwrap();
+        connection
+    }
+
+    async fn insert_contact(
+        connection: &mut AsyncPgConnection,
+        book: AddressBookId,
+        last_name: &str,
+    ) -> ContactId {
+        use crate::schema::contacts;
+
+        let attributes = ContactAttributes {
+            first_name: "Ada".to_string(),
+            last_name: last_name.to_string(),
+            phone: Default::default(),
+            email_address: Default::default(),
+        };
+        diesel::insert_into(contacts::table)
+            .values((&attributes, contacts::address_book_id.eq(book)))
+            .returning(contacts::id)
+            .get_result(connection)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pages_neither_skip_nor_repeat_rows_with_the_same_value() {
+        let mut connection = test_connection().await;
+        let user = auth::create_user(&mut connection, "pagination-test", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let book = books::default_book(&mut connection, user.id).await.unwrap();
+        let mut expected = vec![];
+        for last_name in ["Byron", "Adams", "Byron", "Adams", "Byron"] {
+            expected.push((
+                last_name,
+                insert_contact(&mut connection, book, last_name).await,
+            ));
+        }
+        expected.sort();
+        let expected: Vec<ContactId> = expected.into_iter().map(|(_, id)| id).collect();
+
+        let ordering = Ordering::Column(SortColumn::LastName, SortDirection::Asc);
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let page = load_page(
+                &mut connection,
+                user.id,
+                ContactFilter::default(),
+                ordering,
+                cursor.as_ref(),
+                2,
+            )
+            .await
+            .unwrap();
+            pages.push(
+                page.contacts
+                    .iter()
+                    .map(|contact| contact.id)
+                    .collect::<Vec<_>>(),
+            );
+            cursor = page.next.and_then(|next| Cursor::decode(&next.encode()));
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(pages.concat(), expected);
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), [2, 2, 1]);
+
+        // Going back from the last page gives the one before it.
+        let last = load_page(
+            &mut connection,
+            user.id,
+            ContactFilter::default(),
+            ordering,
+            Some(&Cursor {
+                direction: Direction::After,
+                id: expected[3],
+                rank: None,
+                value: Some("Byron".to_string()),
+            }),
+            2,
+        )
+        .await
+        .unwrap();
+        let previous = load_page(
+            &mut connection,
+            user.id,
+            ContactFilter::default(),
+            ordering,
+            last.prev.as_ref(),
+            2,
+        )
+        .await
+        .unwrap();
+        let ids: Vec<ContactId> = previous.contacts.iter().map(|contact| contact.id).collect();
+        assert_eq!(ids, expected[2..4]);
+        assert!(previous.prev.is_some());
+    }
+
+    #[tokio::test]
+    async fn recently_added_contacts_come_newest_first() {
+        use crate::schema::contacts;
+
+        let mut connection = test_connection().await;
+        let user = auth::create_user(&mut connection, "recent-test", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let book = books::default_book(&mut connection, user.id).await.unwrap();
+        let mut added = vec![];
+        for days_ago in [RECENT_DAYS + 23, 2, 1] {
+            let contact = insert_contact(&mut connection, book, "Byron").await;
+            diesel::update(contacts::table.find(contact))
+                .set(contacts::created_at.eq(Utc::now() - Duration::days(days_ago)))
+                .execute(&mut connection)
+                .await
+                .unwrap();
+            added.push(contact);
+        }
+
+        let filter = ContactFilter {
+            recent: Some(Recent::Added),
+            ..Default::default()
+        };
+        let ordering = Ordering::new(None, None, SortDirection::Asc, filter.recent);
+        let first = load_page(&mut connection, user.id, filter, ordering, None, 1)
+            .await
+            .unwrap();
+        let second = load_page(
+            &mut connection,
+            user.id,
+            filter,
+            ordering,
+            first.next.as_ref(),
+            1,
+        )
+        .await
+        .unwrap();
+        let ids: Vec<ContactId> = [first.contacts, second.contacts]
+            .concat()
+            .iter()
+            .map(|contact| contact.id)
+            .collect();
+        assert_eq!(ids, [added[2], added[1]]);
+        assert!(second.next.is_none());
+    }
+}
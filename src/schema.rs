@@ -1,11 +1,168 @@
 // @generated automatically by Diesel CLI.
 
+pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "tsvector", schema = "pg_catalog"))]
+    pub struct Tsvector;
+}
+
+diesel::table! {
+    address_books (id) {
+        id -> Int4,
+        name -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        token_hash -> Varchar,
+        scopes -> Array<Text>,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    contact_addresses (id) {
+        id -> Int4,
+        contact_id -> Int4,
+        label -> Varchar,
+        street -> Varchar,
+        city -> Varchar,
+        region -> Varchar,
+        postal_code -> Varchar,
+        country -> Varchar,
+    }
+}
+
+diesel::table! {
+    contact_emails (id) {
+        id -> Int4,
+        contact_id -> Int4,
+        label -> Varchar,
+        address -> Varchar,
+        is_primary -> Bool,
+        address_book_id -> Nullable<Int4>,
+        in_trash -> Bool,
+    }
+}
+
+diesel::table! {
+    contact_events (id) {
+        id -> Int4,
+        contact_id -> Int4,
+        kind -> Varchar,
+        source -> Varchar,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        created_at -> Timestamptz,
+        user_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    contact_phones (id) {
+        id -> Int4,
+        contact_id -> Int4,
+        label -> Varchar,
+        number -> Varchar,
+        is_primary -> Bool,
+    }
+}
+
+diesel::table! {
+    contact_tags (contact_id, tag_id) {
+        contact_id -> Int4,
+        tag_id -> Int4,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Tsvector;
+
     contacts (id) {
         id -> Int4,
         first_name -> Varchar,
         last_name -> Varchar,
         phone -> Varchar,
         email_address -> Varchar,
+        search_text -> Text,
+        search_document -> Tsvector,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        version -> Int4,
+        deleted_at -> Nullable<Timestamptz>,
+        merged_into -> Nullable<Int4>,
+        address_book_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    memberships (address_book_id, user_id) {
+        address_book_id -> Int4,
+        user_id -> Int4,
+        role -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Uuid,
+        user_id -> Int4,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        csrf_token -> Varchar,
+    }
+}
+
+diesel::table! {
+    tags (id) {
+        id -> Int4,
+        name -> Varchar,
+        address_book_id -> Nullable<Int4>,
     }
 }
+
+diesel::table! {
+    users (id) {
+        id -> Int4,
+        username -> Varchar,
+        password_hash -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::joinable!(api_tokens -> users (user_id));
+diesel::joinable!(contact_addresses -> contacts (contact_id));
+diesel::joinable!(contact_emails -> contacts (contact_id));
+diesel::joinable!(contact_events -> contacts (contact_id));
+diesel::joinable!(contact_events -> users (user_id));
+diesel::joinable!(contact_phones -> contacts (contact_id));
+diesel::joinable!(contact_tags -> contacts (contact_id));
+diesel::joinable!(contact_tags -> tags (tag_id));
+diesel::joinable!(contacts -> address_books (address_book_id));
+diesel::joinable!(memberships -> address_books (address_book_id));
+diesel::joinable!(memberships -> users (user_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(tags -> address_books (address_book_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    address_books,
+    api_tokens,
+    contact_addresses,
+    contact_emails,
+    contact_events,
+    contact_phones,
+    contact_tags,
+    contacts,
+    memberships,
+    sessions,
+    tags,
+    users,
+);
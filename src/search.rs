@@ -0,0 +1,174 @@
+//! Ranked, typo-tolerant search across every contact field.
+//!
+//! A contact matches if the full-text document matches the query,
+//! if the query appears anywhere in the contact, or if it is close enough to a word in it
+//! (by trigram similarity) to count as a typo. Results are ranked by a mix of the two scores.
+//! Phone numbers are stored in E.164 but typed in any format, so queries that look like one
+//! are also matched against the digits of the primary phone alone.
+
+use std::ops::Range;
+
+use diesel::dsl::sql;
+use diesel::expression::BoxableExpression;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::query_builder::QueryId;
+use diesel::sql_types::Bool;
+use diesel::sql_types::Float4;
+use diesel::sql_types::SqlType;
+use diesel::sql_types::Text;
+
+use crate::model::PhoneNumber;
+use crate::schema::contacts;
+use crate::schema::sql_types::Tsvector;
+
+#[derive(QueryId, Clone, SqlType)]
+#[diesel(postgres_type(name = "tsquery", schema = "pg_catalog"))]
+pub struct Tsquery;
+
+diesel::infix_operator!(Matches, " @@ ", backend: Pg);
+diesel::infix_operator!(WordSimilar, " <% ", backend: Pg);
+
+define_sql_function!(fn ts_rank(document: Tsvector, query: Tsquery) -> Float4);
+define_sql_function!(fn word_similarity(needle: Text, haystack: Text) -> Float4);
+define_sql_function!(fn regexp_replace(text: Text, pattern: Text, replacement: Text, flags: Text) -> Text);
+
+pub type ContactsExpression<ST> = Box<dyn BoxableExpression<contacts::table, Pg, SqlType = ST>>;
+
+/// `websearch_to_tsquery` needs a `regconfig`,
+/// which is easiest to spell out in SQL rather than binding it.
+fn to_tsquery(query: &str) -> ContactsExpression<Tsquery> {
+    Box::new(
+        sql::<Tsquery>("websearch_to_tsquery('simple', ")
+            .bind::<Text, _>(query.to_string())
+            .sql(")"),
+    )
+}
+
+/// Escapes `LIKE` wildcards so that they are matched literally.
+fn escape_like(query: &str) -> String {
+    query
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// The digits to look for in phone numbers, if the query looks like one, like "(415) 555-0123".
+/// A number that parses is also looked for without its country code or national prefix, since
+/// neither is necessarily there in the format it's shown in.
+fn phone_needles(query: &str) -> Vec<String> {
+    let digits: String = query.chars().filter(char::is_ascii_digit).collect();
+    let phone_like = query
+        .chars()
+        .all(|c| c.is_ascii_digit() || " +-().".contains(c));
+    if digits.is_empty() || !phone_like {
+        return vec![];
+    }
+    let mut needles = vec![digits];
+    if let Some(digits) = PhoneNumber::parse(query).and_then(|number| number.national_digits()) {
+        if !needles.contains(&digits) {
+            needles.push(digits);
+        }
+    }
+    needles
+}
+
+pub fn matches(query: &str) -> ContactsExpression<Bool> {
+    let needle = query.to_lowercase();
+    let mut matches: ContactsExpression<Bool> = Box::new(
+        Matches::new(contacts::search_document, to_tsquery(query))
+            .or(contacts::search_text.like(format!("%{}%", escape_like(&needle))))
+            .or(WordSimilar::new(
+                needle.into_sql::<Text>(),
+                contacts::search_text,
+            )),
+    );
+    for digits in phone_needles(query) {
+        let phone_digits = regexp_replace(contacts::phone, r"\D", "", "g");
+        matches = Box::new(matches.or(phone_digits.like(format!("%{digits}%"))));
+    }
+    matches
+}
+
+/// Higher is better.
+pub fn rank(query: &str) -> ContactsExpression<Float4> {
+    Box::new(
+        ts_rank(contacts::search_document, to_tsquery(query))
+            + word_similarity(query.to_lowercase(), contacts::search_text),
+    )
+}
+
+/// Where each word of the query appears in `text`, ignoring case, as byte ranges.
+/// Overlapping matches are merged so that they can be highlighted in one go.
+pub fn matched_ranges(text: &str, query: &str) -> Vec<Range<usize>> {
+    let lower = |s: &str| -> Vec<char> {
+        s.chars()
+            .map(|c| c.to_lowercase().next().unwrap_or(c))
+            .collect()
+    };
+    let haystack = lower(text);
+    let offsets: Vec<usize> = text
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(text.len()))
+        .collect();
+
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for term in query.split_whitespace().map(lower) {
+        if term.is_empty() || term.len() > haystack.len() {
+            continue;
+        }
+        for start in 0..=(haystack.len() - term.len()) {
+            if haystack[start..start + term.len()] == term[..] {
+                ranges.push(offsets[start]..offsets[start + term.len()]);
+            }
+        }
+    }
+    merge(ranges)
+}
+
+/// `matched_ranges` for a phone number as it's shown, along with the digits that `matches`
+/// looked for, whatever is between them in `text`.
+pub fn matched_phone_ranges(text: &str, query: &str) -> Vec<Range<usize>> {
+    let mut ranges = matched_ranges(text, query);
+    let digits: Vec<(usize, char)> = text
+        .char_indices()
+        .filter(|(_, c)| c.is_ascii_digit())
+        .collect();
+    for needle in phone_needles(query) {
+        let needle: Vec<char> = needle.chars().collect();
+        for window in digits.windows(needle.len()) {
+            if window.iter().map(|(_, c)| *c).eq(needle.iter().copied()) {
+                // Digits are a single byte each.
+                ranges.push(window[0].0..window[window.len() - 1].0 + 1);
+            }
+        }
+    }
+    merge(ranges)
+}
+
+fn merge(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = vec![];
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phone_numbers_match_by_their_digits() {
+        let text = "(415) 555-0123";
+        assert_eq!(matched_phone_ranges(text, "5550123"), vec![6..14]);
+        assert_eq!(matched_phone_ranges(text, "+1 415 555 0123"), vec![1..14]);
+        assert_eq!(matched_phone_ranges(text, "555-0123"), vec![6..14]);
+        assert!(matched_phone_ranges(text, "ada").is_empty());
+    }
+}
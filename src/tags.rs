@@ -0,0 +1,244 @@
+//! Grouping contacts under free-form tags, like "team", "customer" or "vendor".
+
+use std::collections::HashMap;
+
+use diesel::define_sql_function;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+
+use crate::books;
+use crate::model::AddressBookId;
+use crate::model::Contact;
+use crate::model::ContactId;
+use crate::model::Permission;
+use crate::model::Tag;
+use crate::model::TagId;
+use crate::model::TaggedContact;
+use crate::model::UserId;
+use crate::AppError;
+
+define_sql_function!(fn lower(text: Text) -> Text);
+
+/// The names of the tags in every book `user` can see, for the filter.
+/// Books can have tags of the same name, which the filter treats as one.
+pub async fn all_tags(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Vec<String>, AppError> {
+    use crate::schema::tags;
+
+    let mut names: Vec<String> = tags::table
+        .filter(tags::address_book_id.eq_any(books::allowing(user, Permission::View)))
+        .order(tags::name)
+        .select(tags::name)
+        .distinct()
+        .load(connection)
+        .await?;
+    names.dedup_by(|name, previous| name.to_lowercase() == previous.to_lowercase());
+    Ok(names)
+}
+
+/// The tags of the book `contact_id` is in, for suggestions in its tag editor.
+pub async fn book_tags(
+    connection: &mut AsyncPgConnection,
+    contact_id: ContactId,
+) -> Result<Vec<Tag>, AppError> {
+    use crate::schema::tags;
+
+    let book = contact_book(connection, contact_id).await?;
+    Ok(tags::table
+        .filter(tags::address_book_id.eq(book))
+        .order(tags::name)
+        .select(Tag::as_select())
+        .load(connection)
+        .await?)
+}
+
+async fn contact_book(
+    connection: &mut AsyncPgConnection,
+    contact_id: ContactId,
+) -> Result<AddressBookId, AppError> {
+    use crate::schema::contacts;
+
+    contacts::table
+        .find(contact_id)
+        .select(contacts::address_book_id)
+        .first::<Option<AddressBookId>>(connection)
+        .await?
+        .ok_or(AppError::NotFound)
+}
+
+/// The tags of each of `contact_ids`, in one query.
+/// Contacts without any tags are left out of the map.
+pub async fn tags_for(
+    connection: &mut AsyncPgConnection,
+    contact_ids: &[ContactId],
+) -> Result<HashMap<ContactId, Vec<Tag>>, AppError> {
+    use crate::schema::contact_tags;
+    use crate::schema::tags;
+
+    let rows: Vec<(ContactId, Tag)> = contact_tags::table
+        .inner_join(tags::table)
+        .filter(contact_tags::contact_id.eq_any(contact_ids))
+        .order(tags::name)
+        .select((contact_tags::contact_id, Tag::as_select()))
+        .load(connection)
+        .await?;
+
+    let mut by_contact: HashMap<ContactId, Vec<Tag>> = HashMap::new();
+    for (contact_id, tag) in rows {
+        by_contact.entry(contact_id).or_default().push(tag);
+    }
+    Ok(by_contact)
+}
+
+pub async fn with_tags(
+    connection: &mut AsyncPgConnection,
+    contacts: Vec<Contact>,
+) -> Result<Vec<TaggedContact>, AppError> {
+    let ids: Vec<ContactId> = contacts.iter().map(|contact| contact.id).collect();
+    let mut tags = tags_for(connection, &ids).await?;
+    Ok(contacts
+        .into_iter()
+        .map(|contact| TaggedContact {
+            tags: tags
+                .remove(&contact.id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|tag| tag.name)
+                .collect(),
+            contact,
+        })
+        .collect())
+}
+
+/// Tags a contact, creating the tag in its book if this is the first time the book sees its name.
+/// Names are matched whatever their case, keeping the one the tag was created with.
+/// Blank names are ignored.
+pub async fn add_tag(
+    connection: &mut AsyncPgConnection,
+    contact_id: ContactId,
+    name: &str,
+) -> Result<(), AppError> {
+    use crate::schema::contact_tags;
+    use crate::schema::tags;
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Ok(());
+    }
+
+    let book = contact_book(connection, contact_id).await?;
+    // The unique index is on an expression, which `ON CONFLICT` can't name,
+    // so a tag that's already there is looked up on its own.
+    let created: Option<TagId> = diesel::insert_into(tags::table)
+        .values((tags::name.eq(name), tags::address_book_id.eq(book)))
+        .on_conflict_do_nothing()
+        .returning(tags::id)
+        .get_result(connection)
+        .await
+        .optional()?;
+    let tag_id = match created {
+        Some(tag_id) => tag_id,
+        None => {
+            tags::table
+                .filter(tags::address_book_id.eq(book))
+                .filter(lower(tags::name).eq(name.to_lowercase()))
+                .select(tags::id)
+                .first(connection)
+                .await?
+        }
+    };
+    diesel::insert_into(contact_tags::table)
+        .values((
+            contact_tags::contact_id.eq(contact_id),
+            contact_tags::tag_id.eq(tag_id),
+        ))
+        .on_conflict_do_nothing()
+        .execute(connection)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn remove_tag(
+    connection: &mut AsyncPgConnection,
+    contact_id: ContactId,
+    tag_id: TagId,
+) -> Result<(), AppError> {
+    use crate::schema::contact_tags;
+
+    diesel::delete(contact_tags::table.find((contact_id, tag_id)))
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel_async::AsyncConnection;
+
+    use super::*;
+    use crate::auth;
+    use crate::model::ContactAttributes;
+
+    async fn test_connection() -> AsyncPgConnection {
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection = AsyncPgConnection::establish(&url).await.unwrap();
+        connection.begin_test_transaction().await.unwrap();
+        connection
+    }
+
+    async fn insert_contact(connection: &mut AsyncPgConnection, book: AddressBookId) -> ContactId {
+        use crate::schema::contacts;
+
+        let attributes = ContactAttributes {
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            phone: Default::default(),
+            email_address: Default::default(),
+        };
+        diesel::insert_into(contacts::table)
+            .values((&attributes, contacts::address_book_id.eq(book)))
+            .returning(contacts::id)
+            .get_result(connection)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tags_are_shared_within_a_book_whatever_their_case() {
+        let mut connection = test_connection().await;
+        let alice = auth::create_user(&mut connection, "tags-alice", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let bob = auth::create_user(&mut connection, "tags-bob", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let alice_book = books::default_book(&mut connection, alice.id)
+            .await
+            .unwrap();
+        let bob_book = books::default_book(&mut connection, bob.id).await.unwrap();
+        let first = insert_contact(&mut connection, alice_book).await;
+        let second = insert_contact(&mut connection, alice_book).await;
+        let bobs = insert_contact(&mut connection, bob_book).await;
+
+        add_tag(&mut connection, first, "Team").await.unwrap();
+        add_tag(&mut connection, second, "TEAM").await.unwrap();
+        add_tag(&mut connection, bobs, "team").await.unwrap();
+
+        let tags = tags_for(&mut connection, &[first, second, bobs])
+            .await
+            .unwrap();
+        assert_eq!(tags[&first][0].name, "Team");
+        assert_eq!(tags[&second][0].id, tags[&first][0].id);
+        assert_eq!(tags[&bobs][0].name, "team");
+        assert_ne!(tags[&bobs][0].id, tags[&first][0].id);
+        assert_eq!(all_tags(&mut connection, bob.id).await.unwrap(), ["team"]);
+    }
+}
@@ -0,0 +1,181 @@
+//! Personal API tokens, which the API takes instead of a login.
+//!
+//! A token is only shown once, when it's created. What's stored is its SHA-256 hash, which is
+//! enough since tokens are long and random rather than something people pick.
+
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::auth;
+use crate::model::ApiToken;
+use crate::model::ApiTokenId;
+use crate::model::Scope;
+use crate::model::User;
+use crate::model::UserId;
+use crate::AppError;
+
+/// Makes tokens easy to recognize, for people and for secret scanners.
+const TOKEN_PREFIX: &str = "hsr_";
+
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Returns the new token, which can't be looked up again afterwards.
+pub async fn create(
+    connection: &mut AsyncPgConnection,
+    owner: UserId,
+    name: &str,
+    scopes: &[Scope],
+) -> Result<String, AppError> {
+    use crate::schema::api_tokens;
+
+    let token = format!("{TOKEN_PREFIX}{}", auth::random_secret());
+    let scopes: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
+    diesel::insert_into(api_tokens::table)
+        .values((
+            api_tokens::user_id.eq(owner),
+            api_tokens::name.eq(name),
+            api_tokens::token_hash.eq(hash(&token)),
+            api_tokens::scopes.eq(scopes),
+        ))
+        .execute(connection)
+        .await?;
+    Ok(token)
+}
+
+/// `owner`'s tokens, newest first.
+pub async fn tokens_of(
+    connection: &mut AsyncPgConnection,
+    owner: UserId,
+) -> Result<Vec<ApiToken>, AppError> {
+    use crate::schema::api_tokens;
+
+    Ok(api_tokens::table
+        .filter(api_tokens::user_id.eq(owner))
+        .order(api_tokens::id.desc())
+        .select(ApiToken::as_select())
+        .load(connection)
+        .await?)
+}
+
+pub async fn revoke(
+    connection: &mut AsyncPgConnection,
+    owner: UserId,
+    token_id: ApiTokenId,
+) -> Result<usize, AppError> {
+    use crate::schema::api_tokens;
+
+    Ok(diesel::delete(
+        api_tokens::table
+            .find(token_id)
+            .filter(api_tokens::user_id.eq(owner)),
+    )
+    .execute(connection)
+    .await?)
+}
+
+/// The owner of `token` and what it's allowed to do, noting that it was just used.
+pub async fn authenticate(
+    connection: &mut AsyncPgConnection,
+    token: &str,
+) -> Result<Option<(User, Vec<Scope>)>, AppError> {
+    use crate::schema::api_tokens;
+    use crate::schema::users;
+
+    let found: Option<(UserId, Vec<String>)> =
+        diesel::update(api_tokens::table.filter(api_tokens::token_hash.eq(hash(token))))
+            .set(api_tokens::last_used_at.eq(Utc::now()))
+            .returning((api_tokens::user_id, api_tokens::scopes))
+            .get_result(connection)
+            .await
+            .optional()?;
+    let Some((user_id, scopes)) = found else {
+        return Ok(None);
+    };
+    let user = users::table
+        .find(user_id)
+        .select(User::as_select())
+        .first(connection)
+        .await?;
+    let scopes = Scope::ALL
+        .into_iter()
+        .filter(|scope| scopes.iter().any(|granted| granted == scope.as_str()))
+        .collect();
+    Ok(Some((user, scopes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel_async::AsyncConnection;
+
+    use super::*;
+
+    async fn test_connection() -> AsyncPgConnection {
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection = AsyncPgConnection::establish(&url).await.unwrap();
+        connection.begin_test_transaction().await.unwrap();
+        connection
+    }
+
+    #[tokio::test]
+    async fn tokens_work_until_their_owner_revokes_them() {
+        use crate::schema::api_tokens;
+
+        let mut connection = test_connection().await;
+        let owner = auth::create_user(&mut connection, "tokens-owner", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let other = auth::create_user(&mut connection, "tokens-other", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let token = create(&mut connection, owner.id, "sync script", &[Scope::Read])
+            .await
+            .unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        let stored: Vec<String> = api_tokens::table
+            .select(api_tokens::token_hash)
+            .load(&mut connection)
+            .await
+            .unwrap();
+        assert!(!stored.contains(&token));
+
+        let (user, scopes) = authenticate(&mut connection, &token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.id, owner.id);
+        assert_eq!(scopes, [Scope::Read]);
+        assert!(authenticate(&mut connection, "hsr_guess")
+            .await
+            .unwrap()
+            .is_none());
+
+        let listed = tokens_of(&mut connection, owner.id).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].last_used_at.is_some());
+        assert_eq!(
+            revoke(&mut connection, other.id, listed[0].id)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            revoke(&mut connection, owner.id, listed[0].id)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(authenticate(&mut connection, &token)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
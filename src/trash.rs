@@ -0,0 +1,351 @@
+//! Deleting a contact only moves it to the trash, where it can be restored
+//! until it's purged for good, by hand or once the retention period is over.
+//!
+//! Every query for the contacts people work with has to leave out the ones in the trash,
+//! with `deleted_at.is_null()`. Only admins of a book can move its contacts to the trash and
+//! see them there.
+
+use std::time::Duration as StdDuration;
+
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+
+use crate::books;
+use crate::history;
+use crate::history::Change;
+use crate::history::Source;
+use crate::model::Contact;
+use crate::model::ContactAttributes;
+use crate::model::ContactId;
+use crate::model::EventKind;
+use crate::model::Permission;
+use crate::model::UserId;
+use crate::search::ContactsExpression;
+use crate::AppError;
+
+/// How long contacts stay in the trash unless `TRASH_RETENTION_DAYS` says otherwise.
+pub const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+/// How often the background task looks for contacts past the retention period.
+const PURGE_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+
+/// Moves the contacts to the trash, returning when that happened.
+/// Every contact trashed together gets the same time, which is how "Undo" finds them again.
+pub async fn trash(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    source: Source,
+    contact_ids: &[ContactId],
+) -> Result<DateTime<Utc>, AppError> {
+    let now = Utc::now();
+    connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::contacts::dsl::*;
+
+                let trashed: Vec<(ContactId, ContactAttributes)> = diesel::update(
+                    contacts
+                        .filter(address_book_id.eq_any(books::allowing(user, Permission::Delete)))
+                        .filter(id.eq_any(contact_ids))
+                        .filter(deleted_at.is_null()),
+                )
+                .set(deleted_at.eq(now))
+                .returning((id, ContactAttributes::as_returning()))
+                .get_results(connection)
+                .await?;
+                let changes: Vec<Change> = trashed
+                    .iter()
+                    .map(|(contact_id, attributes)| Change {
+                        contact_id: *contact_id,
+                        before: Some(attributes),
+                        after: None,
+                    })
+                    .collect();
+                history::record(connection, user, source, EventKind::Deleted, &changes).await
+            }
+            .scope_boxed()
+        })
+        .await?;
+    Ok(now)
+}
+
+/// Everything in the trash, most recently deleted first.
+pub async fn trashed(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Vec<(Contact, DateTime<Utc>)>, AppError> {
+    use crate::schema::contacts::dsl::*;
+
+    let rows: Vec<(Contact, Option<DateTime<Utc>>)> = contacts
+        .filter(address_book_id.eq_any(books::allowing(user, Permission::Delete)))
+        .filter(deleted_at.is_not_null())
+        .order((deleted_at.desc(), id))
+        .select((Contact::as_select(), deleted_at))
+        .load(connection)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(contact, at)| Some((contact, at?)))
+        .collect())
+}
+
+pub async fn restore(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    source: Source,
+    contact_ids: &[ContactId],
+) -> Result<usize, AppError> {
+    use crate::schema::contacts::dsl::*;
+
+    let trashed = id
+        .eq_any(contact_ids.to_vec())
+        .and(deleted_at.is_not_null());
+    restore_where(connection, user, source, Box::new(trashed)).await
+}
+
+/// Restores everything that `trash` moved to the trash at `at`.
+pub async fn restore_trashed_at(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    source: Source,
+    at: DateTime<Utc>,
+) -> Result<usize, AppError> {
+    use crate::schema::contacts::dsl::*;
+
+    restore_where(
+        connection,
+        user,
+        source,
+        Box::new(deleted_at.eq(at).assume_not_null()),
+    )
+    .await
+}
+
+/// Takes the contacts matching `trashed` out of the trash, in the books where `user` is an admin.
+async fn restore_where(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    source: Source,
+    trashed: ContactsExpression<Bool>,
+) -> Result<usize, AppError> {
+    connection
+        .transaction(|connection| {
+            async move {
+                use crate::schema::contacts::dsl::*;
+
+                let restored: Vec<(ContactId, ContactAttributes)> = diesel::update(
+                    contacts
+                        .filter(address_book_id.eq_any(books::allowing(user, Permission::Delete)))
+                        .filter(trashed),
+                )
+                .set((
+                    deleted_at.eq(None::<DateTime<Utc>>),
+                    merged_into.eq(None::<ContactId>),
+                ))
+                .returning((id, ContactAttributes::as_returning()))
+                .get_results(connection)
+                .await?;
+                let changes: Vec<Change> = restored
+                    .iter()
+                    .map(|(contact_id, attributes)| Change {
+                        contact_id: *contact_id,
+                        before: None,
+                        after: Some(attributes),
+                    })
+                    .collect();
+                history::record(connection, user, source, EventKind::Restored, &changes).await?;
+                Ok(restored.len())
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// Deletes contacts for good, as long as they're already in the trash.
+pub async fn purge(
+    connection: &mut AsyncPgConnection,
+    user: UserId,
+    contact_ids: &[ContactId],
+) -> Result<usize, AppError> {
+    use crate::schema::contacts::dsl::*;
+
+    Ok(diesel::delete(
+        contacts
+            .filter(address_book_id.eq_any(books::allowing(user, Permission::Delete)))
+            .filter(id.eq_any(contact_ids))
+            .filter(deleted_at.is_not_null()),
+    )
+    .execute(connection)
+    .await?)
+}
+
+/// Deletes everything in the trash for good, in every book where `user` is an admin.
+pub async fn empty(connection: &mut AsyncPgConnection, user: UserId) -> Result<usize, AppError> {
+    use crate::schema::contacts::dsl::*;
+
+    Ok(diesel::delete(
+        contacts
+            .filter(address_book_id.eq_any(books::allowing(user, Permission::Delete)))
+            .filter(deleted_at.is_not_null()),
+    )
+    .execute(connection)
+    .await?)
+}
+
+/// Deletes everything, in every book, that was moved to the trash before `cutoff`.
+pub async fn purge_trashed_before(
+    connection: &mut AsyncPgConnection,
+    cutoff: DateTime<Utc>,
+) -> Result<usize, AppError> {
+    use crate::schema::contacts::dsl::*;
+
+    Ok(diesel::delete(contacts.filter(deleted_at.lt(cutoff)))
+        .execute(connection)
+        .await?)
+}
+
+/// Runs forever, purging whatever has been in the trash for longer than `retention`.
+pub async fn purge_periodically(pool: Pool<AsyncPgConnection>, retention: Duration) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let purged = match pool.get().await {
+            Ok(mut connection) => {
+                purge_trashed_before(&mut connection, Utc::now() - retention).await
+            }
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = purged {
+            tracing::error!(%err, "could not purge the trash");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+    use crate::model::AddressBookId;
+
+    async fn test_connection() -> AsyncPgConnection {
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut connection = AsyncPgConnection::establish(&url).await.unwrap();
+        connection.begin_test_transaction().await.unwrap();
+        connection
+    }
+
+    async fn insert_contact(connection: &mut AsyncPgConnection, book: AddressBookId) -> ContactId {
+        use crate::schema::contacts;
+
+        let attributes = ContactAttributes {
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            phone: Default::default(),
+            email_address: Default::default(),
+        };
+        diesel::insert_into(contacts::table)
+            .values((&attributes, contacts::address_book_id.eq(book)))
+            .returning(contacts::id)
+            .get_result(connection)
+            .await
+            .unwrap()
+    }
+
+    async fn trashed_ids(connection: &mut AsyncPgConnection, user: UserId) -> Vec<ContactId> {
+        trashed(connection, user)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(contact, _)| contact.id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn trashed_contacts_can_be_restored_until_purged() {
+        let mut connection = test_connection().await;
+        let user = auth::create_user(&mut connection, "trash-test", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let book = books::default_book(&mut connection, user.id).await.unwrap();
+        let first = insert_contact(&mut connection, book).await;
+        let second = insert_contact(&mut connection, book).await;
+
+        let at = trash(&mut connection, user.id, Source::Web, &[first, second])
+            .await
+            .unwrap();
+        let mut ids = trashed_ids(&mut connection, user.id).await;
+        ids.sort();
+        assert_eq!(ids, [first, second]);
+
+        // "Undo" brings back everything that went together.
+        assert_eq!(
+            restore_trashed_at(&mut connection, user.id, Source::Web, at)
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(trashed_ids(&mut connection, user.id).await.is_empty());
+
+        // Only contacts in the trash can be purged.
+        assert_eq!(purge(&mut connection, user.id, &[first]).await.unwrap(), 0);
+        trash(&mut connection, user.id, Source::Web, &[first])
+            .await
+            .unwrap();
+        assert_eq!(purge(&mut connection, user.id, &[first]).await.unwrap(), 1);
+        assert_eq!(
+            restore(&mut connection, user.id, Source::Web, &[first])
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn only_contacts_past_the_retention_period_are_purged() {
+        use crate::schema::contacts;
+
+        let mut connection = test_connection().await;
+        let user = auth::create_user(&mut connection, "retention-test", "password")
+            .await
+            .unwrap()
+            .unwrap();
+        let book = books::default_book(&mut connection, user.id).await.unwrap();
+        let old = insert_contact(&mut connection, book).await;
+        let recent = insert_contact(&mut connection, book).await;
+        let kept = insert_contact(&mut connection, book).await;
+        trash(&mut connection, user.id, Source::Web, &[old, recent])
+            .await
+            .unwrap();
+        diesel::update(contacts::table.find(old))
+            .set(contacts::deleted_at.eq(Utc::now() - Duration::days(DEFAULT_RETENTION_DAYS + 1)))
+            .execute(&mut connection)
+            .await
+            .unwrap();
+
+        purge_trashed_before(
+            &mut connection,
+            Utc::now() - Duration::days(DEFAULT_RETENTION_DAYS),
+        )
+        .await
+        .unwrap();
+
+        let left: Vec<ContactId> = contacts::table
+            .filter(contacts::id.eq_any([old, recent, kept]))
+            .order(contacts::id)
+            .select(contacts::id)
+            .load(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(left, [recent, kept]);
+    }
+}
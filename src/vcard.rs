@@ -0,0 +1,551 @@
+//! Reading and writing contacts as vCards,
+//! following RFC 2426 for version 3.0 and RFC 6350 for version 4.0.
+//!
+//! Only the properties we have somewhere to put are handled (`FN`/`N`, `TEL`, `EMAIL` and `ADR`),
+//! everything else in an imported card is ignored.
+//! Labels travel as the `TYPE` parameter, as far as vCard has a type for them.
+
+use serde::Deserialize;
+
+use crate::model::Address;
+use crate::model::ContactAttributes;
+use crate::model::ContactDetails;
+use crate::model::DetailsInput;
+use crate::model::EmailInput;
+use crate::model::PendingContact;
+use crate::model::PhoneInput;
+
+const CRLF: &str = "\r\n";
+/// RFC 6350 asks for lines to be folded at 75 octets, not counting the line break.
+const MAX_LINE_LENGTH: usize = 75;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum VCardVersion {
+    #[serde(rename = "3.0")]
+    V3,
+    #[default]
+    #[serde(rename = "4.0")]
+    V4,
+}
+
+impl VCardVersion {
+    fn as_str(self) -> &'static str {
+        match self {
+            VCardVersion::V3 => "3.0",
+            VCardVersion::V4 => "4.0",
+        }
+    }
+}
+
+pub fn write_vcard(
+    contact: &ContactAttributes,
+    details: &ContactDetails,
+    version: VCardVersion,
+) -> String {
+    let mut card = String::new();
+    let mut line = |content: String| {
+        card.push_str(&fold(&content));
+        card.push_str(CRLF);
+    };
+
+    line("BEGIN:VCARD".to_string());
+    line(format!("VERSION:{}", version.as_str()));
+    line(format!(
+        "FN:{}",
+        escape(format!("{} {}", contact.first_name, contact.last_name).trim())
+    ));
+    line(format!(
+        "N:{};{};;;",
+        escape(&contact.last_name),
+        escape(&contact.first_name)
+    ));
+    for phone in &details.phones {
+        let (value, kind) = match version {
+            VCardVersion::V3 => ("", "VOICE"),
+            VCardVersion::V4 => (";VALUE=text", "voice"),
+        };
+        line(format!(
+            "TEL{value}{}:{}",
+            params(version, &phone.label, phone.primary, Some(kind)),
+            escape(&phone.number)
+        ));
+    }
+    for email in &details.emails {
+        let kind = (version == VCardVersion::V3).then_some("INTERNET");
+        line(format!(
+            "EMAIL{}:{}",
+            params(version, &email.label, email.primary, kind),
+            escape(&email.address)
+        ));
+    }
+    for address in &details.addresses {
+        // The post office box and extended address come first, we don't keep either.
+        line(format!(
+            "ADR{}:;;{};{};{};{};{}",
+            params(version, &address.label, false, None),
+            escape(&address.street),
+            escape(&address.city),
+            escape(&address.region),
+            escape(&address.postal_code),
+            escape(&address.country)
+        ));
+    }
+    line("END:VCARD".to_string());
+
+    card
+}
+
+pub fn write_vcards<'a>(
+    contacts: impl IntoIterator<Item = (&'a ContactAttributes, &'a ContactDetails)>,
+    version: VCardVersion,
+) -> String {
+    contacts
+        .into_iter()
+        .map(|(contact, details)| write_vcard(contact, details, version))
+        .collect()
+}
+
+/// The vCard type for one of our labels, if it has one.
+/// Other labels can't be written without quoting and would mean nothing to other phone books anyway.
+fn label_type(label: &str) -> Option<&'static str> {
+    match label.to_ascii_lowercase().as_str() {
+        "home" => Some("home"),
+        "work" => Some("work"),
+        "mobile" | "cell" => Some("cell"),
+        "fax" => Some("fax"),
+        _ => None,
+    }
+}
+
+/// The `TYPE` and preference parameters of a `TEL`, `EMAIL` or `ADR` line,
+/// where `kind` is the type some versions expect on all of them.
+fn params(version: VCardVersion, label: &str, primary: bool, kind: Option<&str>) -> String {
+    let mut types: Vec<&str> = label_type(label).into_iter().chain(kind).collect();
+    let mut params = String::new();
+    match version {
+        VCardVersion::V3 => {
+            if primary {
+                types.push("PREF");
+            }
+            if !types.is_empty() {
+                params.push_str(&format!(";TYPE={}", types.join(",")));
+            }
+        }
+        VCardVersion::V4 => {
+            if !types.is_empty() {
+                params.push_str(&format!(";TYPE={}", types.join(",")));
+            }
+            if primary {
+                params.push_str(";PREF=1");
+            }
+        }
+    }
+    params
+}
+
+/// Our label for the `TYPE` parameters of a property, skipping the ones that just say what it is.
+fn type_label(params: &str) -> String {
+    params
+        .split(';')
+        .filter_map(|param| param.split_once('='))
+        .filter(|(name, _)| name.eq_ignore_ascii_case("TYPE"))
+        .flat_map(|(_, types)| types.trim_matches('"').split(','))
+        .map(str::to_ascii_lowercase)
+        .find(|kind| {
+            !matches!(
+                kind.as_str(),
+                "voice" | "internet" | "pref" | "text" | "x400"
+            )
+        })
+        .map(|kind| match kind.as_str() {
+            "cell" => "mobile".to_string(),
+            _ => kind,
+        })
+        .unwrap_or_default()
+}
+
+/// How strongly a `TEL` or `EMAIL` line is preferred, lower meaning more: `PREF=1` to `PREF=100`
+/// in version 4.0, while `TYPE=PREF` in 3.0 (or a bare `PREF` from 2.1) just marks the preferred one.
+fn preference(params: &str) -> Option<u8> {
+    params
+        .split(';')
+        .find_map(|param| match param.split_once('=') {
+            Some((name, value)) if name.eq_ignore_ascii_case("PREF") => {
+                value.trim_matches('"').parse().ok()
+            }
+            Some((name, types)) if name.eq_ignore_ascii_case("TYPE") => types
+                .trim_matches('"')
+                .split(',')
+                .any(|kind| kind.eq_ignore_ascii_case("pref"))
+                .then_some(1),
+            None if param.eq_ignore_ascii_case("PREF") => Some(1),
+            _ => None,
+        })
+}
+
+/// The position of the most preferred value, the first of them if several are just as preferred.
+fn most_preferred(preferences: impl Iterator<Item = Option<u8>>) -> Option<usize> {
+    preferences
+        .enumerate()
+        .filter_map(|(index, preference)| Some((preference?, index)))
+        .min()
+        .map(|(_, index)| index)
+}
+
+/// Splits a `.vcf` file into one pending contact per card.
+///
+/// This is deliberately lenient: we pull out whatever fields we recognize
+/// and leave it to `PendingContact::Form::to_valid` to report what is missing,
+/// so that every card gets its own errors instead of the whole file being rejected.
+pub fn parse_vcards(input: &str) -> Vec<PendingContact::Form> {
+    let mut cards = vec![];
+    let mut current: Option<Card> = None;
+
+    for line in unfold(input) {
+        let Some((name, params, value)) = split_property(&line) else {
+            continue;
+        };
+        match name.as_str() {
+            "BEGIN" if value.eq_ignore_ascii_case("VCARD") => current = Some(Card::default()),
+            "END" if value.eq_ignore_ascii_case("VCARD") => {
+                if let Some(card) = current.take() {
+                    cards.push(card.into_form());
+                }
+            }
+            _ => {
+                if let Some(card) = current.as_mut() {
+                    card.property(&name, &params, value);
+                }
+            }
+        }
+    }
+    // Be forgiving about a missing `END:VCARD` at the end of the file.
+    if let Some(card) = current {
+        cards.push(card.into_form());
+    }
+
+    cards
+}
+
+#[derive(Default)]
+struct Card {
+    formatted_name: Option<String>,
+    name: Option<(String, String)>,
+    phones: Vec<(PhoneInput, Option<u8>)>,
+    emails: Vec<(EmailInput, Option<u8>)>,
+    addresses: Vec<Address>,
+}
+
+impl Card {
+    fn property(&mut self, name: &str, params: &str, value: &str) {
+        match name {
+            "FN" => self.formatted_name = Some(unescape(value)),
+            "N" => {
+                let mut components = split_components(value).into_iter();
+                let last_name = components.next().unwrap_or_default();
+                let first_name = components.next().unwrap_or_default();
+                self.name = Some((first_name, last_name));
+            }
+            "TEL" => {
+                let phone = unescape(value);
+                let phone = phone.strip_prefix("tel:").unwrap_or(&phone);
+                self.phones.push((
+                    PhoneInput {
+                        label: type_label(params),
+                        number: phone.to_string(),
+                        primary: false,
+                    },
+                    preference(params),
+                ));
+            }
+            "EMAIL" => self.emails.push((
+                EmailInput {
+                    label: type_label(params),
+                    address: unescape(value),
+                    primary: false,
+                },
+                preference(params),
+            )),
+            "ADR" => {
+                let mut components = split_components(value).into_iter().skip(2);
+                let mut next = || components.next().unwrap_or_default();
+                self.addresses.push(Address {
+                    label: type_label(params),
+                    street: next(),
+                    city: next(),
+                    region: next(),
+                    postal_code: next(),
+                    country: next(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn into_form(mut self) -> PendingContact::Form {
+        // Only the most preferred phone and email become primary, there's one of each.
+        if let Some(index) = most_preferred(self.phones.iter().map(|(_, preference)| *preference)) {
+            self.phones[index].0.primary = true;
+        }
+        if let Some(index) = most_preferred(self.emails.iter().map(|(_, preference)| *preference)) {
+            self.emails[index].0.primary = true;
+        }
+        let (first_name, last_name) = match (self.name, self.formatted_name) {
+            (Some(name), _) => (Some(name.0), Some(name.1)),
+            (None, Some(formatted_name)) => match formatted_name.rsplit_once(' ') {
+                Some((first_name, last_name)) => {
+                    (Some(first_name.to_string()), Some(last_name.to_string()))
+                }
+                None => (Some(formatted_name), Some(String::new())),
+            },
+            (None, None) => (None, None),
+        };
+        PendingContact::Form::new(
+            first_name,
+            last_name,
+            &DetailsInput {
+                phones: self.phones.into_iter().map(|(phone, _)| phone).collect(),
+                emails: self.emails.into_iter().map(|(email, _)| email).collect(),
+                addresses: self.addresses,
+            },
+        )
+    }
+}
+
+/// Joins folded lines back together: a line break followed by a space or tab is a continuation.
+fn unfold(input: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in input.lines() {
+        match line.strip_prefix([' ', '\t']) {
+            Some(continuation) if !lines.is_empty() => {
+                lines.last_mut().unwrap().push_str(continuation)
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            folded.push_str(CRLF);
+            folded.push(' ');
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded
+}
+
+/// Splits `group.NAME;PARAM=a:value` into the upper-cased name, the parameters and the value.
+fn split_property(line: &str) -> Option<(String, String, &str)> {
+    let (key, value) = line.split_once(':')?;
+    let (name, params) = key.split_once(';').unwrap_or((key, ""));
+    let name = name.rsplit('.').next().unwrap_or(name);
+    Some((name.to_ascii_uppercase(), params.to_string(), value))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace('\n', "\\n")
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') | Some('N') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                None => {}
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+/// Splits a structured value like `N` on its unescaped semicolons.
+fn split_components(value: &str) -> Vec<String> {
+    let mut components = vec![];
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ';' => {
+                components.push(unescape(&value[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    components.push(unescape(&value[start..]));
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Email;
+    use crate::model::EmailAddress;
+    use crate::model::Phone;
+    use crate::model::PhoneNumber;
+    use crate::model::ValidContact;
+
+    fn contact() -> (ContactAttributes, ContactDetails) {
+        let phone = |label: &str, number: &str, primary| Phone {
+            label: label.to_string(),
+            number: PhoneNumber::parse(number).unwrap(),
+            primary,
+        };
+        let email = |label: &str, address: &str, primary| Email {
+            label: label.to_string(),
+            address: EmailAddress::parse(address).unwrap(),
+            primary,
+        };
+        let details = ContactDetails {
+            phones: vec![
+                phone("home", "+44 20 7946 0958", false),
+                phone("mobile", "+1 415 555 0123", true),
+            ],
+            emails: vec![
+                email("work", "ada@example.com", false),
+                email("", "lovelace@example.com", true),
+            ],
+            addresses: vec![Address {
+                label: "home".to_string(),
+                street: "12 St. James's Square; Flat 3, 2nd floor\nBack door".to_string(),
+                city: "London".to_string(),
+                region: String::new(),
+                postal_code: "SW1Y 4JH".to_string(),
+                country: "United Kingdom".to_string(),
+            }],
+        };
+        let attributes = ContactAttributes {
+            first_name: "Ada, Countess".to_string(),
+            last_name: "King; née Byron".to_string(),
+            phone: details.phones[1].number.clone(),
+            email_address: details.emails[1].address.clone(),
+        };
+        (attributes, details)
+    }
+
+    fn read_back(card: &str) -> ValidContact {
+        let mut forms = parse_vcards(card);
+        assert_eq!(forms.len(), 1);
+        forms
+            .remove(0)
+            .to_valid()
+            .unwrap_or_else(|_| panic!("the card is valid"))
+    }
+
+    #[test]
+    fn cards_read_back_as_they_were_written() {
+        let (attributes, details) = contact();
+        for version in [VCardVersion::V3, VCardVersion::V4] {
+            let card = write_vcard(&attributes, &details, version);
+            let valid = read_back(&card);
+            assert_eq!(valid.attributes, attributes, "{version:?}");
+            assert_eq!(valid.details, details, "{version:?}");
+        }
+    }
+
+    #[test]
+    fn long_lines_are_folded_and_unfolded() {
+        let (mut attributes, details) = contact();
+        attributes.first_name =
+            "Ada Augusta Ada Augusta Ada Augusta Ada Augusta Ada Augusta Ada Augusta Åda"
+                .to_string();
+        let card = write_vcard(&attributes, &details, VCardVersion::V4);
+
+        assert!(card.split(CRLF).all(|line| line.len() <= MAX_LINE_LENGTH));
+        assert!(card.contains("\r\n "));
+        assert_eq!(
+            read_back(&card).attributes.first_name,
+            attributes.first_name
+        );
+        let tab_folded = "BEGIN:VCARD\nN:Love\n\tlace;Ada;;;\nEMAIL:ada@example.com\nEND:VCARD\n";
+        assert_eq!(read_back(tab_folded).attributes.last_name, "Lovelace");
+    }
+
+    #[test]
+    fn escaping_survives_a_round_trip() {
+        for value in ["a,b;c\\d", "first\nsecond", "\\n is not a line break", ""] {
+            assert_eq!(unescape(&escape(value)), value);
+        }
+        assert_eq!(unescape("Line\\Nbreak"), "Line\nbreak");
+    }
+
+    #[test]
+    fn structured_values_split_on_unescaped_semicolons_only() {
+        assert_eq!(
+            split_components("King\\; née Byron;Ada;;;"),
+            ["King; née Byron", "Ada", "", "", ""]
+        );
+        assert_eq!(split_components("a\\\\;b"), ["a\\", "b"]);
+    }
+
+    #[test]
+    fn types_become_labels() {
+        assert_eq!(type_label("TYPE=cell,voice"), "mobile");
+        assert_eq!(type_label("TYPE=INTERNET,PREF;TYPE=WORK"), "work");
+        assert_eq!(type_label("TYPE=\"home\""), "home");
+        assert_eq!(type_label("PREF=1"), "");
+    }
+
+    #[test]
+    fn only_the_most_preferred_value_is_primary() {
+        let card = "BEGIN:VCARD\r\n\
+            VERSION:4.0\r\n\
+            N:Lovelace;Ada;;;\r\n\
+            EMAIL;PREF=2:second@example.com\r\n\
+            EMAIL:none@example.com\r\n\
+            EMAIL;TYPE=work;PREF=1:first@example.com\r\n\
+            TEL;PREF=3:+1 415 555 0123\r\n\
+            TEL;PREF=3:+44 20 7946 0958\r\n\
+            END:VCARD\r\n";
+        let valid = read_back(card);
+        assert_eq!(&*valid.attributes.email_address, "first@example.com");
+        assert_eq!(
+            valid
+                .details
+                .emails
+                .iter()
+                .filter(|email| email.primary)
+                .count(),
+            1
+        );
+        assert_eq!(&*valid.attributes.phone, "+14155550123");
+        assert_eq!(
+            valid
+                .details
+                .phones
+                .iter()
+                .filter(|phone| phone.primary)
+                .count(),
+            1
+        );
+
+        let card = "BEGIN:VCARD\r\n\
+            VERSION:3.0\r\n\
+            N:Lovelace;Ada;;;\r\n\
+            EMAIL;TYPE=INTERNET:other@example.com\r\n\
+            EMAIL;TYPE=INTERNET,PREF:ada@example.com\r\n\
+            END:VCARD\r\n";
+        assert_eq!(
+            &*read_back(card).attributes.email_address,
+            "ada@example.com"
+        );
+    }
+}